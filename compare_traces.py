@@ -1,5 +1,5 @@
 trace_path = "trace.txt"
-nestest_trace_path = "tests/nestest_trace.txt"
+nestest_trace_path = "nes-core/tests/nestest_trace.txt"
 
 previous_trace_line = ""
 previous_nestest_trace_line = ""
@@ -0,0 +1,980 @@
+// The 2A03's audio processing unit, two pulse channels, a triangle, a noise channel and the delta modulation channel (DMC).
+// See https://www.nesdev.org/wiki/APU
+
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+
+use crate::region::Region;
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[rustfmt::skip]
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+const DUTY_SEQUENCES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+#[rustfmt::skip]
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+// The largest timer period the sweep unit can produce before it mutes the channel
+const MAX_PULSE_PERIOD: u16 = 0x7FF;
+
+// The NES's output filters, two high pass filters from the console and a low pass filter from the cartridge connector
+const HIGH_PASS_CUTOFFS: [f32; 2] = [90.0, 440.0];
+const LOW_PASS_CUTOFF: f32 = 14000.0;
+
+#[derive(Default, Clone)]
+struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.enabled);
+        state.write(&self.halted);
+        state.write(&self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read()?;
+        self.halted = state.read()?;
+        self.counter = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Default, Clone)]
+struct Envelope {
+    start: bool,
+    constant_volume: bool,
+    looping: bool,
+    // The constant volume, or the envelope's period when it's decaying
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant_volume = value & 0x10 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.start);
+        state.write(&self.constant_volume);
+        state.write(&self.looping);
+        state.write(&self.volume);
+        state.write(&self.divider);
+        state.write(&self.decay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.start = state.read()?;
+        self.constant_volume = state.read()?;
+        self.looping = state.read()?;
+        self.volume = state.read()?;
+        self.divider = state.read()?;
+        self.decay = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Pulse {
+    // Pulse 1 negates its sweep with ones' complement, so it sweeps one lower than pulse 2
+    ones_complement: bool,
+    // The MMC5's pulse channels are the same without a sweep unit
+    has_sweep: bool,
+    duty: usize,
+    step: usize,
+    timer_period: u16,
+    timer: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool, has_sweep: bool) -> Self {
+        Pulse {
+            ones_complement,
+            has_sweep,
+            duty: 0,
+            step: 0,
+            timer_period: 0,
+            timer: 0,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    // Registers 0-3, $4000-$4003 for pulse 1
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = (value >> 6) as usize;
+                self.length.halted = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0b111;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn active(&self) -> bool {
+        self.length.active()
+    }
+
+    // Clocked every other cpu cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        if !self.has_sweep {
+            return;
+        }
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer_period + change
+        } else if self.ones_complement {
+            self.timer_period.saturating_sub(change + 1)
+        } else {
+            self.timer_period.saturating_sub(change)
+        }
+    }
+
+    // The sweep unit mutes the channel when the period is too low or its target is out of range, even while it's disabled
+    fn muted(&self) -> bool {
+        self.has_sweep && (self.timer_period < 8 || self.sweep_target() > MAX_PULSE_PERIOD)
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.muted() || DUTY_SEQUENCES[self.duty][self.step] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.duty);
+        state.write(&self.step);
+        state.write(&self.timer_period);
+        state.write(&self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write(&self.sweep_enabled);
+        state.write(&self.sweep_period);
+        state.write(&self.sweep_negate);
+        state.write(&self.sweep_shift);
+        state.write(&self.sweep_reload);
+        state.write(&self.sweep_divider);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.read::<usize>()? % DUTY_SEQUENCES.len();
+        self.step = state.read::<usize>()? % DUTY_SEQUENCES[0].len();
+        self.timer_period = state.read()?;
+        self.timer = state.read()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.sweep_enabled = state.read()?;
+        self.sweep_period = state.read()?;
+        self.sweep_negate = state.read()?;
+        self.sweep_shift = state.read()?;
+        self.sweep_reload = state.read()?;
+        self.sweep_divider = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Default, Clone)]
+struct Triangle {
+    length: LengthCounter,
+    // Halts the length counter and keeps the linear counter reloading
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    timer_period: u16,
+    timer: u16,
+    step: usize,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.halted = self.control;
+                self.linear_reload_value = value & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0b111) << 8);
+                self.length.load(value >> 3);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    // Clocked every cpu cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % TRIANGLE_SEQUENCE.len();
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // The triangle keeps outputting its last step while it's silenced
+    fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.step]
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.length.save_state(state);
+        state.write(&self.control);
+        state.write(&self.linear_reload_value);
+        state.write(&self.linear_counter);
+        state.write(&self.linear_reload);
+        state.write(&self.timer_period);
+        state.write(&self.timer);
+        state.write(&self.step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.length.load_state(state)?;
+        self.control = state.read()?;
+        self.linear_reload_value = state.read()?;
+        self.linear_counter = state.read()?;
+        self.linear_reload = state.read()?;
+        self.timer_period = state.read()?;
+        self.timer = state.read()?;
+        self.step = state.read::<usize>()? % TRIANGLE_SEQUENCE.len();
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Noise {
+    length: LengthCounter,
+    envelope: Envelope,
+    // Short mode feeds back bit 6 instead of bit 1, which gives a 93 step metallic sounding sequence
+    short_mode: bool,
+    period: u16,
+    timer: u16,
+    shift_register: u16,
+    periods: &'static [u16; 16],
+}
+
+impl Noise {
+    fn new(region: Region) -> Self {
+        Noise {
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            short_mode: false,
+            period: region.noise_periods()[0],
+            timer: 0,
+            shift_register: 1,
+            periods: region.noise_periods(),
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.length.halted = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                self.period = self.periods[(value & 0x0F) as usize];
+            }
+            3 => {
+                self.length.load(value >> 3);
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    // Clocked every cpu cycle, the periods are in cpu cycles
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.shift_register & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write(&self.short_mode);
+        state.write(&self.period);
+        state.write(&self.timer);
+        state.write(&self.shift_register);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.short_mode = state.read()?;
+        self.period = state.read()?;
+        self.timer = state.read()?;
+        self.shift_register = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Dmc {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    rates: &'static [u16; 16],
+}
+
+impl Dmc {
+    fn new(region: Region) -> Self {
+        Dmc {
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            rate: region.dmc_rates()[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            rates: region.dmc_rates(),
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = value & 0x40 != 0;
+                self.rate = self.rates[(value & 0x0F) as usize];
+            }
+            1 => self.level = value & 0x7F,
+            2 => self.sample_address = 0xC000 | ((value as u16) << 6),
+            _ => self.sample_length = ((value as u16) << 4) | 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Clocked every cpu cycle, the rates are in cpu cycles
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+            self.shift_register >>= 1;
+        }
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    fn read_address(&self) -> Option<u16> {
+        (self.buffer.is_none() && self.bytes_remaining > 0).then_some(self.current_address)
+    }
+
+    fn fill_buffer(&mut self, value: u8) {
+        self.buffer = Some(value);
+        // The address wraps around to $8000 rather than $0000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.irq_enabled);
+        state.write(&self.irq);
+        state.write(&self.looping);
+        state.write(&self.rate);
+        state.write(&self.timer);
+        state.write(&self.level);
+        state.write(&self.sample_address);
+        state.write(&self.sample_length);
+        state.write(&self.current_address);
+        state.write(&self.bytes_remaining);
+        state.write(&self.buffer);
+        state.write(&self.shift_register);
+        state.write(&self.bits_remaining);
+        state.write(&self.silence);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = state.read()?;
+        self.irq = state.read()?;
+        self.looping = state.read()?;
+        self.rate = state.read()?;
+        self.timer = state.read()?;
+        self.level = state.read()?;
+        self.sample_address = state.read()?;
+        self.sample_length = state.read()?;
+        self.current_address = state.read()?;
+        self.bytes_remaining = state.read()?;
+        self.buffer = state.read()?;
+        self.shift_register = state.read()?;
+        self.bits_remaining = state.read()?;
+        self.silence = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    region: Region,
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    odd_cycle: bool,
+    // The channels are mixed nonlinearly, see https://www.nesdev.org/wiki/APU_Mixer
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (index, level) in pulse_table.iter_mut().enumerate().skip(1) {
+            *level = 95.52 / (8128.0 / index as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (index, level) in tnd_table.iter_mut().enumerate().skip(1) {
+            *level = 163.67 / (24329.0 / index as f32 + 100.0);
+        }
+        Apu {
+            pulse1: Pulse::new(true, true),
+            pulse2: Pulse::new(false, true),
+            triangle: Triangle::default(),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
+            region,
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            odd_cycle: false,
+            pulse_table,
+            tnd_table,
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.noise.periods = region.noise_periods();
+        self.dmc.rates = region.dmc_rates();
+    }
+
+    // $4000-$4013, $4015 and $4017
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse1.write(address & 0b11, value),
+            0x4004..=0x4007 => self.pulse2.write(address & 0b11, value),
+            0x4008..=0x400B => self.triangle.write(address & 0b11, value),
+            0x400C..=0x400F => self.noise.write(address & 0b11, value),
+            0x4010..=0x4013 => self.dmc.write(address & 0b11, value),
+            0x4015 => {
+                self.pulse1.set_enabled(value & 0x01 != 0);
+                self.pulse2.set_enabled(value & 0x02 != 0);
+                self.triangle.length.set_enabled(value & 0x04 != 0);
+                self.noise.length.set_enabled(value & 0x08 != 0);
+                if value & 0x10 == 0 {
+                    self.dmc.bytes_remaining = 0;
+                } else if self.dmc.bytes_remaining == 0 {
+                    self.dmc.restart();
+                }
+                self.dmc.irq = false;
+            }
+            0x4017 => {
+                self.five_step_mode = value & 0x80 != 0;
+                self.irq_inhibit = value & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                // The 5 step sequence clocks everything as soon as it's selected
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // $4015, reading it acknowledges the frame interrupt
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn peek_status(&self) -> u8 {
+        (self.pulse1.active() as u8)
+            | (self.pulse2.active() as u8) << 1
+            | (self.triangle.length.active() as u8) << 2
+            | (self.noise.length.active() as u8) << 3
+            | ((self.dmc.bytes_remaining > 0) as u8) << 4
+            | (self.frame_irq as u8) << 6
+            | (self.dmc.irq as u8) << 7
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // The address the DMC wants its next sample byte from, which the memory bus reads for it
+    pub fn dmc_read_address(&self) -> Option<u16> {
+        self.dmc.read_address()
+    }
+
+    pub fn fill_dmc_buffer(&mut self, value: u8) {
+        self.dmc.fill_buffer(value);
+    }
+
+    // Runs the apu for one cpu cycle
+    pub fn clock(&mut self) {
+        self.clock_frame_counter();
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let steps = self.region.frame_counter_steps();
+        let last_step = if self.five_step_mode {
+            self.region.frame_counter_fifth_step()
+        } else {
+            steps[3]
+        };
+        if self.frame_cycle == steps[0] || self.frame_cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if self.frame_cycle == steps[1] || self.frame_cycle == last_step {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+        if self.frame_cycle == last_step && !self.five_step_mode && !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+        if self.frame_cycle > last_step {
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.envelope.clock();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    // The mixed output of all channels, 0.0 to 1.0
+    pub fn output(&self) -> f32 {
+        let pulse = self.pulse_table[(self.pulse1.output() + self.pulse2.output()) as usize];
+        let tnd = self.tnd_table[(3 * self.triangle.output() + 2 * self.noise.output() + self.dmc.level) as usize];
+        pulse + tnd
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        state.write(&self.region);
+        state.write(&self.five_step_mode);
+        state.write(&self.irq_inhibit);
+        state.write(&self.frame_irq);
+        state.write(&self.frame_cycle);
+        state.write(&self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.set_region(state.read()?);
+        self.five_step_mode = state.read()?;
+        self.irq_inhibit = state.read()?;
+        self.frame_irq = state.read()?;
+        self.frame_cycle = state.read()?;
+        self.odd_cycle = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct HighPass {
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl HighPass {
+    fn new(cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        HighPass {
+            alpha: rc / (rc + 1.0 / sample_rate),
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.previous_output = self.alpha * (self.previous_output + input - self.previous_input);
+        self.previous_input = input;
+        self.previous_output
+    }
+}
+
+// Turns the per cycle output of the apu and expansion audio into samples at a normal sample rate
+#[derive(Clone)]
+pub struct AudioSampler {
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    cycles_until_sample: f64,
+    sum: f32,
+    count: u32,
+    high_passes: [HighPass; 2],
+    low_pass_alpha: f32,
+    low_pass_output: f32,
+    pub samples: Vec<f32>,
+}
+
+impl AudioSampler {
+    pub fn new(sample_rate: u32, cpu_clock_rate: f64) -> Self {
+        let sample_rate_f32 = sample_rate as f32;
+        let low_pass_rc = 1.0 / (2.0 * PI * LOW_PASS_CUTOFF);
+        let sample_period = 1.0 / sample_rate_f32;
+        AudioSampler {
+            sample_rate,
+            cycles_per_sample: cpu_clock_rate / sample_rate as f64,
+            cycles_until_sample: cpu_clock_rate / sample_rate as f64,
+            sum: 0.0,
+            count: 0,
+            high_passes: HIGH_PASS_CUTOFFS.map(|cutoff| HighPass::new(cutoff, sample_rate_f32)),
+            low_pass_alpha: sample_period / (low_pass_rc + sample_period),
+            low_pass_output: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Called every cpu cycle with the mixed output, each sample is the average over its cycles
+    pub fn push(&mut self, level: f32) {
+        self.sum += level;
+        self.count += 1;
+        self.cycles_until_sample -= 1.0;
+        if self.cycles_until_sample > 0.0 {
+            return;
+        }
+        self.cycles_until_sample += self.cycles_per_sample;
+
+        let mut sample = self.sum / self.count as f32;
+        self.sum = 0.0;
+        self.count = 0;
+        for high_pass in &mut self.high_passes {
+            sample = high_pass.process(sample);
+        }
+        self.low_pass_output += self.low_pass_alpha * (sample - self.low_pass_output);
+        self.samples.push(self.low_pass_output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_counters_in_status() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4015, 0x0F);
+        apu.write(0x4003, 0x08);
+        apu.write(0x400F, 0x08);
+        assert_eq!(apu.read_status(), 0b1001);
+        apu.write(0x4015, 0x08);
+        assert_eq!(apu.read_status(), 0b1000);
+
+        // Writes while a channel is disabled don't load its length counter
+        apu.write(0x400B, 0x08);
+        assert_eq!(apu.read_status(), 0b1000);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new(Region::Ntsc);
+        for _ in 0..29828 {
+            apu.clock();
+        }
+        assert!(!apu.irq_pending());
+        apu.clock();
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.irq_pending());
+
+        apu.write(0x4017, 0x40);
+        for _ in 0..40000 {
+            apu.clock();
+        }
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_pulse_waveform() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4015, 0x01);
+        // 50% duty at constant volume 15 with a period of 100, so each step lasts 202 cpu cycles
+        apu.write(0x4000, 0b1011_1111);
+        apu.write(0x4002, 100);
+        apu.write(0x4003, 0x08);
+
+        let mut levels = Vec::new();
+        for _ in 0..202 * 8 {
+            levels.push(apu.pulse1.output());
+            apu.clock();
+        }
+        assert_eq!(levels.iter().filter(|level| **level == 15).count(), 202 * 4);
+        assert!(levels.iter().all(|level| *level == 0 || *level == 15));
+        assert!(apu.output() >= 0.0);
+    }
+
+    #[test]
+    fn test_dmc_reads_sample() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4010, 0x80);
+        apu.write(0x4012, 0x01);
+        apu.write(0x4013, 0x00);
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.dmc_read_address(), Some(0xC040));
+        apu.fill_dmc_buffer(0xFF);
+        assert_eq!(apu.dmc_read_address(), None);
+        // The one byte sample has been read, so the IRQ fires
+        assert!(apu.irq_pending());
+        assert_eq!(apu.peek_status() & 0x90, 0x80);
+    }
+
+    #[test]
+    fn test_sampler_rate() {
+        let mut sampler = AudioSampler::new(44100, Region::Ntsc.cpu_clock_rate());
+        for _ in 0..1_789_773 {
+            sampler.push(0.5);
+        }
+        assert!((44099..=44101).contains(&sampler.samples.len()));
+        // The high pass filters remove the constant offset
+        assert!(sampler.samples.last().unwrap().abs() < 0.01);
+    }
+}
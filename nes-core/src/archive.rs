@@ -0,0 +1,376 @@
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use miniz_oxide::inflate::{decompress_to_vec_with_limit, TINFLStatus};
+
+use crate::hash::crc32;
+
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4B50;
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4B50;
+const ZIP_END_OF_DIRECTORY_SIGNATURE: u32 = 0x0605_4B50;
+const ZIP_END_OF_DIRECTORY_SIZE: usize = 22;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+// More than any real rom or disk image, so a zip or gzip bomb can't use up all the memory
+pub const MAX_UNPACKED_SIZE: usize = 32 * 1024 * 1024;
+
+// File extensions of roms that can be picked out of an archive
+pub const ROM_EXTENSIONS: [&str; 6] = ["nes", "unf", "unif", "fds", "nsf", "nsfe"];
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ArchiveError {
+    // The archive is damaged or uses a feature that isn't supported, such as zip64
+    Corrupt(String),
+    UnsupportedCompression(u16),
+    ChecksumMismatch { name: String },
+    // The file unpacks to more than MAX_UNPACKED_SIZE
+    TooLarge { name: String, size: usize },
+    NoRom,
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Corrupt(reason) => write!(f, "Archive is corrupt: {}", reason),
+            ArchiveError::UnsupportedCompression(method) => write!(f, "Archive uses unsupported compression method {}", method),
+            ArchiveError::ChecksumMismatch { name } => write!(f, "Checksum of {} in the archive doesn't match", name),
+            ArchiveError::TooLarge { name, size } => {
+                write!(
+                    f,
+                    "{} in the archive is {} bytes, more than the {} allowed",
+                    name, size, MAX_UNPACKED_SIZE
+                )
+            }
+            ArchiveError::NoRom => write!(f, "Archive doesn't contain a rom ({})", ROM_EXTENSIONS.join(", ")),
+        }
+    }
+}
+
+impl core::error::Error for ArchiveError {}
+
+impl From<String> for ArchiveError {
+    fn from(reason: String) -> Self {
+        ArchiveError::Corrupt(reason)
+    }
+}
+
+// A file in an archive, which is only decompressed when it's needed
+pub struct ArchiveEntry<'a> {
+    pub name: String,
+    compression: u16,
+    crc32: u32,
+    uncompressed_size: usize,
+    data: &'a [u8],
+}
+
+impl ArchiveEntry<'_> {
+    pub fn is_rom(&self) -> bool {
+        let extension = self.name.rsplit_once('.').map_or("", |(_, extension)| extension);
+        ROM_EXTENSIONS.iter().any(|rom_extension| extension.eq_ignore_ascii_case(rom_extension))
+    }
+
+    pub fn contents(&self) -> Result<Vec<u8>, ArchiveError> {
+        let contents = match self.compression {
+            ZIP_STORED => self.data.to_vec(),
+            ZIP_DEFLATED => inflate(self.data, self.uncompressed_size, &self.name)?,
+            method => return Err(ArchiveError::UnsupportedCompression(method)),
+        };
+        if crc32(&contents) != self.crc32 {
+            return Err(ArchiveError::ChecksumMismatch { name: self.name.clone() });
+        }
+        Ok(contents)
+    }
+}
+
+pub fn is_zip(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && read_u32(bytes, 0) == ZIP_LOCAL_HEADER_SIGNATURE
+}
+
+pub fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.starts_with(&GZIP_MAGIC)
+}
+
+// Returns the rom inside a zip or gzip archive, and anything else unchanged
+pub fn unpack_rom(bytes: Vec<u8>) -> Result<Vec<u8>, ArchiveError> {
+    if is_gzip(&bytes) {
+        gunzip(&bytes)
+    } else if is_zip(&bytes) {
+        let entries = zip_entries(&bytes)?;
+        let rom = entries.iter().find(|entry| entry.is_rom()).ok_or(ArchiveError::NoRom)?;
+        rom.contents()
+    } else {
+        Ok(bytes)
+    }
+}
+
+// Lists the files in a zip archive using its central directory.
+// See https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+pub fn zip_entries(bytes: &[u8]) -> Result<Vec<ArchiveEntry<'_>>, ArchiveError> {
+    let corrupt = |reason: &str| ArchiveError::Corrupt(reason.to_owned());
+
+    // The end of central directory record is followed by a comment of up to 64 KiB, so it has to be searched for
+    let end_of_directory = (0..=bytes.len().saturating_sub(ZIP_END_OF_DIRECTORY_SIZE))
+        .rev()
+        .take(u16::MAX as usize + 1)
+        .find(|offset| read_u32(bytes, *offset) == ZIP_END_OF_DIRECTORY_SIGNATURE)
+        .ok_or_else(|| corrupt("no end of central directory record"))?;
+    let entry_count = read_u16(bytes, end_of_directory + 10) as usize;
+    let mut offset = read_u32(bytes, end_of_directory + 16) as usize;
+
+    let mut entries = Vec::with_capacity(entry_count);
+    for _ in 0..entry_count {
+        if offset + 46 > bytes.len() || read_u32(bytes, offset) != ZIP_CENTRAL_HEADER_SIGNATURE {
+            return Err(corrupt("bad central directory header"));
+        }
+        let compression = read_u16(bytes, offset + 10);
+        let crc32 = read_u32(bytes, offset + 16);
+        let compressed_size = read_u32(bytes, offset + 20) as usize;
+        let uncompressed_size = read_u32(bytes, offset + 24) as usize;
+        let name_length = read_u16(bytes, offset + 28) as usize;
+        let extra_length = read_u16(bytes, offset + 30) as usize;
+        let comment_length = read_u16(bytes, offset + 32) as usize;
+        let local_header = read_u32(bytes, offset + 42) as usize;
+        let name = bytes
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or_else(|| corrupt("file name out of bounds"))?;
+        offset += 46 + name_length + extra_length + comment_length;
+
+        // The local header has its own extra field, which can differ in length from the central one
+        if local_header + 30 > bytes.len() || read_u32(bytes, local_header) != ZIP_LOCAL_HEADER_SIGNATURE {
+            return Err(corrupt("bad local file header"));
+        }
+        let data_start = local_header + 30 + read_u16(bytes, local_header + 26) as usize + read_u16(bytes, local_header + 28) as usize;
+        let data = bytes
+            .get(data_start..data_start + compressed_size)
+            .ok_or_else(|| corrupt("file data out of bounds"))?;
+
+        entries.push(ArchiveEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            compression,
+            crc32,
+            uncompressed_size,
+            data,
+        });
+    }
+    Ok(entries)
+}
+
+// Builds a zip with every file stored uncompressed, for formats like BizHawk movies that are zips of text files
+pub fn write_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    // 1980-01-01, the earliest date zip can hold
+    const DOS_DATE: u16 = 0x21;
+    const VERSION: u16 = 20;
+
+    let mut bytes = Vec::new();
+    let mut directory = Vec::new();
+    for (name, contents) in files {
+        let crc32 = crc32(contents);
+        let local_header = bytes.len() as u32;
+        bytes.extend_from_slice(&ZIP_LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        for field in [VERSION, 0, ZIP_STORED, 0, DOS_DATE] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [crc32, contents.len() as u32, contents.len() as u32] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [name.len() as u16, 0] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(contents);
+
+        directory.extend_from_slice(&ZIP_CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+        for field in [VERSION, VERSION, 0, ZIP_STORED, 0, DOS_DATE] {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [crc32, contents.len() as u32, contents.len() as u32] {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+        // Name length, extra length, comment length, disk number and internal attributes
+        for field in [name.len() as u16, 0, 0, 0, 0] {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [0, local_header] {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let directory_offset = bytes.len() as u32;
+    bytes.extend_from_slice(&directory);
+    bytes.extend_from_slice(&ZIP_END_OF_DIRECTORY_SIGNATURE.to_le_bytes());
+    for field in [0, 0, files.len() as u16, files.len() as u16] {
+        bytes.extend_from_slice(&field.to_le_bytes());
+    }
+    for field in [directory.len() as u32, directory_offset] {
+        bytes.extend_from_slice(&field.to_le_bytes());
+    }
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    bytes
+}
+
+// See https://www.rfc-editor.org/rfc/rfc1952
+pub fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    const FLAG_HEADER_CRC: u8 = 0b10;
+    const FLAG_EXTRA: u8 = 0b100;
+    const FLAG_NAME: u8 = 0b1000;
+    const FLAG_COMMENT: u8 = 0b1_0000;
+    let corrupt = |reason: &str| ArchiveError::Corrupt(reason.to_owned());
+
+    if bytes.len() < 18 || !is_gzip(bytes) {
+        return Err(corrupt("truncated gzip header"));
+    }
+    if bytes[2] != ZIP_DEFLATED as u8 {
+        return Err(ArchiveError::UnsupportedCompression(bytes[2] as u16));
+    }
+    let flags = bytes[3];
+    let mut offset = 10;
+    if flags & FLAG_EXTRA != 0 {
+        offset += 2 + read_u16(bytes, offset) as usize;
+    }
+    // The original file name and a comment, both zero terminated
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let length = bytes.get(offset..).and_then(|rest| rest.iter().position(|byte| *byte == 0));
+            offset += length.ok_or_else(|| corrupt("unterminated gzip header string"))? + 1;
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        offset += 2;
+    }
+    if offset + 8 > bytes.len() {
+        return Err(corrupt("truncated gzip header"));
+    }
+
+    // The trailer has the size modulo 2^32, which is exact for anything under MAX_UNPACKED_SIZE
+    let trailer = bytes.len() - 8;
+    let contents = inflate(&bytes[offset..trailer], read_u32(bytes, trailer + 4) as usize, "gzip data")?;
+    if crc32(&contents) != read_u32(bytes, trailer) || contents.len() as u32 != read_u32(bytes, trailer + 4) {
+        return Err(ArchiveError::ChecksumMismatch {
+            name: "gzip data".to_owned(),
+        });
+    }
+    Ok(contents)
+}
+
+// Decompresses DEFLATE data that's declared to unpack to size bytes, and refuses to go past that
+fn inflate(data: &[u8], size: usize, name: &str) -> Result<Vec<u8>, ArchiveError> {
+    if size > MAX_UNPACKED_SIZE {
+        return Err(ArchiveError::TooLarge { name: name.to_owned(), size });
+    }
+    decompress_to_vec_with_limit(data, size).map_err(|error| match error.status {
+        TINFLStatus::HasMoreOutput => ArchiveError::Corrupt(format!("{} unpacks to more than its declared {} bytes", name, size)),
+        _ => ArchiveError::Corrupt(format!("{}: {}", name, error)),
+    })
+}
+
+// Little endian reads that return 0 past the end, the callers check the bounds of anything they use
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    match bytes.get(offset..offset + 2) {
+        Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    match bytes.get(offset..offset + 4) {
+        Some(bytes) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // A zip made with Python's zipfile holding readme.txt (stored) and game.nes (deflated), each containing their own name 8 times
+    const TEST_ZIP: [u8; 303] = [
+        0x50, 0x4B, 0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x50, 0x16, 0xC6, 0x93, 0xF7, 0x50, 0x00, 0x00, 0x00, 0x50,
+        0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78, 0x74, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65,
+        0x2E, 0x74, 0x78, 0x74, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78, 0x74, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78,
+        0x74, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78, 0x74, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78, 0x74, 0x72, 0x65,
+        0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78, 0x74, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78, 0x74, 0x72, 0x65, 0x61, 0x64, 0x6D,
+        0x65, 0x2E, 0x74, 0x78, 0x74, 0x50, 0x4B, 0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x21, 0x50, 0xF5, 0xF6, 0x96, 0x09,
+        0x0D, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x67, 0x61, 0x6D, 0x65, 0x2E, 0x6E, 0x65, 0x73, 0x4B, 0x4F, 0xCC,
+        0x4D, 0xD5, 0xCB, 0x4B, 0x2D, 0x4E, 0x27, 0x93, 0x06, 0x00, 0x50, 0x4B, 0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x21, 0x50, 0x16, 0xC6, 0x93, 0xF7, 0x50, 0x00, 0x00, 0x00, 0x50, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x72, 0x65, 0x61, 0x64, 0x6D, 0x65, 0x2E, 0x74, 0x78, 0x74, 0x50, 0x4B, 0x01,
+        0x02, 0x14, 0x03, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x21, 0x50, 0xF5, 0xF6, 0x96, 0x09, 0x0D, 0x00, 0x00, 0x00, 0x40, 0x00,
+        0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x01, 0x78, 0x00, 0x00, 0x00, 0x67, 0x61, 0x6D,
+        0x65, 0x2E, 0x6E, 0x65, 0x73, 0x50, 0x4B, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x02, 0x00, 0x6E, 0x00, 0x00, 0x00, 0xAB, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // "game.nes" repeated 8 times, gzipped with its file name in the header
+    const TEST_GZIP: [u8; 40] = [
+        0x1F, 0x8B, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xFF, 0x67, 0x61, 0x6D, 0x65, 0x2E, 0x6E, 0x65, 0x73, 0x00, 0x4B, 0x4F, 0xCC, 0x4D,
+        0xD5, 0xCB, 0x4B, 0x2D, 0x4E, 0x27, 0x93, 0x06, 0x00, 0xF5, 0xF6, 0x96, 0x09, 0x40, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_zip() {
+        let entries = zip_entries(&TEST_ZIP).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "readme.txt");
+        assert!(!entries[0].is_rom());
+        assert_eq!(entries[0].contents().unwrap(), b"readme.txt".repeat(8));
+        assert!(entries[1].is_rom());
+        assert_eq!(unpack_rom(TEST_ZIP.to_vec()).unwrap(), b"game.nes".repeat(8));
+    }
+
+    #[test]
+    fn test_write_zip() {
+        let zip = write_zip(&[("Header.txt", b"MovieVersion 1\n"), ("empty", b"")]);
+        assert!(is_zip(&zip));
+        let entries = zip_entries(&zip).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "Header.txt");
+        assert_eq!(entries[0].contents().unwrap(), b"MovieVersion 1\n");
+        assert_eq!(entries[1].name, "empty");
+        assert_eq!(entries[1].contents().unwrap(), b"");
+    }
+
+    #[test]
+    fn test_gzip() {
+        assert_eq!(unpack_rom(TEST_GZIP.to_vec()).unwrap(), b"game.nes".repeat(8));
+
+        let mut corrupted = TEST_GZIP.to_vec();
+        let trailer = corrupted.len() - 8;
+        corrupted[trailer] ^= 1;
+        assert!(matches!(unpack_rom(corrupted), Err(ArchiveError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn test_unpacked_size_limit() {
+        // A file that inflates to more than its declared size stops at that size
+        let mut entries = zip_entries(&TEST_ZIP).unwrap();
+        entries[1].uncompressed_size = 16;
+        assert!(matches!(entries[1].contents(), Err(ArchiveError::Corrupt(_))));
+        entries[1].uncompressed_size = MAX_UNPACKED_SIZE + 1;
+        assert!(matches!(entries[1].contents(), Err(ArchiveError::TooLarge { .. })));
+
+        let mut gzip = TEST_GZIP.to_vec();
+        let trailer = gzip.len() - 8;
+        gzip[trailer + 4..].copy_from_slice(&16u32.to_le_bytes());
+        assert!(matches!(unpack_rom(gzip.clone()), Err(ArchiveError::Corrupt(_))));
+        gzip[trailer + 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            unpack_rom(gzip),
+            Err(ArchiveError::TooLarge {
+                name: "gzip data".to_owned(),
+                size: u32::MAX as usize
+            })
+        );
+    }
+
+    #[test]
+    fn test_plain_rom_unchanged() {
+        let bytes = vec![0x4E, 0x45, 0x53, 0x1A];
+        assert_eq!(unpack_rom(bytes.clone()).unwrap(), bytes);
+    }
+}
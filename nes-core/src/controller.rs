@@ -0,0 +1,91 @@
+// The standard NES controller, read one button at a time through $4016 and $4017.
+// See https://www.nesdev.org/wiki/Standard_controller
+use bitflags::bitflags;
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+bitflags! {
+    // In the order the controller shifts them out
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+    pub struct Buttons: u8 {
+        const A = 0b0000_0001;
+        const B = 0b0000_0010;
+        const Select = 0b0000_0100;
+        const Start = 0b0000_1000;
+        const Up = 0b0001_0000;
+        const Down = 0b0010_0000;
+        const Left = 0b0100_0000;
+        const Right = 0b1000_0000;
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Controller {
+    pub buttons: Buttons,
+    // While the strobe is high the shift register keeps reloading, so reads always return A
+    strobe: bool,
+    shift_register: u8,
+}
+
+impl Controller {
+    // $4016 writes, which strobe both controllers
+    pub fn write_strobe(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.shift_register = self.buttons.bits();
+        }
+    }
+
+    // After the 8 buttons official controllers return 1s
+    pub fn read(&mut self) -> u8 {
+        let value = self.peek();
+        if self.strobe {
+            self.shift_register = self.buttons.bits();
+        } else {
+            self.shift_register = (self.shift_register >> 1) | 0x80;
+        }
+        value
+    }
+
+    // The bit the next read would return, without shifting
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons.bits() & 1
+        } else {
+            self.shift_register & 1
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.buttons.bits());
+        state.write(&self.strobe);
+        state.write(&self.shift_register);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = Buttons::from_bits_truncate(state.read()?);
+        self.strobe = state.read()?;
+        self.shift_register = state.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_read_buttons() {
+        let mut controller = Controller {
+            buttons: Buttons::A | Buttons::Start | Buttons::Right,
+            ..Default::default()
+        };
+        controller.write_strobe(1);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+        controller.write_strobe(0);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+}
@@ -1,16 +1,21 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use bitflags::Flags;
 
 use crate::mapper::Mapper;
 use crate::memory_bus::MemoryBus;
 use crate::opcodes::{Instruction, CPU_OPCODES};
 use crate::ppu::{ControlFlags, StatusFlags};
+use crate::state::{StateError, StateReader, StateWriter};
 use bitflags::bitflags;
-use std::intrinsics::wrapping_add;
 
 pub const RESET_VECTOR: u16 = 0xFFFC;
 const SP_START: u8 = 0xFD;
 const STATUS_DEFAULT: u8 = 0b0010_0100;
 const NMI_ADDRESS: u16 = 0xFFFA;
+const IRQ_ADDRESS: u16 = 0xFFFE;
 
 pub struct Cpu {
     pub pc: u16,                 // Program counter
@@ -20,7 +25,8 @@ pub struct Cpu {
     pub y: u8,                   // Y register
     pub status: ProcessorStatus, // Status register
     pub cycles: u32,
-    pub memory_bus: MemoryBus, // Memory
+    pub ppu_dot_remainder: u32, // Fraction of a ppu dot left over from the last instruction, in 1/denominator dots
+    pub memory_bus: MemoryBus,  // Memory
 }
 
 impl Cpu {
@@ -33,6 +39,7 @@ impl Cpu {
             y: 0,
             status: ProcessorStatus::from_bits_truncate(STATUS_DEFAULT),
             cycles: 0,
+            ppu_dot_remainder: 0,
             memory_bus,
         }
     }
@@ -45,18 +52,70 @@ impl Cpu {
         self.status = ProcessorStatus::from_bits_truncate(STATUS_DEFAULT);
         self.cycles = 0;
     }
+    // Snapshots the whole machine, see state.rs for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write(&self.pc);
+        state.write(&self.sp);
+        state.write(&self.a);
+        state.write(&self.x);
+        state.write(&self.y);
+        state.write(&self.status.bits());
+        state.write(&self.cycles);
+        state.write(&self.ppu_dot_remainder);
+        self.memory_bus.save_state(&mut state);
+        state.bytes
+    }
+
+    // A state that fails to load part way through leaves the machine as it was
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(bytes)?;
+        let backup = self.save_state();
+        self.read_state(&mut state).and_then(|_| state.finish()).inspect_err(|_| {
+            let mut backup = StateReader::new(&backup).expect("backup state has a valid header");
+            self.read_state(&mut backup).expect("backup state loads");
+        })
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pc = state.read()?;
+        self.sp = state.read()?;
+        self.a = state.read()?;
+        self.x = state.read()?;
+        self.y = state.read()?;
+        self.status = ProcessorStatus::from_bits_truncate(state.read()?);
+        self.cycles = state.read()?;
+        self.ppu_dot_remainder = state.read()?;
+        self.memory_bus.load_state(state)
+    }
+
     pub fn instruction_cycle(&mut self) {
         if self.memory_bus.ppu.control_register.contains(ControlFlags::GenerateNmi) {
             self.memory_bus.ppu.control_register.set(ControlFlags::GenerateNmi, false);
             self.interrupt_nmi();
+        } else if self.memory_bus.irq_pending() && !self.status.contains(ProcessorStatus::InterruptDisable) {
+            self.interrupt_irq();
         }
 
         let instruction = self.fetch();
         let cycles = self.execute(&instruction);
         self.cycles += cycles as u32;
-        for _ in 0..cycles * 3 {
+
+        // NTSC runs exactly 3 ppu dots per cpu cycle but PAL runs 3.2, so the leftover fraction is carried over
+        let (dots, denominator) = self.memory_bus.ppu.region.ppu_dots_per_cpu_cycle();
+        let total_dots = cycles as u32 * dots + self.ppu_dot_remainder;
+        for _ in 0..total_dots / denominator {
             self.memory_bus.ppu.step();
         }
+        self.ppu_dot_remainder = total_dots % denominator;
+        self.memory_bus.clock(cycles as u32);
+    }
+    // Runs until the ppu finishes the frame it's on
+    pub fn run_frame(&mut self) {
+        let frame_count = self.memory_bus.ppu.frame_count;
+        while self.memory_bus.ppu.frame_count == frame_count {
+            self.instruction_cycle();
+        }
     }
     pub fn fetch(&self) -> Instruction {
         let opcode = self.memory_bus.debug_read(self.pc) as usize;
@@ -158,19 +217,19 @@ impl Cpu {
             AddressingMode::Absolute => self.memory_bus.read_word(self.pc),
             AddressingMode::ZeroPageX => {
                 let addr = self.memory_bus.read(self.pc);
-                wrapping_add(addr, self.x) as u16
+                addr.wrapping_add(self.x) as u16
             }
             AddressingMode::ZeroPageY => {
                 let addr = self.memory_bus.read(self.pc);
-                wrapping_add(addr, self.y) as u16
+                addr.wrapping_add(self.y) as u16
             }
             AddressingMode::AbsoluteX => {
                 let addr = self.memory_bus.read_word(self.pc);
-                wrapping_add(addr, self.x as u16)
+                addr.wrapping_add(self.x as u16)
             }
             AddressingMode::AbsoluteY => {
                 let addr = self.memory_bus.read_word(self.pc);
-                wrapping_add(addr, self.y as u16)
+                addr.wrapping_add(self.y as u16)
             }
             AddressingMode::IndirectX => {
                 let zero_page_addr = self.memory_bus.read(self.pc);
@@ -238,6 +297,19 @@ impl Cpu {
         self.cycles += 2;
         self.pc = self.memory_bus.read_word(NMI_ADDRESS);
     }
+    fn interrupt_irq(&mut self) {
+        self.push_word(self.pc);
+        // Unlike BRK, an IRQ pushes the status with the break flag clear
+        let mut flag = self.status.clone();
+        flag.set(ProcessorStatus::Break, false);
+        flag.set(ProcessorStatus::Break1, true);
+
+        self.push(flag.bits());
+        self.status.set(ProcessorStatus::InterruptDisable, true);
+
+        self.cycles += 7;
+        self.pc = self.memory_bus.read_word(IRQ_ADDRESS);
+    }
 }
 
 bitflags! {
@@ -285,10 +357,11 @@ mod tests {
     use crate::rom::Rom;
 
     use super::*;
+    use alloc::vec;
 
     #[test]
     fn test_stack() {
-        let mut cpu = Cpu::new(MemoryBus::new(Rom::default()));
+        let mut cpu = Cpu::new(MemoryBus::new(Rom::default()).unwrap());
 
         let pushed_byte = 164;
         cpu.push(pushed_byte);
@@ -300,4 +373,82 @@ mod tests {
 
         assert_eq!(cpu.pull_word(), pushed_word);
     }
+
+    // An NROM game that turns the background on and keeps changing the scroll, a palette entry and a pulse channel
+    fn state_test_rom(chr_banks: u8) -> Rom {
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, chr_banks];
+        bytes.resize(16, 0);
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x08, 0x8D, 0x01, 0x20, // LDA #$08, STA $2001
+            0xA9, 0x01, 0x8D, 0x15, 0x40, // LDA #$01, STA $4015
+            0xE8,                         // loop: INX
+            0x8E, 0x05, 0x20,             // STX $2005
+            0x8E, 0x05, 0x20,             // STX $2005
+            0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F, STA $2006
+            0x8E, 0x06, 0x20,             // STX $2006
+            0x8E, 0x07, 0x20,             // STX $2007
+            0x8E, 0x00, 0x40,             // STX $4000
+            0x8E, 0x03, 0x40,             // STX $4003
+            0x4C, 0x0A, 0x80,             // JMP loop
+        ];
+        let mut prg_rom = program.to_vec();
+        prg_rom.resize(0x4000, 0);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        bytes.extend(prg_rom);
+        bytes.extend((0..0x2000 * chr_banks as usize).map(|i| (i * 7) as u8));
+        Rom::new(&bytes).unwrap()
+    }
+
+    fn run_frame(cpu: &mut Cpu) -> Vec<u16> {
+        loop {
+            let scanline = cpu.memory_bus.ppu.scanline;
+            cpu.instruction_cycle();
+            if cpu.memory_bus.ppu.scanline < scanline {
+                return cpu.memory_bus.ppu.frame.pixels.clone();
+            }
+        }
+    }
+
+    #[test]
+    fn test_state_mid_frame() {
+        let mut cpu = Cpu::new(MemoryBus::new(state_test_rom(1)).unwrap());
+        run_frame(&mut cpu);
+        while cpu.memory_bus.ppu.scanline < 100 {
+            cpu.instruction_cycle();
+        }
+        let state = cpu.save_state();
+        let frames: Vec<Vec<u16>> = (0..3).map(|_| run_frame(&mut cpu)).collect();
+        assert!(frames[0].iter().any(|pixel| *pixel != frames[0][0]));
+
+        // Loading into the same machine after it moved on
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.save_state(), state);
+        let reloaded_frames: Vec<Vec<u16>> = (0..3).map(|_| run_frame(&mut cpu)).collect();
+        assert!(reloaded_frames == frames);
+
+        // Loading into a freshly powered on machine
+        let mut cpu = Cpu::new(MemoryBus::new(state_test_rom(1)).unwrap());
+        cpu.load_state(&state).unwrap();
+        let fresh_frames: Vec<Vec<u16>> = (0..3).map(|_| run_frame(&mut cpu)).collect();
+        assert!(fresh_frames == frames);
+    }
+
+    #[test]
+    fn test_bad_state_leaves_machine_alone() {
+        let mut cpu = Cpu::new(MemoryBus::new(state_test_rom(1)).unwrap());
+        run_frame(&mut cpu);
+        let state = cpu.save_state();
+        cpu.instruction_cycle();
+        let current_state = cpu.save_state();
+
+        assert_eq!(cpu.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        let mut longer_state = state.clone();
+        longer_state.push(0);
+        assert_eq!(cpu.load_state(&longer_state), Err(StateError::Mismatch("state size")));
+        // A game with twice the CHR ROM
+        let other_state = Cpu::new(MemoryBus::new(state_test_rom(2)).unwrap()).save_state();
+        assert_eq!(cpu.load_state(&other_state), Err(StateError::Mismatch("memory size")));
+        assert_eq!(cpu.save_state(), current_state);
+    }
 }
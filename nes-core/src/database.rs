@@ -0,0 +1,286 @@
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::hash::{crc32_update, Sha1};
+use crate::lazy::Lazy;
+use crate::rom::{ConsoleType, Mirroring, TimingMode};
+
+// Games whose iNES headers are known to be wrong, in the NES 2.0 XML database format. Only nestest is bundled for now.
+// See https://www.nesdev.org/wiki/NES_2.0_XML_Database
+pub static BUILTIN_DATABASE: Lazy<Database> =
+    Lazy::new(|| Database::parse(include_str!("../data/nes20db.xml")).expect("The bundled rom database should be valid"));
+
+// What the database knows about a single game, the fields mirror a NES 2.0 header
+#[derive(Debug, PartialEq, Clone)]
+pub struct GameInfo {
+    // Taken from the file name in the comment at the start of the entry
+    pub title: String,
+    // Checksums of the PRG ROM followed by the CHR ROM
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: u16,
+    pub submapper: u8,
+    // None for boards where the mapper controls mirroring
+    pub mirroring: Option<Mirroring>,
+    pub has_battery: bool,
+    // None when the entry has no <prgram> or <prgnvram>, which leaves the header's PRG RAM size alone
+    pub prg_ram_size: Option<usize>,
+    pub prg_nvram_size: Option<usize>,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: TimingMode,
+    pub console_type: ConsoleType,
+    // See https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+    pub expansion_device: u8,
+}
+
+impl GameInfo {
+    pub fn expansion_device_name(&self) -> &'static str {
+        expansion_device_name(self.expansion_device)
+    }
+}
+
+#[derive(Default)]
+pub struct Database {
+    // Entries indexed by crc32, different games can share a crc32 so the sha1 settles it
+    games: BTreeMap<u32, Vec<GameInfo>>,
+}
+
+impl Database {
+    pub fn parse(xml: &str) -> Result<Self, String> {
+        let mut database = Database::default();
+        let mut game: Option<GameInfo> = None;
+        let mut rest = xml;
+
+        while let Some(start) = rest.find('<') {
+            rest = &rest[start..];
+            if let Some(comment) = rest.strip_prefix("<!--") {
+                let end = comment.find("-->").ok_or("Unterminated comment")?;
+                if let Some(game) = game.as_mut().filter(|game| game.title.is_empty()) {
+                    let file_name = comment[..end].trim();
+                    game.title = file_name.strip_suffix(".nes").unwrap_or(file_name).to_owned();
+                }
+                rest = &comment[end + 3..];
+                continue;
+            }
+
+            let end = rest.find('>').ok_or("Unterminated tag")?;
+            let tag = rest[1..end].trim_end_matches('/').trim();
+            rest = &rest[end + 1..];
+            if tag.starts_with('?') || tag.starts_with('!') {
+                continue;
+            }
+
+            let (name, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+            let attributes = parse_attributes(attributes)?;
+            let attribute = |key: &str| attributes.iter().find(|(name, _)| *name == key).map(|(_, value)| *value);
+            let number = |key: &str| attribute(key).map_or(Ok(0), parse_number);
+
+            if name == "game" {
+                game = Some(GameInfo {
+                    title: String::new(),
+                    crc32: 0,
+                    sha1: None,
+                    mapper: 0,
+                    submapper: 0,
+                    mirroring: None,
+                    has_battery: false,
+                    prg_ram_size: None,
+                    prg_nvram_size: None,
+                    chr_ram_size: 0,
+                    chr_nvram_size: 0,
+                    timing: TimingMode::Ntsc,
+                    console_type: ConsoleType::Nes,
+                    expansion_device: 0,
+                });
+                continue;
+            }
+            if name == "/game" {
+                let game = game.take().ok_or("</game> without <game>")?;
+                database.games.entry(game.crc32).or_default().push(game);
+                continue;
+            }
+            let Some(game) = game.as_mut() else {
+                continue;
+            };
+
+            match name {
+                "rom" => {
+                    game.crc32 = u32::from_str_radix(attribute("crc32").ok_or("<rom> without a crc32")?, 16).map_err(|error| error.to_string())?;
+                    game.sha1 = attribute("sha1").map(parse_sha1).transpose()?;
+                }
+                "pcb" => {
+                    game.mapper = number("mapper")? as u16;
+                    game.submapper = number("submapper")? as u8;
+                    game.has_battery = number("battery")? != 0;
+                    game.mirroring = match attribute("mirroring") {
+                        Some("H") => Some(Mirroring::Horizontal),
+                        Some("V") => Some(Mirroring::Vertical),
+                        Some("4") => Some(Mirroring::FourScreen),
+                        _ => None,
+                    };
+                }
+                "prgram" => game.prg_ram_size = Some(number("size")?),
+                "prgnvram" => game.prg_nvram_size = Some(number("size")?),
+                "chrram" => game.chr_ram_size = number("size")?,
+                "chrnvram" => game.chr_nvram_size = number("size")?,
+                "console" => {
+                    game.timing = match number("region")? {
+                        0 => TimingMode::Ntsc,
+                        1 => TimingMode::Pal,
+                        2 => TimingMode::MultiRegion,
+                        _ => TimingMode::Dendy,
+                    };
+                    game.console_type = match number("type")? {
+                        0 => ConsoleType::Nes,
+                        1 => ConsoleType::VsSystem {
+                            ppu_type: 0,
+                            hardware_type: 0,
+                        },
+                        2 => ConsoleType::Playchoice10,
+                        console_type => ConsoleType::Extended(console_type as u8),
+                    };
+                }
+                "vs" => {
+                    game.console_type = ConsoleType::VsSystem {
+                        ppu_type: number("ppu")? as u8,
+                        hardware_type: number("hardware")? as u8,
+                    }
+                }
+                "expansion" => game.expansion_device = number("type")? as u8,
+                _ => {}
+            }
+        }
+
+        Ok(database)
+    }
+
+    pub fn find(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<&GameInfo> {
+        let crc32 = crc32_update(crc32_update(0, prg_rom), chr_rom);
+        let games = self.games.get(&crc32)?;
+        // Hashing is slow enough that the sha1 is only computed once a crc32 matches
+        let mut sha1 = Sha1::new();
+        sha1.update(prg_rom);
+        sha1.update(chr_rom);
+        let sha1 = sha1.finish();
+        games.iter().find(|game| game.sha1.is_none_or(|game_sha1| game_sha1 == sha1))
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+}
+
+// Splits name="value" pairs
+fn parse_attributes(mut attributes: &str) -> Result<Vec<(&str, &str)>, String> {
+    let mut pairs = Vec::new();
+    loop {
+        attributes = attributes.trim_start();
+        if attributes.is_empty() {
+            return Ok(pairs);
+        }
+        let (name, rest) = attributes
+            .split_once('=')
+            .ok_or_else(|| format!("Attribute without a value: {}", attributes))?;
+        let rest = rest.trim_start();
+        let quote = rest
+            .chars()
+            .next()
+            .filter(|quote| *quote == '"' || *quote == '\'')
+            .ok_or("Unquoted attribute value")?;
+        let end = rest[1..].find(quote).ok_or("Unterminated attribute value")?;
+        pairs.push((name.trim(), &rest[1..end + 1]));
+        attributes = &rest[end + 2..];
+    }
+}
+
+fn parse_number(value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("Invalid number: {}", value))
+}
+
+fn parse_sha1(value: &str) -> Result<[u8; 20], String> {
+    if value.len() != 40 || !value.is_ascii() {
+        return Err(format!("Invalid sha1: {}", value));
+    }
+    let mut sha1 = [0; 20];
+    for (index, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[index * 2..index * 2 + 2], 16).map_err(|_| format!("Invalid sha1: {}", value))?;
+    }
+    Ok(sha1)
+}
+
+// See https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+pub fn expansion_device_name(device: u8) -> &'static str {
+    match device {
+        0x00 => "Unspecified",
+        0x01 => "Standard controllers",
+        0x02 => "Four Score",
+        0x03 => "Famicom four player adapter",
+        0x04 => "Vs. System controllers ($4016)",
+        0x05 => "Vs. System controllers ($4017)",
+        0x07 => "Vs. Zapper",
+        0x08 => "Zapper",
+        0x09 => "Two Zappers",
+        0x0A => "Bandai Hyper Shot",
+        0x0B => "Power Pad side A",
+        0x0C => "Power Pad side B",
+        0x0D => "Family Trainer side A",
+        0x0E => "Family Trainer side B",
+        0x0F => "Arkanoid paddle (NES)",
+        0x10 => "Arkanoid paddle (Famicom)",
+        0x11 => "Two Arkanoid paddles and data recorder",
+        0x15 => "Family BASIC keyboard and data recorder",
+        0x1A => "Oeka Kids tablet",
+        0x1F => "Famicom Network controller",
+        0x23 => "SNES mouse",
+        _ => "Other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DATABASE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db>
+<game>
+	<!-- Test Game (Europe).nes -->
+	<rom size="3" crc32="352441C2" sha1="A9993E364706816ABA3E25717850C26C9CD0D89D"/>
+	<pcb mapper="1" submapper="5" mirroring="V" battery="1"/>
+	<prgnvram size="8192"/>
+	<chrram size="8192"/>
+	<console type="0" region="1"/>
+	<expansion type="8"/>
+</game>
+</nes20db>
+"#;
+
+    #[test]
+    fn test_parse() {
+        let database = Database::parse(TEST_DATABASE).unwrap();
+        assert_eq!(database.len(), 1);
+
+        let game = database.find(b"ab", b"c").unwrap();
+        assert_eq!(game.title, "Test Game (Europe)");
+        assert_eq!((game.mapper, game.submapper), (1, 5));
+        assert_eq!(game.mirroring, Some(Mirroring::Vertical));
+        assert!(game.has_battery);
+        assert_eq!((game.prg_ram_size, game.prg_nvram_size, game.chr_ram_size), (None, Some(8192), 8192));
+        assert_eq!(game.timing, TimingMode::Pal);
+        assert_eq!(game.expansion_device_name(), "Zapper");
+
+        assert!(database.find(b"abd", b"").is_none());
+    }
+
+    #[test]
+    fn test_builtin_database() {
+        assert!(!BUILTIN_DATABASE.is_empty());
+    }
+}
@@ -0,0 +1,675 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fds_audio::FdsAudio;
+use crate::mapper::Mapper;
+use crate::rom::{Mirroring, RomError};
+use crate::state::{StateError, StateReader, StateWriter};
+
+// The fwNES header some .fds files start with, "FDS<EOF>" followed by the number of sides
+const FWNES_MAGIC: [u8; 4] = [0x46, 0x44, 0x53, 0x1A];
+const FWNES_HEADER_SIZE: usize = 16;
+// Every disk side starts with a disk info block, which starts with its block code and "*NINTENDO-HVC*"
+const DISK_INFO_MAGIC: &[u8] = b"\x01*NINTENDO-HVC*";
+// The size of a side in a .fds file, which only holds the blocks without their gaps and CRCs
+pub const DISK_SIDE_SIZE: usize = 65500;
+pub const BIOS_SIZE: usize = 8192;
+pub const PRG_RAM_SIZE: usize = 32768;
+pub const CHR_RAM_SIZE: usize = 8192;
+
+// The drive reads the first block after 28300 bits of gap, blocks are separated by 976 bits of gap
+const LEADING_GAP_BYTES: usize = 28300 / 8;
+const BLOCK_GAP_BYTES: usize = 976 / 8;
+const BLOCK_START_MARK: u8 = 0x80;
+// A byte goes past the head roughly every 150 cpu cycles
+const CYCLES_PER_BYTE: u32 = 150;
+// How long the head takes to go back to the start of the disk once it reaches the end
+const REWIND_CYCLES: u32 = 50000;
+// A newly inserted disk reads as missing for about a second, so games notice when the side changes
+pub const INSERT_DELAY_CYCLES: u32 = 1_789_773;
+
+pub fn is_fds_image(bytes: &[u8]) -> bool {
+    bytes.starts_with(&FWNES_MAGIC) || bytes.starts_with(DISK_INFO_MAGIC)
+}
+
+// Splits a .fds file into its disk sides, with or without the fwNES header
+pub fn parse_disk_sides(bytes: &[u8]) -> Result<Vec<Vec<u8>>, RomError> {
+    let data = if bytes.starts_with(&FWNES_MAGIC) {
+        bytes.get(FWNES_HEADER_SIZE..).unwrap_or_default()
+    } else {
+        bytes
+    };
+    // Some dumps are missing padding at the end of the last side
+    let side_count = data.len().div_ceil(DISK_SIDE_SIZE);
+    if side_count == 0 {
+        return Err(RomError::NoPrgRom);
+    }
+    let sides: Vec<Vec<u8>> = data
+        .chunks(DISK_SIDE_SIZE)
+        .map(|side| {
+            let mut side = side.to_vec();
+            side.resize(DISK_SIDE_SIZE, 0);
+            side
+        })
+        .collect();
+    if !sides.iter().all(|side| side.starts_with(DISK_INFO_MAGIC)) {
+        return Err(RomError::BadMagic);
+    }
+    Ok(sides)
+}
+
+// Adds the gaps, block start marks and CRCs the drive sees between the blocks stored in a .fds file
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEADING_GAP_BYTES];
+    let mut position = 0;
+    let mut file_size = 0;
+    while position < side.len() {
+        let block_size = match side[position] {
+            // Disk info, file count, file header and file data
+            1 => 56,
+            2 => 2,
+            3 => {
+                file_size = side
+                    .get(position + 13..position + 15)
+                    .map_or(0, |size| u16::from_le_bytes([size[0], size[1]]) as usize);
+                16
+            }
+            4 => 1 + file_size,
+            // Anything else is the unused space after the last file
+            _ => break,
+        };
+        let Some(block) = side.get(position..position + block_size) else {
+            break;
+        };
+
+        let mut crc = 0;
+        for byte in [BLOCK_START_MARK].iter().chain(block) {
+            crc = update_crc(crc, *byte);
+        }
+        crc = update_crc(update_crc(crc, 0), 0);
+        raw.push(BLOCK_START_MARK);
+        raw.extend_from_slice(block);
+        raw.extend(crc.to_le_bytes());
+        raw.extend([0; BLOCK_GAP_BYTES]);
+        position += block_size;
+    }
+    raw.resize(raw.len().max(DISK_SIDE_SIZE), 0);
+    raw
+}
+
+// The drive's CRC, which covers the block start mark and the block. Feeding it the CRC itself gives 0
+fn update_crc(mut crc: u16, value: u8) -> u16 {
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if value & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+// The disk drive of the RAM adapter, which streams bytes to and from the inserted disk side.
+// See https://www.nesdev.org/wiki/Family_Computer_Disk_System#Drive
+pub struct DiskDrive {
+    // Every side of every disk one after another, with gaps and CRCs added
+    image: Vec<u8>,
+    // Start and length of each side in the image
+    sides: Vec<(usize, usize)>,
+    inserted_side: Option<usize>,
+    insert_delay: u32,
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    previous_crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    transfer_complete: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+    crc: u16,
+    read_data: u8,
+    write_data: u8,
+}
+
+impl DiskDrive {
+    pub fn new(sides: Vec<Vec<u8>>) -> Self {
+        let mut image = Vec::new();
+        let mut side_ranges = Vec::new();
+        for side in &sides {
+            let raw = add_gaps(side);
+            side_ranges.push((image.len(), raw.len()));
+            image.extend(raw);
+        }
+        DiskDrive {
+            image,
+            sides: side_ranges,
+            inserted_side: Some(0),
+            insert_delay: 0,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            previous_crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            transfer_complete: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            position: 0,
+            delay: 0,
+            crc: 0,
+            read_data: 0,
+            write_data: 0,
+        }
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    pub fn inserted_side(&self) -> Option<usize> {
+        self.inserted_side.filter(|_| self.insert_delay == 0)
+    }
+
+    // Disk 1 side A, disk 1 side B, disk 2 side A and so on
+    pub fn side_name(side: usize) -> String {
+        format!("Disk {} side {}", side / 2 + 1, if side.is_multiple_of(2) { 'A' } else { 'B' })
+    }
+
+    pub fn eject(&mut self) {
+        self.inserted_side = None;
+        self.insert_delay = 0;
+    }
+
+    // Swapping disks takes a moment, during which the drive reports no disk
+    pub fn insert(&mut self, side: usize) {
+        if side < self.sides.len() {
+            self.inserted_side = Some(side);
+            self.insert_delay = INSERT_DELAY_CYCLES;
+        }
+    }
+
+    fn is_inserted(&self) -> bool {
+        self.inserted_side().is_some()
+    }
+
+    // $4024
+    fn write_data(&mut self, value: u8) {
+        self.write_data = value;
+        self.transfer_complete = false;
+        self.disk_irq = false;
+    }
+
+    // $4025
+    fn write_control(&mut self, value: u8) {
+        self.motor_on = value & 0b1 != 0;
+        self.reset_transfer = value & 0b10 != 0;
+        self.read_mode = value & 0b100 != 0;
+        self.crc_control = value & 0b1_0000 != 0;
+        self.disk_ready = value & 0b100_0000 != 0;
+        self.disk_irq_enabled = value & 0b1000_0000 != 0;
+        self.disk_irq = false;
+    }
+
+    // $4031
+    fn read_data(&mut self) -> u8 {
+        self.transfer_complete = false;
+        self.disk_irq = false;
+        self.read_data
+    }
+
+    // $4032
+    fn status(&self) -> u8 {
+        let not_inserted = !self.is_inserted() as u8;
+        let not_ready = (!self.is_inserted() || !self.scanning) as u8;
+        // Disks are never write protected, so that bit only shows when there is no disk
+        not_inserted | not_ready << 1 | not_inserted << 2
+    }
+
+    fn clock(&mut self) {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+            return;
+        }
+        let Some(side) = self.inserted_side.filter(|_| self.motor_on) else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let (start, length) = self.sides[side];
+        let disk = &mut self.image[start..start + length];
+        let mut irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let byte = disk[self.position];
+            if !self.previous_crc_control {
+                self.crc = update_crc(self.crc, byte);
+            }
+            if !self.disk_ready {
+                self.gap_ended = false;
+                self.crc = 0;
+            } else if byte != 0 && !self.gap_ended {
+                // The block start mark ends the gap, the byte after it is the first one transferred
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = byte;
+                self.disk_irq |= irq;
+            }
+        } else {
+            let mut byte = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                byte = self.write_data;
+                self.disk_irq |= irq;
+            }
+            if !self.disk_ready {
+                byte = 0;
+            }
+            if !self.crc_control {
+                self.crc = update_crc(self.crc, byte);
+            } else {
+                // With CRC control set the drive writes the two CRC bytes of the block
+                if !self.previous_crc_control {
+                    self.crc = update_crc(update_crc(self.crc, 0), 0);
+                }
+                byte = self.crc as u8;
+                self.crc >>= 8;
+            }
+            disk[self.position] = byte;
+            self.gap_ended = false;
+        }
+
+        self.previous_crc_control = self.crc_control;
+        self.position += 1;
+        if self.position >= disk.len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = CYCLES_PER_BYTE;
+        }
+    }
+
+    // The whole image is saved since games write to their disks
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.image);
+        state.write(&self.inserted_side);
+        state.write(&self.insert_delay);
+        state.write(&self.motor_on);
+        state.write(&self.reset_transfer);
+        state.write(&self.read_mode);
+        state.write(&self.crc_control);
+        state.write(&self.previous_crc_control);
+        state.write(&self.disk_ready);
+        state.write(&self.disk_irq_enabled);
+        state.write(&self.disk_irq);
+        state.write(&self.transfer_complete);
+        state.write(&self.end_of_head);
+        state.write(&self.scanning);
+        state.write(&self.gap_ended);
+        state.write(&self.position);
+        state.write(&self.delay);
+        state.write(&self.crc);
+        state.write(&self.read_data);
+        state.write(&self.write_data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.image)?;
+        self.inserted_side = state.read()?;
+        if self.inserted_side.is_some_and(|side| side >= self.sides.len()) {
+            return Err(StateError::Mismatch("disk side"));
+        }
+        self.insert_delay = state.read()?;
+        self.motor_on = state.read()?;
+        self.reset_transfer = state.read()?;
+        self.read_mode = state.read()?;
+        self.crc_control = state.read()?;
+        self.previous_crc_control = state.read()?;
+        self.disk_ready = state.read()?;
+        self.disk_irq_enabled = state.read()?;
+        self.disk_irq = state.read()?;
+        self.transfer_complete = state.read()?;
+        self.end_of_head = state.read()?;
+        self.scanning = state.read()?;
+        self.gap_ended = state.read()?;
+        self.position = state.read()?;
+        if self.sides.iter().all(|(_, length)| self.position >= *length) {
+            return Err(StateError::Mismatch("disk position"));
+        }
+        self.delay = state.read()?;
+        self.crc = state.read()?;
+        self.read_data = state.read()?;
+        self.write_data = state.read()?;
+        Ok(())
+    }
+}
+
+// The RAM adapter, which holds 32 KiB of PRG RAM, 8 KiB of CHR RAM, the BIOS, a timer and the disk drive interface.
+// This is iNES mapper 20. See https://www.nesdev.org/wiki/Family_Computer_Disk_System
+pub struct FdsMapper {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    pub drive: DiskDrive,
+    pub audio: FdsAudio,
+    disk_registers_enabled: bool,
+    sound_registers_enabled: bool,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+    horizontal_mirroring: bool,
+}
+
+impl FdsMapper {
+    pub fn new(bios: Vec<u8>, disk_sides: Vec<Vec<u8>>) -> Self {
+        FdsMapper {
+            bios,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            drive: DiskDrive::new(disk_sides),
+            audio: FdsAudio::new(),
+            disk_registers_enabled: true,
+            sound_registers_enabled: true,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            horizontal_mirroring: false,
+        }
+    }
+}
+
+impl Mapper for FdsMapper {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x4030 if self.disk_registers_enabled => {
+                self.timer_irq as u8 | (self.drive.transfer_complete as u8) << 1 | (self.drive.end_of_head as u8) << 6
+            }
+            0x4031 if self.disk_registers_enabled => self.drive.read_data,
+            // Bit 6 is open bus, which is almost always set from the high byte of the address
+            0x4032 if self.disk_registers_enabled => self.drive.status() | 0x40,
+            // Bit 7 is set while the battery is good
+            0x4033 if self.disk_registers_enabled => 0x80,
+            0x4040..=0x4092 if self.sound_registers_enabled => self.audio.read(address) | 0x40,
+            0x6000..=0xDFFF => self.prg_ram[(address - 0x6000) as usize],
+            0xE000..=0xFFFF => self.bios[(address - 0xE000) as usize % self.bios.len()],
+            _ => 0,
+        }
+    }
+
+    fn read_mut(&mut self, address: u16) -> u8 {
+        let value = self.read(address);
+        match address {
+            // Reading the status acknowledges both interrupts
+            0x4030 if self.disk_registers_enabled => {
+                self.timer_irq = false;
+                self.drive.disk_irq = false;
+                self.drive.transfer_complete = false;
+            }
+            0x4031 if self.disk_registers_enabled => {
+                self.drive.read_data();
+            }
+            _ => {}
+        }
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | value as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = value & 0b1 != 0;
+                self.timer_enabled = value & 0b10 != 0 && self.disk_registers_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers_enabled = value & 0b1 != 0;
+                self.sound_registers_enabled = value & 0b10 != 0;
+                if !self.disk_registers_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.drive.disk_irq = false;
+                }
+            }
+            0x4024 if self.disk_registers_enabled => self.drive.write_data(value),
+            0x4025 if self.disk_registers_enabled => {
+                self.horizontal_mirroring = value & 0b1000 != 0;
+                self.drive.write_control(value);
+            }
+            0x4040..=0x4092 if self.sound_registers_enabled => self.audio.write(address, value),
+            0x6000..=0xDFFF => self.prg_ram[(address - 0x6000) as usize] = value,
+            // The BIOS is ROM, and $4026 drives the expansion port which has nothing connected
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self, cpu_cycles: u32) {
+        for _ in 0..cpu_cycles {
+            if self.timer_enabled {
+                if self.timer_counter == 0 {
+                    self.timer_irq = true;
+                    self.timer_counter = self.timer_reload;
+                    self.timer_enabled = self.timer_repeat;
+                } else {
+                    self.timer_counter -= 1;
+                }
+            }
+
+            self.drive.clock();
+            self.audio.clock();
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.drive.disk_irq
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(if self.horizontal_mirroring {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        })
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+        self.drive.save_state(state);
+        self.audio.save_state(state);
+        state.write(&self.disk_registers_enabled);
+        state.write(&self.sound_registers_enabled);
+        state.write(&self.timer_reload);
+        state.write(&self.timer_counter);
+        state.write(&self.timer_repeat);
+        state.write(&self.timer_enabled);
+        state.write(&self.timer_irq);
+        state.write(&self.horizontal_mirroring);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.prg_ram)?;
+        self.drive.load_state(state)?;
+        self.audio.load_state(state)?;
+        self.disk_registers_enabled = state.read()?;
+        self.sound_registers_enabled = state.read()?;
+        self.timer_reload = state.read()?;
+        self.timer_counter = state.read()?;
+        self.timer_repeat = state.read()?;
+        self.timer_enabled = state.read()?;
+        self.timer_irq = state.read()?;
+        self.horizontal_mirroring = state.read()?;
+        Ok(())
+    }
+
+    // Disk writes are kept in the save file instead of modifying the .fds file
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.drive.image)
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        // A save from a different version of the disk would corrupt it
+        if data.len() == self.drive.image.len() {
+            self.drive.image.copy_from_slice(data);
+        }
+    }
+
+    fn disk_drive(&mut self) -> Option<&mut DiskDrive> {
+        Some(&mut self.drive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk_side() -> Vec<u8> {
+        let mut side = DISK_INFO_MAGIC.to_vec();
+        side.resize(56, 0);
+        // One file of 3 bytes
+        side.extend([2, 1]);
+        let mut file_header = vec![3; 16];
+        file_header[13..15].copy_from_slice(&3u16.to_le_bytes());
+        side.extend(file_header);
+        side.extend([4, 0xAA, 0xBB, 0xCC]);
+        side.resize(DISK_SIDE_SIZE, 0);
+        side
+    }
+
+    fn fds_mapper() -> FdsMapper {
+        FdsMapper::new(vec![0; BIOS_SIZE], vec![disk_side(), disk_side()])
+    }
+
+    #[test]
+    fn test_parse_disk_sides() {
+        let mut headered = FWNES_MAGIC.to_vec();
+        headered.push(2);
+        headered.resize(FWNES_HEADER_SIZE, 0);
+        headered.extend(disk_side());
+        headered.extend(disk_side());
+        assert!(is_fds_image(&headered));
+        assert_eq!(parse_disk_sides(&headered).unwrap().len(), 2);
+
+        // Headerless, and missing the padding at the end
+        let headerless = &disk_side()[..1000];
+        assert!(is_fds_image(headerless));
+        let sides = parse_disk_sides(headerless).unwrap();
+        assert_eq!(sides.len(), 1);
+        assert_eq!(sides[0], disk_side());
+    }
+
+    #[test]
+    fn test_gaps_and_crc() {
+        let raw = add_gaps(&disk_side());
+        assert!(raw[..LEADING_GAP_BYTES].iter().all(|byte| *byte == 0));
+        assert_eq!(raw[LEADING_GAP_BYTES], BLOCK_START_MARK);
+        assert_eq!(&raw[LEADING_GAP_BYTES + 1..LEADING_GAP_BYTES + 16], DISK_INFO_MAGIC);
+
+        // Running the CRC over the start mark, the block and its CRC gives 0
+        let crc = raw[LEADING_GAP_BYTES..LEADING_GAP_BYTES + 1 + 56 + 2]
+            .iter()
+            .fold(0, |crc, byte| update_crc(crc, *byte));
+        assert_eq!(crc, 0);
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut mapper = fds_mapper();
+        mapper.write(0x4020, 10);
+        mapper.write(0x4021, 0);
+        mapper.write(0x4022, 0b10);
+        mapper.clock(10);
+        assert!(!mapper.irq_pending());
+        mapper.clock(1);
+        assert!(mapper.irq_pending());
+        assert_eq!(mapper.read_mut(0x4030) & 1, 1);
+        assert!(!mapper.irq_pending());
+
+        // Without repeat the timer stops after firing once
+        mapper.clock(100);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn test_read_disk() {
+        let mut mapper = fds_mapper();
+        // Motor on, read mode, disk ready
+        mapper.write(0x4025, 0b0100_0101);
+        let mut bytes = Vec::new();
+        for _ in 0..REWIND_CYCLES + (LEADING_GAP_BYTES as u32 + 20) * (CYCLES_PER_BYTE + 1) {
+            mapper.clock(1);
+            if mapper.read(0x4030) & 0b10 != 0 {
+                bytes.push(mapper.read_mut(0x4031));
+            }
+        }
+        // The block start mark ends the gap and comes through first
+        assert_eq!(bytes[0], BLOCK_START_MARK);
+        assert_eq!(&bytes[1..16], DISK_INFO_MAGIC);
+    }
+
+    #[test]
+    fn test_side_switching() {
+        let mut mapper = fds_mapper();
+        let drive = mapper.disk_drive().unwrap();
+        assert_eq!(drive.side_count(), 2);
+        assert_eq!(drive.inserted_side(), Some(0));
+        drive.eject();
+        assert_eq!(mapper.read(0x4032) & 0b1, 1);
+
+        mapper.drive.insert(1);
+        assert_eq!(mapper.read(0x4032) & 0b1, 1);
+        mapper.clock(INSERT_DELAY_CYCLES);
+        assert_eq!(mapper.drive.inserted_side(), Some(1));
+        assert_eq!(mapper.read(0x4032) & 0b1, 0);
+        assert_eq!(DiskDrive::side_name(3), "Disk 2 side B");
+    }
+
+    #[test]
+    fn test_mirroring_and_ram() {
+        let mut mapper = fds_mapper();
+        mapper.write(0x4025, 0b1000);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        mapper.write(0xDFFF, 0x12);
+        assert_eq!(mapper.read(0xDFFF), 0x12);
+    }
+}
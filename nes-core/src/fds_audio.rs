@@ -0,0 +1,375 @@
+// The Famicom Disk System's wavetable sound channel, a 64 step wavetable with a volume envelope and frequency modulation.
+// See https://www.nesdev.org/wiki/FDS_audio
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+const WAVE_TABLE_SIZE: usize = 64;
+const MOD_TABLE_SIZE: usize = 64;
+// The largest gain that affects the output, the envelopes can count further but the output is clamped
+const MAX_OUTPUT_GAIN: u32 = 32;
+// Master volume multipliers for 2/2, 2/3, 2/4 and 2/5 volume, the products are divided by 1152
+const MASTER_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+const MAX_OUTPUT_LEVEL: f32 = 63.0;
+// At full volume the channel is about 2.4 times as loud as a 2A03 pulse channel, which peaks at 0.15 of the 2A03's full output
+pub const FDS_MIX_LEVEL: f32 = 0.36;
+// How much each mod table entry moves the mod counter, None resets it to 0
+const MOD_ADJUSTMENTS: [Option<i32>; 8] = [Some(0), Some(1), Some(2), Some(4), None, Some(-4), Some(-2), Some(-1)];
+
+// The volume and mod envelopes work the same way, ramping their gain up or down at a set speed
+#[derive(Default, Clone)]
+struct Envelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    disabled: bool,
+    timer: u32,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8, master_speed: u8) {
+        self.speed = value & 0x3F;
+        self.increase = value & 0x40 != 0;
+        self.disabled = value & 0x80 != 0;
+        self.reset_timer(master_speed);
+        // With the envelope disabled the speed sets the gain directly
+        if self.disabled {
+            self.gain = self.speed;
+        }
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    // Returns whether the gain changed
+    fn clock(&mut self, master_speed: u8) -> bool {
+        if self.disabled || master_speed == 0 {
+            return false;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return false;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < MAX_OUTPUT_GAIN as u8 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.speed);
+        state.write(&self.gain);
+        state.write(&self.increase);
+        state.write(&self.disabled);
+        state.write(&self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.speed = state.read()?;
+        self.gain = state.read()?;
+        self.increase = state.read()?;
+        self.disabled = state.read()?;
+        self.timer = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct FdsAudio {
+    wave_table: [u8; WAVE_TABLE_SIZE],
+    wave_write_enabled: bool,
+    wave_position: usize,
+    // 16 bit accumulator, the wave steps every time it overflows
+    wave_accumulator: u16,
+    frequency: u16,
+    wave_halted: bool,
+    envelopes_halted: bool,
+    volume: Envelope,
+    master_volume: usize,
+    master_envelope_speed: u8,
+
+    mod_envelope: Envelope,
+    mod_table: [u8; MOD_TABLE_SIZE],
+    mod_position: usize,
+    mod_accumulator: u16,
+    mod_frequency: u16,
+    mod_halted: bool,
+    // 7 bit signed counter that bends the pitch
+    mod_counter: i32,
+    // Pitch adjustment added to the frequency, calculated from the counter and the mod gain
+    mod_output: i32,
+
+    output_level: u8,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave_table: [0; WAVE_TABLE_SIZE],
+            wave_write_enabled: false,
+            wave_position: 0,
+            wave_accumulator: 0,
+            frequency: 0,
+            wave_halted: true,
+            envelopes_halted: true,
+            volume: Envelope::default(),
+            master_volume: 0,
+            // The BIOS sets this to $E8 at boot
+            master_envelope_speed: 0xE8,
+            mod_envelope: Envelope::default(),
+            mod_table: [0; MOD_TABLE_SIZE],
+            mod_position: 0,
+            mod_accumulator: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_counter: 0,
+            mod_output: 0,
+            output_level: 0,
+        }
+    }
+
+    // $4040-$4092
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            // While writing is disabled the wavetable reads back the sample being played
+            0x4040..=0x407F if self.wave_write_enabled => self.wave_table[(address - 0x4040) as usize],
+            0x4040..=0x407F => self.wave_table[self.wave_position],
+            0x4090 => self.volume.gain,
+            0x4092 => self.mod_envelope.gain,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4040..=0x407F if self.wave_write_enabled => self.wave_table[(address - 0x4040) as usize] = value & 0x3F,
+            0x4080 => self.volume.write(value, self.master_envelope_speed),
+            0x4082 => self.frequency = (self.frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.wave_halted = value & 0x80 != 0;
+                self.envelopes_halted = value & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_halted {
+                    self.volume.reset_timer(self.master_envelope_speed);
+                    self.mod_envelope.reset_timer(self.master_envelope_speed);
+                }
+            }
+            0x4084 => {
+                self.mod_envelope.write(value, self.master_envelope_speed);
+                self.update_mod_output();
+            }
+            0x4085 => {
+                self.set_mod_counter((value & 0x7F) as i32);
+                self.update_mod_output();
+            }
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.mod_halted = value & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // The mod table can only be written while the modulator is halted, each write fills two entries
+            0x4088 if self.mod_halted => {
+                self.mod_table[self.mod_position] = value & 0b111;
+                self.mod_table[(self.mod_position + 1) % MOD_TABLE_SIZE] = value & 0b111;
+                self.mod_position = (self.mod_position + 2) % MOD_TABLE_SIZE;
+            }
+            0x4089 => {
+                self.wave_write_enabled = value & 0x80 != 0;
+                self.master_volume = (value & 0b11) as usize;
+            }
+            0x408A => self.master_envelope_speed = value,
+            _ => {}
+        }
+    }
+
+    // Runs the channel for one cpu cycle
+    pub fn clock(&mut self) {
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume.clock(self.master_envelope_speed);
+            if self.mod_envelope.clock(self.master_envelope_speed) {
+                self.update_mod_output();
+            }
+        }
+        if !self.mod_halted && self.mod_frequency > 0 {
+            let (accumulator, overflowed) = self.mod_accumulator.overflowing_add(self.mod_frequency);
+            self.mod_accumulator = accumulator;
+            if overflowed {
+                match MOD_ADJUSTMENTS[self.mod_table[self.mod_position] as usize] {
+                    Some(adjustment) => self.set_mod_counter(self.mod_counter + adjustment),
+                    None => self.set_mod_counter(0),
+                }
+                self.mod_position = (self.mod_position + 1) % MOD_TABLE_SIZE;
+                self.update_mod_output();
+            }
+        }
+
+        if self.wave_halted {
+            self.wave_position = 0;
+        } else {
+            let pitch = self.frequency as i32 + self.mod_output;
+            if pitch > 0 && !self.wave_write_enabled {
+                let (accumulator, overflowed) = self.wave_accumulator.overflowing_add(pitch as u16);
+                self.wave_accumulator = accumulator;
+                if overflowed {
+                    self.wave_position = (self.wave_position + 1) % WAVE_TABLE_SIZE;
+                }
+            }
+        }
+        // The output holds its last value while the wavetable is being written
+        if !self.wave_write_enabled {
+            let gain = (self.volume.gain as u32).min(MAX_OUTPUT_GAIN);
+            self.output_level = (self.wave_table[self.wave_position] as u32 * gain * MASTER_VOLUMES[self.master_volume] / 1152) as u8;
+        }
+    }
+
+    // The current output, 0.0 to FDS_MIX_LEVEL
+    pub fn output(&self) -> f32 {
+        self.output_level as f32 / MAX_OUTPUT_LEVEL * FDS_MIX_LEVEL
+    }
+
+    fn set_mod_counter(&mut self, value: i32) {
+        // Wraps around as a 7 bit signed value
+        self.mod_counter = (value + 64).rem_euclid(128) - 64;
+    }
+
+    // The hardware's integer maths for turning the mod counter and gain into a pitch adjustment
+    fn update_mod_output(&mut self) {
+        let mut temp = self.mod_counter * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= self.frequency as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_output = temp;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.wave_table);
+        state.write(&self.wave_write_enabled);
+        state.write(&self.wave_position);
+        state.write(&self.wave_accumulator);
+        state.write(&self.frequency);
+        state.write(&self.wave_halted);
+        state.write(&self.envelopes_halted);
+        self.volume.save_state(state);
+        state.write(&self.master_volume);
+        state.write(&self.master_envelope_speed);
+        self.mod_envelope.save_state(state);
+        state.write(&self.mod_table);
+        state.write(&self.mod_position);
+        state.write(&self.mod_accumulator);
+        state.write(&self.mod_frequency);
+        state.write(&self.mod_halted);
+        state.write(&self.mod_counter);
+        state.write(&self.mod_output);
+        state.write(&self.output_level);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.wave_table = state.read()?;
+        self.wave_write_enabled = state.read()?;
+        self.wave_position = state.read::<usize>()? % WAVE_TABLE_SIZE;
+        self.wave_accumulator = state.read()?;
+        self.frequency = state.read()?;
+        self.wave_halted = state.read()?;
+        self.envelopes_halted = state.read()?;
+        self.volume.load_state(state)?;
+        self.master_volume = state.read::<usize>()? % MASTER_VOLUMES.len();
+        self.master_envelope_speed = state.read()?;
+        self.mod_envelope.load_state(state)?;
+        self.mod_table = state.read()?;
+        self.mod_position = state.read::<usize>()? % MOD_TABLE_SIZE;
+        self.mod_accumulator = state.read()?;
+        self.mod_frequency = state.read()?;
+        self.mod_halted = state.read()?;
+        self.mod_counter = state.read()?;
+        self.mod_output = state.read()?;
+        self.output_level = state.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing_square_wave() -> FdsAudio {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for index in 0..WAVE_TABLE_SIZE as u16 {
+            audio.write(0x4040 + index, if index < 32 { 63 } else { 0 });
+        }
+        audio.write(0x4089, 0x00);
+        // Full volume with the envelope disabled
+        audio.write(0x4080, 0x80 | 32);
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x04);
+        audio
+    }
+
+    #[test]
+    fn test_wave_playback() {
+        let mut audio = playing_square_wave();
+        audio.clock();
+        assert_eq!(audio.output_level, 63);
+        assert_eq!(audio.read(0x4090), 32);
+
+        // A frequency of $400 steps the wave every 64 cycles, so the high half lasts 32 * 64 cycles
+        let mut cycles = 1;
+        while audio.output_level != 0 {
+            audio.clock();
+            cycles += 1;
+        }
+        assert_eq!(cycles, 32 * 64);
+    }
+
+    #[test]
+    fn test_halt_resets_wave() {
+        let mut audio = playing_square_wave();
+        for _ in 0..40 * 64 {
+            audio.clock();
+        }
+        assert_eq!(audio.output_level, 0);
+        audio.write(0x4083, 0x84);
+        audio.clock();
+        assert_eq!(audio.wave_position, 0);
+        assert_eq!(audio.output_level, 63);
+    }
+
+    #[test]
+    fn test_mod_counter_wraps() {
+        let mut audio = FdsAudio::new();
+        audio.set_mod_counter(63 + 4);
+        assert_eq!(audio.mod_counter, -61);
+        audio.write(0x4085, 0x7F);
+        assert_eq!(audio.mod_counter, -1);
+    }
+}
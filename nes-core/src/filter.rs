@@ -0,0 +1,69 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::frame::Frame;
+use crate::palette::Palette;
+
+// Converts the raw pixels emitted by the ppu into RGBA8 images
+pub trait VideoFilter {
+    // Size of the image produced from a 256x240 frame
+    fn output_size(&self) -> (usize, usize);
+    // Output is row major RGBA8 and must hold output_size().0 * output_size().1 * 4 bytes
+    fn apply(&mut self, frame: &Frame, output: &mut [u8]);
+
+    fn apply_to_vec(&mut self, frame: &Frame) -> Vec<u8> {
+        let (width, height) = self.output_size();
+        let mut output = vec![0; width * height * 4];
+        self.apply(frame, &mut output);
+        output
+    }
+}
+
+// Looks every pixel up in a palette, without any further processing
+pub struct PaletteFilter {
+    pub palette: Palette,
+}
+
+impl PaletteFilter {
+    pub fn new(palette: Palette) -> Self {
+        Self { palette }
+    }
+}
+
+impl VideoFilter for PaletteFilter {
+    fn output_size(&self) -> (usize, usize) {
+        (Frame::WIDTH, Frame::HEIGHT)
+    }
+
+    fn apply(&mut self, frame: &Frame, output: &mut [u8]) {
+        for (pixel, rgba) in frame.pixels.iter().zip(output.chunks_exact_mut(4)) {
+            let (r, g, b) = self.palette.colors[*pixel as usize & 0x1FF];
+            rgba.copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_palette_filter() {
+        let mut frame = Frame::default();
+        frame.set_pixel(0, 0, 0x30);
+        frame.set_pixel(255, 239, 0x0F);
+        frame.set_pixel(1, 0, 0b001 << 6 | 0x30);
+
+        let palette = Palette::default();
+        let mut filter = PaletteFilter::new(palette.clone());
+        let output = filter.apply_to_vec(&frame);
+        assert_eq!(output.len(), Frame::WIDTH * Frame::HEIGHT * 4);
+
+        let (r, g, b) = palette.color(0x30, 0);
+        assert_eq!(output[0..4], [r, g, b, 255]);
+        let (r, g, b) = palette.color(0x30, 0b001);
+        assert_eq!(output[4..8], [r, g, b, 255]);
+        let (r, g, b) = palette.color(0x0F, 0);
+        assert_eq!(output[output.len() - 4..], [r, g, b, 255]);
+    }
+}
@@ -0,0 +1,36 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::hash::crc32_update;
+
+pub struct Frame {
+    // Row major 9 bit pixels, the colour emphasis bits from PPUMASK << 6 | the 6 bit palette colour.
+    // Use a VideoFilter to convert them into something displayable
+    pub pixels: Vec<u16>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self {
+            pixels: vec![0; Frame::WIDTH * Frame::HEIGHT],
+        }
+    }
+}
+
+impl Frame {
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: u16) {
+        self.pixels[y * Frame::WIDTH + x] = pixel;
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * Frame::WIDTH + x]
+    }
+
+    // CRC32 of the pixels as little endian bytes, for checking that two runs drew the same thing
+    pub fn crc32(&self) -> u32 {
+        self.pixels.iter().fold(0, |crc, pixel| crc32_update(crc, &pixel.to_le_bytes()))
+    }
+}
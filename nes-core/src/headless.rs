@@ -0,0 +1,49 @@
+// Runs the emulator without a window one frame at a time, for regression tests and scripted runs
+use alloc::vec::Vec;
+
+use crate::controller::Buttons;
+use crate::frame::Frame;
+use crate::movie::MovieSession;
+use crate::nes::Nes;
+
+pub struct HeadlessRunner {
+    pub nes: Nes,
+    pub movie: Option<MovieSession>,
+    // Frames run so far
+    pub frame: u64,
+}
+
+impl HeadlessRunner {
+    // A movie should already be started on the machine, see MovieSession::play
+    pub fn new(nes: Nes, movie: Option<MovieSession>) -> Self {
+        HeadlessRunner { nes, movie, frame: 0 }
+    }
+
+    // Runs one frame with the movie's input, or with nothing pressed once the movie runs out
+    pub fn step_frame(&mut self) -> &Frame {
+        match &mut self.movie {
+            Some(movie) => movie.run_frame(&mut self.nes, [Buttons::empty(); 2]),
+            None => self.nes.run_frame(),
+        }
+        self.frame += 1;
+        self.nes.frame()
+    }
+
+    // Runs until each of the frames, which must be in order, and returns the crc32 of the picture at each
+    pub fn frame_hashes(&mut self, frames: &[u64]) -> Vec<u32> {
+        frames
+            .iter()
+            .map(|frame| {
+                while self.frame < *frame {
+                    self.step_frame();
+                }
+                self.nes.frame().crc32()
+            })
+            .collect()
+    }
+
+    // The first frame whose picture differed from the movie's recording
+    pub fn desync(&self) -> Option<usize> {
+        self.movie.as_ref().and_then(|movie| movie.desync)
+    }
+}
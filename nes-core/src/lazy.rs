@@ -0,0 +1,38 @@
+// A value computed on first use, like once_cell's sync::Lazy but built on OnceBox so it only needs an allocator and atomics.
+// If two threads race to initialize it both compute the value and one of them is dropped
+use alloc::boxed::Box;
+use core::ops::Deref;
+
+use once_cell::race::OnceBox;
+
+pub struct Lazy<T> {
+    cell: OnceBox<T>,
+    init: fn() -> T,
+}
+
+impl<T> Lazy<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        Lazy { cell: OnceBox::new(), init }
+    }
+}
+
+impl<T> Deref for Lazy<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.cell.get_or_init(|| Box::new((self.init)()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SQUARES: Lazy<[u32; 4]> = Lazy::new(|| core::array::from_fn(|index| (index * index) as u32));
+
+    #[test]
+    fn test_initialized_once() {
+        assert_eq!(*SQUARES, [0, 1, 4, 9]);
+        assert!(core::ptr::eq(&*SQUARES, &*SQUARES));
+    }
+}
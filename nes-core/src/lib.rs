@@ -0,0 +1,43 @@
+#![no_std]
+
+// The emulator without any windowing, audio output or file access, driven through nes::Nes by the frontends.
+// It only needs an allocator, so it also runs on embedded targets
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+pub mod apu;
+pub mod archive;
+pub mod controller;
+pub mod cpu;
+pub mod database;
+pub mod fds;
+pub mod fds_audio;
+pub mod filter;
+pub mod frame;
+pub mod hash;
+pub mod headless;
+mod instructions;
+mod lazy;
+pub mod mapper;
+pub mod memory_bus;
+pub mod mmc5_audio;
+pub mod movie;
+pub mod n163_audio;
+pub mod nes;
+mod nes_tests;
+pub mod nsf;
+pub mod ntsc;
+mod opcodes;
+pub mod palette;
+pub mod patch;
+pub mod png;
+pub mod ppu;
+pub mod region;
+pub mod rewind;
+pub mod rom;
+pub mod scaler;
+pub mod state;
+pub mod sunsoft5b_audio;
+pub mod vrc6_audio;
+pub mod wav;
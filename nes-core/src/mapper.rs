@@ -0,0 +1,126 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fds::DiskDrive;
+use crate::rom::Mirroring;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub trait Mapper {
+    // Reads without side effects, so debuggers can peek at the mapper
+    fn read(&self, address: u16) -> u8;
+    // Reads by the cpu, which can have side effects such as acknowledging an interrupt
+    fn read_mut(&mut self, address: u16) -> u8 {
+        self.read(address)
+    }
+    fn read_word(&self, address: u16) -> u16 {
+        let low = self.read(address) as u16;
+        let high = self.read(address.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+    fn write(&mut self, address: u16, value: u8);
+    fn write_word(&mut self, address: u16, value: u16) {
+        let low_byte = (value & 0xFF) as u8;
+        let high_byte = ((value >> 8) & 0xFF) as u8;
+
+        self.write(address, low_byte);
+        self.write(address.wrapping_add(1), high_byte);
+    }
+    // Battery backed memory that should be persisted between sessions, such as PRG RAM or an EEPROM
+    fn save_ram(&self) -> Option<&[u8]> {
+        None
+    }
+    // Restores memory previously returned by save_ram
+    fn load_save_ram(&mut self, _data: &[u8]) {}
+    // Writes the mapper's registers and RAM to a save state, ROM doesn't change so it's left out
+    fn save_state(&self, state: &mut StateWriter);
+    // Restores what save_state wrote, in the same order
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+    // Runs timers and expansion audio along with the cpu
+    fn clock(&mut self, _cpu_cycles: u32) {}
+    // Whether the mapper is holding the cpu's IRQ line low
+    fn irq_pending(&self) -> bool {
+        false
+    }
+    // Expansion audio output, where 1.0 is the loudest the 2A03's own channels get
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+    // The Famicom Disk System's drive, for inserting and ejecting disks
+    fn disk_drive(&mut self) -> Option<&mut DiskDrive> {
+        None
+    }
+    // Mappers that control nametable mirroring return the current arrangement, which overrides the one from the header
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+}
+
+pub struct NromMapper {
+    prg_rom: Vec<u8>,
+    // Mirrored across $6000-$7FFF, empty if the board has no PRG RAM
+    prg_ram: Vec<u8>,
+    has_battery: bool,
+}
+
+impl NromMapper {
+    pub fn new(prg_rom: Vec<u8>, prg_ram_size: usize, has_battery: bool) -> Self {
+        NromMapper {
+            prg_rom,
+            prg_ram: vec![0; prg_ram_size],
+            has_battery,
+        }
+    }
+}
+
+impl Mapper for NromMapper {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            // Expansion area, nothing is connected here on NROM boards
+            0x4020..=0x5FFF => 0,
+            0x6000..=0x7FFF if self.prg_ram.is_empty() => 0,
+            0x6000..=0x7FFF => self.prg_ram[(address - 0x6000) as usize % self.prg_ram.len()],
+            0x8000..=0xFFFF => {
+                // In NROM, the PRG ROM is directly accessible from CPU address space, 16 KiB roms are mirrored
+                self.prg_rom[(address as usize - 0x8000) % self.prg_rom.len()]
+            }
+            _ => {
+                unreachable!("Mapper should not handle this address");
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        // Excess ram is writable to
+        match address {
+            0x4020..=0x5FFF => {}
+            0x6000..=0x7FFF if self.prg_ram.is_empty() => {}
+            0x6000..=0x7FFF => {
+                let prg_ram_size = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) as usize % prg_ram_size] = value;
+            }
+            0x8000..=0xFFFF => {
+                panic!("Cartridge ROM space is not writable to with the NromMapper");
+            }
+            _ => {
+                unreachable!("Mapper should not handle this address");
+            }
+        }
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        self.has_battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        let length = data.len().min(self.prg_ram.len());
+        self.prg_ram[..length].copy_from_slice(&data[..length]);
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.prg_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.prg_ram)
+    }
+}
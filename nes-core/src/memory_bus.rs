@@ -0,0 +1,344 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use bitflags::Flags;
+
+use crate::apu::{Apu, AudioSampler};
+use crate::controller::Controller;
+use crate::fds::FdsMapper;
+use crate::mapper::{Mapper, NromMapper};
+use crate::ppu::Ppu;
+use crate::region::Region;
+use crate::rom::{Mirroring, Rom, RomError};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const RAM_MIRRORS_END: u16 = 0x1FFF;
+const RAM_MIRROR_MASK: u16 = 0x0800 - 1;
+const PPU_REGISTERS: u16 = 0x2000;
+const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+
+const PPUCTRL: u16 = 0x2000;
+const PPUMASK: u16 = 0x2001;
+const PPUSTATUS: u16 = 0x2002;
+const OAMADDR: u16 = 0x2003;
+const OAMDATA: u16 = 0x2004;
+const PPUSCROLL: u16 = 0x2005;
+const PPUADDR: u16 = 0x2006;
+const PPUDATA: u16 = 0x2007;
+const OAMDMA: u16 = 0x4014;
+const APU_STATUS: u16 = 0x4015;
+// Writes strobe both controllers, reads return the first controller
+const JOYPAD1: u16 = 0x4016;
+// Reads return the second controller, writes go to the apu's frame counter
+const JOYPAD2: u16 = 0x4017;
+const APU_FRAME_COUNTER: u16 = 0x4017;
+const TRAINER_START: u16 = 0x7000;
+// The whole $6000-$7FFF window, so the trainer isn't mirrored over by a smaller RAM
+const TRAINER_PRG_RAM_SIZE: usize = 0x2000;
+
+pub struct MemoryBus {
+    pub cpu_vram: [u8; 2048],
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub apu_io_registers: [u8; 0x20],
+    pub controllers: [Controller; 2],
+    pub mapper: Box<dyn Mapper>,
+    // Kept so it can be copied back over PRG RAM after a save is loaded into it
+    pub trainer: Vec<u8>,
+    // Collects audio samples when something is listening, such as the NSF player
+    pub audio: Option<AudioSampler>,
+}
+
+impl MemoryBus {
+    pub fn new(rom: Rom) -> Result<Self, RomError> {
+        let mut prg_ram_size = rom.prg_ram_size + rom.prg_nvram_size;
+        // A trainer needs PRG RAM at $7000-$71FF to live in, even when a NES 2.0 header declares none
+        if !rom.trainer.is_empty() {
+            prg_ram_size = prg_ram_size.max(TRAINER_PRG_RAM_SIZE);
+        }
+        let mapper: Box<dyn Mapper> = match rom.mapper {
+            // Use NROM mapper for mapper number 0
+            0 => Box::new(NromMapper::new(rom.prg_rom, prg_ram_size, rom.has_battery)),
+            // Mapper 20 is reserved for the Famicom Disk System, whose BIOS is loaded as the PRG ROM
+            20 => Box::new(FdsMapper::new(rom.prg_rom, rom.disk_sides)),
+            // Add cases for other mappers as needed
+            _ => {
+                return Err(RomError::UnsupportedMapper {
+                    mapper: rom.mapper,
+                    submapper: rom.submapper,
+                })
+            }
+        };
+
+        // Cartridges without CHR ROM have CHR RAM in its place
+        let chr = if rom.chr_rom.is_empty() {
+            vec![0; (rom.chr_ram_size + rom.chr_nvram_size).max(8192)]
+        } else {
+            rom.chr_rom
+        };
+
+        let mut memory_bus = Self::from_mapper(mapper, chr, rom.screen_mirroring, rom.region);
+        memory_bus.trainer = rom.trainer;
+        memory_bus.load_trainer();
+        Ok(memory_bus)
+    }
+
+    pub fn from_mapper(mapper: Box<dyn Mapper>, chr: Vec<u8>, screen_mirroring: Mirroring, region: Region) -> Self {
+        MemoryBus {
+            cpu_vram: [0; 2048],
+            ppu: Ppu::new(chr, screen_mirroring, region),
+            apu: Apu::new(region),
+            apu_io_registers: [0; 32],
+            controllers: Default::default(),
+            mapper,
+            trainer: Vec::new(),
+            audio: None,
+        }
+    }
+
+    // The trainer is copied into PRG RAM at power on, before the game starts
+    fn load_trainer(&mut self) {
+        for (offset, byte) in self.trainer.iter().enumerate() {
+            self.mapper.write(TRAINER_START + offset as u16, *byte);
+        }
+    }
+
+    // Loads battery backed RAM from a save, the trainer goes back on top as it would have been copied in after power on
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        self.mapper.load_save_ram(data);
+        self.load_trainer();
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    // The audio sampler belongs to whoever is listening, so it isn't part of the state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.cpu_vram);
+        state.write(&self.apu_io_registers);
+        for controller in &self.controllers {
+            controller.save_state(state);
+        }
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.mapper.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cpu_vram = state.read()?;
+        self.apu_io_registers = state.read()?;
+        for controller in &mut self.controllers {
+            controller.load_state(state)?;
+        }
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.mapper.load_state(state)
+    }
+
+    // Runs the apu and the cartridge along with the cpu
+    pub fn clock(&mut self, cpu_cycles: u32) {
+        for _ in 0..cpu_cycles {
+            self.apu.clock();
+            self.mapper.clock(1);
+            if let Some(address) = self.apu.dmc_read_address() {
+                let value = self.read(address);
+                self.apu.fill_dmc_buffer(value);
+            }
+            if let Some(audio) = &mut self.audio {
+                audio.push(self.apu.output() + self.mapper.audio_output());
+            }
+        }
+    }
+
+    pub fn debug_read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x07FF => self.cpu_vram[address as usize],
+            0x0800..=0x1FFF => {
+                let mirrored_down_address = address & 0x07FF;
+                self.debug_read(mirrored_down_address)
+            }
+            PPUSTATUS => self.ppu.peek_status().bits(),
+            OAMDATA => self.ppu.peek_oam_data(),
+            PPUDATA => self.ppu.peek_ppudata(),
+            // Write only registers return the contents of the ppu io latch
+            PPUCTRL | PPUMASK | OAMADDR | PPUSCROLL | PPUADDR => self.ppu.io_latch,
+            0x2008..=0x3FFF => {
+                let mirror_down_address = address & 0x2007;
+                self.debug_read(mirror_down_address)
+            }
+            APU_STATUS => self.apu.peek_status(),
+            // The upper bits are open bus, which holds the $40 from the address
+            JOYPAD1 => self.controllers[0].peek() | 0x40,
+            JOYPAD2 => self.controllers[1].peek() | 0x40,
+            0x4000..=0x401F => {
+                // NES APU and I/O registers and their functionality
+                self.apu_io_registers[(address - 0x4000) as usize]
+            }
+            0x4020..=0xFFFF => {
+                // Cartridge space: PRG ROM, PRG RAM, and mapper registers
+                self.mapper.read(address)
+            }
+        }
+    }
+
+    // Interrupt requests from the cartridge
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending() || self.apu.irq_pending()
+    }
+
+    pub fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x07FF => self.cpu_vram[address as usize],
+            0x0800..=0x1FFF => {
+                let mirrored_down_address = address & 0x07FF;
+                self.read(mirrored_down_address)
+            }
+            PPUSTATUS => self.ppu.read_status().bits(),
+            OAMDATA => self.ppu.read_oam_data(),
+            PPUDATA => self.ppu.read_ppudata(),
+            // Write only registers return the contents of the ppu io latch
+            PPUCTRL | PPUMASK | OAMADDR | PPUSCROLL | PPUADDR => self.ppu.io_latch,
+            0x2008..=0x3FFF => {
+                let mirror_down_address = address & 0x2007;
+                self.read(mirror_down_address)
+            }
+            APU_STATUS => self.apu.read_status(),
+            JOYPAD1 => self.controllers[0].read() | 0x40,
+            JOYPAD2 => self.controllers[1].read() | 0x40,
+            0x4000..=0x401F => {
+                // NES APU and I/O registers and their functionality
+                self.apu_io_registers[(address - 0x4000) as usize]
+            }
+            0x4020..=0xFFFF => {
+                // Cartridge space: PRG ROM, PRG RAM, and mapper registers
+                self.mapper.read_mut(address)
+            }
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if (PPUCTRL..=PPUDATA).contains(&address) {
+            self.ppu.write_io_latch(value);
+        }
+        match address {
+            0x0000..=0x1FFF => {
+                // 2 KB internal RAM mirrored every 0x0800 bytes
+                let mirror_down_address = address & RAM_MIRROR_MASK; // Same as module 0x800
+                self.cpu_vram[mirror_down_address as usize] = value;
+            }
+            PPUCTRL => self.ppu.write_control(value),
+            PPUMASK => self.ppu.write_mask(value),
+            // Writing to PPUSTATUS only fills the io latch
+            PPUSTATUS => {}
+            OAMADDR => {}
+            OAMDATA => {}
+            PPUSCROLL => {
+                self.ppu.write_scroll(value);
+            }
+            PPUADDR => self.ppu.write_ppuaddr(value),
+            PPUDATA => {
+                self.ppu.write_ppudata(value);
+            }
+            OAMDMA => {
+                let oam_data_slice = &self.cpu_vram[0x0200..0x02FF];
+                let oam_data: &[u8; 256] = oam_data_slice.try_into().expect("slice with incorrect length");
+                self.ppu.write_oam_dma(oam_data);
+            }
+            0x2008..=0x3FFF => {
+                // Mirrors of $2000–$2007 (repeats every 8 bytes)
+                let mirror_down_address = address & 0x2007;
+                self.write(mirror_down_address, value);
+            }
+            JOYPAD1 => {
+                for controller in &mut self.controllers {
+                    controller.write_strobe(value);
+                }
+                self.apu_io_registers[(address - 0x4000) as usize] = value;
+            }
+            0x4000..=0x4013 | APU_STATUS | APU_FRAME_COUNTER => {
+                self.apu.write(address, value);
+                self.apu_io_registers[(address - 0x4000) as usize] = value;
+            }
+            0x4000..=0x401F => {
+                // NES APU and I/O registers and their functionality
+                self.apu_io_registers[(address - 0x4000) as usize] = value;
+            }
+            0x4020..=0xFFFF => {
+                // Cartridge space: PRG ROM, PRG RAM, and mapper registers
+                self.mapper.write(address, value);
+                if let Some(mirroring) = self.mapper.mirroring() {
+                    self.ppu.screen_mirroring = mirroring;
+                }
+            }
+        }
+    }
+
+    pub fn read_word(&mut self, address: u16) -> u16 {
+        let low = self.read(address) as u16;
+        let high = self.read(address.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
+    pub fn write_word(&mut self, address: u16, value: u16) {
+        let low_byte = (value & 0xFF) as u8;
+        let high_byte = ((value >> 8) & 0xFF) as u8;
+
+        self.write(address, low_byte);
+        self.write(address.wrapping_add(1), high_byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trainer_loaded_into_prg_ram() {
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0b100];
+        bytes.resize(16, 0);
+        bytes.extend((0..512).map(|i| i as u8));
+        bytes.resize(16 + 512 + 16384 + 8192, 0);
+
+        let mut memory_bus = MemoryBus::new(Rom::new(&bytes).unwrap()).unwrap();
+        assert_eq!(memory_bus.read(0x6FFF), 0x00);
+        assert_eq!(memory_bus.read(0x7000), 0x00);
+        assert_eq!(memory_bus.read(0x7001), 0x01);
+        assert_eq!(memory_bus.read(0x71FF), 0xFF);
+        assert_eq!(memory_bus.read(0x7200), 0x00);
+    }
+
+    #[test]
+    fn test_trainer_without_declared_prg_ram() {
+        // A NES 2.0 header with a trainer whose byte 10 declares no PRG RAM
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0b100, 0b1000];
+        bytes.resize(16, 0);
+        bytes.extend((0..512).map(|i| i as u8));
+        bytes.resize(16 + 512 + 16384 + 8192, 0);
+        let rom = Rom::new(&bytes).unwrap();
+        assert_eq!(rom.prg_ram_size + rom.prg_nvram_size, 0);
+
+        let mut memory_bus = MemoryBus::new(rom).unwrap();
+        assert_eq!(memory_bus.read(0x7001), 0x01);
+        assert_eq!(memory_bus.read(0x71FF), 0xFF);
+        assert_eq!(memory_bus.read(0x6001), 0x00);
+    }
+
+    #[test]
+    fn test_save_ram_keeps_trainer() {
+        // Battery and trainer
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0b110];
+        bytes.resize(16, 0);
+        bytes.extend((0..512).map(|i| i as u8));
+        bytes.resize(16 + 512 + 16384 + 8192, 0);
+
+        let mut memory_bus = MemoryBus::new(Rom::new(&bytes).unwrap()).unwrap();
+        memory_bus.load_save_ram(&[0x55; 0x2000]);
+        assert_eq!(memory_bus.read(0x6000), 0x55);
+        assert_eq!(memory_bus.read(0x7001), 0x01);
+        assert_eq!(memory_bus.read(0x71FF), 0xFF);
+        assert_eq!(memory_bus.read(0x7200), 0x55);
+    }
+}
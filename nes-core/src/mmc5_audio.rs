@@ -0,0 +1,135 @@
+// The MMC5's expansion audio, two pulse channels like the 2A03's without sweep units and an 8 bit PCM channel.
+// See https://www.nesdev.org/wiki/MMC5_audio
+
+use crate::apu::Pulse;
+use crate::state::{StateError, StateReader, StateWriter};
+
+// The MMC5 has its own frame timer that clocks the envelopes and length counters at 240 Hz
+const FRAME_PERIOD: u32 = 7457;
+// The pulses are mixed like the 2A03's, a pulse at full volume peaks at about 0.15
+const PULSE_LEVEL_PER_STEP: f32 = 0.15 / 15.0;
+// The PCM channel at full swing is about as loud as the DMC at full swing
+const PCM_LEVEL_PER_STEP: f32 = 0.56 / 255.0;
+
+#[derive(Clone)]
+pub struct Mmc5Audio {
+    pulses: [Pulse; 2],
+    frame_timer: u32,
+    odd_cycle: bool,
+    pcm_level: u8,
+    // In read mode the PCM channel plays bytes the cpu reads from $8000-$BFFF, which NSFs don't use
+    pcm_read_mode: bool,
+}
+
+impl Default for Mmc5Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Mmc5Audio {
+            pulses: [Pulse::new(false, false), Pulse::new(false, false)],
+            frame_timer: FRAME_PERIOD,
+            odd_cycle: false,
+            pcm_level: 0,
+            pcm_read_mode: false,
+        }
+    }
+
+    // $5015
+    pub fn read_status(&self) -> u8 {
+        self.pulses[0].active() as u8 | (self.pulses[1].active() as u8) << 1
+    }
+
+    // $5000-$5015
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5003 => self.pulses[0].write(address & 0b11, value),
+            0x5004..=0x5007 => self.pulses[1].write(address & 0b11, value),
+            0x5010 => self.pcm_read_mode = value & 0x01 != 0,
+            // Writes of 0 are ignored
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm_level = value,
+            0x5015 => {
+                self.pulses[0].set_enabled(value & 0x01 != 0);
+                self.pulses[1].set_enabled(value & 0x02 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    // Runs the channels for one cpu cycle
+    pub fn clock(&mut self) {
+        self.frame_timer -= 1;
+        if self.frame_timer == 0 {
+            self.frame_timer = FRAME_PERIOD;
+            for pulse in &mut self.pulses {
+                pulse.clock_quarter_frame();
+                pulse.clock_half_frame();
+            }
+        }
+        if self.odd_cycle {
+            for pulse in &mut self.pulses {
+                pulse.clock_timer();
+            }
+        }
+        self.odd_cycle = !self.odd_cycle;
+    }
+
+    pub fn output(&self) -> f32 {
+        (self.pulses[0].output() + self.pulses[1].output()) as f32 * PULSE_LEVEL_PER_STEP + self.pcm_level as f32 * PCM_LEVEL_PER_STEP
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for pulse in &self.pulses {
+            pulse.save_state(state);
+        }
+        state.write(&self.frame_timer);
+        state.write(&self.odd_cycle);
+        state.write(&self.pcm_level);
+        state.write(&self.pcm_read_mode);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for pulse in &mut self.pulses {
+            pulse.load_state(state)?;
+        }
+        self.frame_timer = state.read::<u32>()?.clamp(1, FRAME_PERIOD);
+        self.odd_cycle = state.read()?;
+        self.pcm_level = state.read()?;
+        self.pcm_read_mode = state.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulse_low_periods_not_muted() {
+        let mut audio = Mmc5Audio::new();
+        audio.write(0x5015, 0x01);
+        // A period of 4 would be muted by a 2A03 pulse's sweep unit
+        audio.write(0x5000, 0b1011_1111);
+        audio.write(0x5002, 4);
+        audio.write(0x5003, 0x08);
+        assert_eq!(audio.read_status(), 0x01);
+        let high_cycles = (0..80)
+            .filter(|_| {
+                audio.clock();
+                audio.pulses[0].output() == 15
+            })
+            .count();
+        assert_eq!(high_cycles, 40);
+    }
+
+    #[test]
+    fn test_pcm_ignores_zero() {
+        let mut audio = Mmc5Audio::new();
+        audio.write(0x5011, 0x80);
+        audio.write(0x5011, 0x00);
+        assert_eq!(audio.pcm_level, 0x80);
+    }
+}
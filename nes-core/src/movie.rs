@@ -0,0 +1,816 @@
+// Input movies, the controller input for every frame from power on or from a save state, which replay the same game when fed back in.
+// Reads and writes FCEUX's .fm2 text format and BizHawk's .bk2 zips.
+// See https://fceux.com/web/FM2.html and https://tasvideos.org/Bizhawk/BK2Format
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use bitflags::bitflags;
+
+use crate::archive::{is_zip, write_zip, zip_entries, ArchiveError};
+use crate::controller::Buttons;
+use crate::hash::{md5, sha1};
+use crate::nes::Nes;
+use crate::region::Region;
+use crate::rom::Rom;
+use crate::state::StateError;
+
+const FM2_VERSION: &str = "3";
+// FCEUX refuses movies from emulators older than the one it expects, so claim a recent one
+const FM2_EMU_VERSION: &str = "22020";
+// The columns of an FM2 pad from left to right
+const FM2_BUTTONS: [(char, Buttons); 8] = [
+    ('R', Buttons::Right),
+    ('L', Buttons::Left),
+    ('D', Buttons::Down),
+    ('U', Buttons::Up),
+    ('T', Buttons::Start),
+    ('S', Buttons::Select),
+    ('B', Buttons::B),
+    ('A', Buttons::A),
+];
+
+const BK2_HEADER: &str = "Header.txt";
+const BK2_INPUT_LOG: &str = "Input Log.txt";
+const BK2_COMMENTS: &str = "Comments.txt";
+// The start state of movies made from a save state, ours rather than NesHawk's so BizHawk can only play power on movies
+const BK2_CORE_STATE: &str = "Core.bin";
+// Not part of the format, BizHawk ignores files it doesn't know
+const BK2_FRAME_HASHES: &str = "FrameHashes.txt";
+// NesHawk's button names and the mnemonics it logs them as, in its column order
+const BK2_BUTTONS: [(&str, char, Buttons); 8] = [
+    ("Up", 'U', Buttons::Up),
+    ("Down", 'D', Buttons::Down),
+    ("Left", 'L', Buttons::Left),
+    ("Right", 'R', Buttons::Right),
+    ("Start", 'S', Buttons::Start),
+    ("Select", 's', Buttons::Select),
+    ("B", 'B', Buttons::B),
+    ("A", 'A', Buttons::A),
+];
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+bitflags! {
+    // The FM2 command bits, things done to the console at the start of a frame
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+    pub struct MovieCommands: u8 {
+        const SoftReset = 0b01;
+        const HardReset = 0b10;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct MovieFrame {
+    pub commands: MovieCommands,
+    pub buttons: [Buttons; 2],
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MovieFormat {
+    Fm2,
+    Bk2,
+}
+
+impl MovieFormat {
+    // Picks the format from a file extension, "fm2" or "bk2" in any case
+    pub fn from_extension(extension: &str) -> Option<MovieFormat> {
+        if extension.eq_ignore_ascii_case("fm2") {
+            Some(MovieFormat::Fm2)
+        } else if extension.eq_ignore_ascii_case("bk2") {
+            Some(MovieFormat::Bk2)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MovieError {
+    // A header or input line couldn't be understood
+    Malformed(String),
+    UnsupportedVersion(String),
+    Archive(ArchiveError),
+    // The movie's start state couldn't be loaded
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Malformed(reason) => write!(f, "Movie is malformed: {}", reason),
+            MovieError::UnsupportedVersion(version) => write!(f, "Movie version {} is not supported", version),
+            MovieError::Archive(error) => write!(f, "{}", error),
+            MovieError::State(error) => write!(f, "Movie start state can't be loaded: {}", error),
+        }
+    }
+}
+
+impl core::error::Error for MovieError {}
+
+impl From<ArchiveError> for MovieError {
+    fn from(error: ArchiveError) -> Self {
+        MovieError::Archive(error)
+    }
+}
+
+// The game a movie is for. The hashes cover the PRG and CHR ROM, so headers and patches to them don't matter
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RomIdentity {
+    pub name: String,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+impl RomIdentity {
+    pub fn new(name: &str, rom: &Rom) -> Self {
+        let data = [rom.prg_rom.as_slice(), rom.chr_rom.as_slice()].concat();
+        RomIdentity {
+            name: name.to_owned(),
+            md5: md5(&data),
+            sha1: sha1(&data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Movie {
+    pub rom_name: String,
+    // FM2 only holds the MD5 and BK2 only the SHA1
+    pub rom_md5: Option<[u8; 16]>,
+    pub rom_sha1: Option<[u8; 20]>,
+    pub region: Region,
+    // How many times a save state was loaded while recording
+    pub rerecord_count: u32,
+    // FM2 only, identifies the movie so FCEUX can tell which save states belong to it
+    pub guid: String,
+    pub comments: Vec<String>,
+    // Movies recorded from a save state instead of power on
+    pub start_state: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+    // The crc32 of every recorded frame's picture, checked during playback to find where it desyncs
+    pub frame_hashes: Vec<u32>,
+}
+
+impl Movie {
+    // The guid is made from the rom and a seed that should differ between recordings, such as the current time
+    pub fn new(rom: &RomIdentity, region: Region, start_state: Option<Vec<u8>>, guid_seed: u128) -> Self {
+        let guid = md5(&[rom.md5.as_slice(), &guid_seed.to_le_bytes()].concat());
+        Movie {
+            rom_name: rom.name.clone(),
+            rom_md5: Some(rom.md5),
+            rom_sha1: Some(rom.sha1),
+            region,
+            guid: format_guid(&guid),
+            start_state,
+            ..Default::default()
+        }
+    }
+
+    // Whether the movie was recorded with this rom, movies that don't hold a hash match anything
+    pub fn matches_rom(&self, rom: &RomIdentity) -> bool {
+        self.rom_md5.is_none_or(|md5| md5 == rom.md5) && self.rom_sha1.is_none_or(|sha1| sha1 == rom.sha1)
+    }
+
+    // BK2 movies are zips, anything else is taken to be FM2 text
+    pub fn load(bytes: &[u8]) -> Result<Movie, MovieError> {
+        if is_zip(bytes) {
+            Movie::from_bk2(bytes)
+        } else {
+            Movie::from_fm2(&String::from_utf8_lossy(bytes))
+        }
+    }
+
+    pub fn to_bytes(&self, format: MovieFormat) -> Vec<u8> {
+        match format {
+            MovieFormat::Fm2 => self.to_fm2().into_bytes(),
+            MovieFormat::Bk2 => self.to_bk2(),
+        }
+    }
+
+    pub fn from_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::default();
+        let mut version = None;
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                movie
+                    .frames
+                    .push(parse_fm2_frame(line).ok_or_else(|| MovieError::Malformed(format!("bad input on line {}", line_number + 1)))?);
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "version" => version = Some(value.to_owned()),
+                "rerecordCount" => movie.rerecord_count = parse_number(key, value)?,
+                "palFlag" => movie.region = if value == "1" { Region::Pal } else { Region::Ntsc },
+                "romFilename" => movie.rom_name = value.to_owned(),
+                "romChecksum" => {
+                    let checksum = decode_base64_value(key, value)?;
+                    movie.rom_md5 = Some(
+                        checksum
+                            .try_into()
+                            .map_err(|_| MovieError::Malformed("romChecksum is not an MD5".to_owned()))?,
+                    );
+                }
+                "guid" => movie.guid = value.to_owned(),
+                "comment" => movie.comments.push(value.to_owned()),
+                "savestate" => movie.start_state = Some(decode_base64_value(key, value)?),
+                "frameHashes" => {
+                    movie.frame_hashes = decode_base64_value(key, value)?
+                        .chunks_exact(4)
+                        .map(|hash| u32::from_le_bytes(hash.try_into().unwrap()))
+                        .collect();
+                }
+                "binary" if value == "1" => return Err(MovieError::Malformed("binary input is not supported".to_owned())),
+                _ => {}
+            }
+        }
+        match version {
+            Some(version) if version == FM2_VERSION => Ok(movie),
+            Some(version) => Err(MovieError::UnsupportedVersion(version)),
+            None => Err(MovieError::Malformed("no version".to_owned())),
+        }
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        let mut header = |key: &str, value: &str| {
+            text.push_str(key);
+            text.push(' ');
+            text.push_str(value);
+            text.push('\n');
+        };
+        header("version", FM2_VERSION);
+        header("emuVersion", FM2_EMU_VERSION);
+        header("rerecordCount", &self.rerecord_count.to_string());
+        header("palFlag", if self.region == Region::Pal { "1" } else { "0" });
+        header("romFilename", &self.rom_name);
+        if let Some(md5) = &self.rom_md5 {
+            header("romChecksum", &format!("base64:{}", encode_base64(md5)));
+        }
+        header("guid", &self.guid);
+        for (key, value) in [
+            ("fourscore", "0"),
+            ("microphone", "0"),
+            ("port0", "1"),
+            ("port1", "1"),
+            ("port2", "0"),
+            ("FDS", "0"),
+            ("NewPPU", "0"),
+        ] {
+            header(key, value);
+        }
+        for comment in &self.comments {
+            header("comment", comment);
+        }
+        if let Some(state) = &self.start_state {
+            header("savestate", &format!("base64:{}", encode_base64(state)));
+        }
+        if !self.frame_hashes.is_empty() {
+            let hashes: Vec<u8> = self.frame_hashes.iter().flat_map(|hash| hash.to_le_bytes()).collect();
+            header("frameHashes", &format!("base64:{}", encode_base64(&hashes)));
+        }
+
+        for frame in &self.frames {
+            text.push_str(&format!("|{}|", frame.commands.bits()));
+            for buttons in frame.buttons {
+                text.extend(
+                    FM2_BUTTONS
+                        .iter()
+                        .map(|(name, button)| if buttons.contains(*button) { *name } else { '.' }),
+                );
+                text.push('|');
+            }
+            text.push_str("|\n");
+        }
+        text
+    }
+
+    pub fn from_bk2(bytes: &[u8]) -> Result<Movie, MovieError> {
+        let entries = zip_entries(bytes)?;
+        let file = |name: &str| entries.iter().find(|entry| entry.name == name).map(|entry| entry.contents()).transpose();
+        let text = |contents: Vec<u8>| String::from_utf8_lossy(&contents).into_owned();
+
+        let header = file(BK2_HEADER)?
+            .map(text)
+            .ok_or_else(|| MovieError::Malformed(format!("no {}", BK2_HEADER)))?;
+        let mut movie = Movie::default();
+        let mut starts_from_state = false;
+        for line in header.lines() {
+            let (key, value) = line.trim_end_matches('\r').split_once(' ').unwrap_or((line, ""));
+            match key {
+                "Platform" if value != "NES" => return Err(MovieError::Malformed(format!("movie is for {}", value))),
+                "GameName" => movie.rom_name = value.to_owned(),
+                "SHA1" => {
+                    let sha1 = decode_hex(value).and_then(|sha1| sha1.try_into().ok());
+                    movie.rom_sha1 = Some(sha1.ok_or_else(|| MovieError::Malformed("SHA1 is not a SHA1".to_owned()))?);
+                }
+                "rerecordCount" => movie.rerecord_count = parse_number(key, value)?,
+                "PAL" => movie.region = if value.eq_ignore_ascii_case("true") { Region::Pal } else { Region::Ntsc },
+                "StartsFromSavestate" => starts_from_state = value.eq_ignore_ascii_case("true"),
+                _ => {}
+            }
+        }
+
+        if let Some(comments) = file(BK2_COMMENTS)?.map(text) {
+            movie.comments = comments.lines().map(str::to_owned).collect();
+        }
+        if starts_from_state {
+            movie.start_state = Some(file(BK2_CORE_STATE)?.ok_or_else(|| MovieError::Malformed(format!("no {}", BK2_CORE_STATE)))?);
+        }
+        if let Some(hashes) = file(BK2_FRAME_HASHES)?.map(text) {
+            movie.frame_hashes = hashes
+                .lines()
+                .map(|hash| u32::from_str_radix(hash.trim(), 16).map_err(|_| MovieError::Malformed(format!("bad frame hash {}", hash))))
+                .collect::<Result<_, _>>()?;
+        }
+
+        let input_log = file(BK2_INPUT_LOG)?
+            .map(text)
+            .ok_or_else(|| MovieError::Malformed(format!("no {}", BK2_INPUT_LOG)))?;
+        // The log key names every column, grouped the same way as the input lines
+        let mut log_key: Option<Vec<Vec<&str>>> = None;
+        for line in input_log.lines().map(|line| line.trim_end_matches('\r')) {
+            if let Some(key) = line.strip_prefix("LogKey:") {
+                let groups = key.split('#').filter(|group| !group.is_empty());
+                log_key = Some(groups.map(|group| group.split('|').filter(|name| !name.is_empty()).collect()).collect());
+            } else if let Some(columns) = line.strip_prefix('|') {
+                let log_key = log_key
+                    .as_ref()
+                    .ok_or_else(|| MovieError::Malformed("input before the LogKey".to_owned()))?;
+                movie.frames.push(parse_bk2_frame(log_key, columns));
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn to_bk2(&self) -> Vec<u8> {
+        let mut header = String::from("MovieVersion BizHawk v2.0.0\nPlatform NES\nCore NesHawk\n");
+        header.push_str(&format!("GameName {}\n", self.rom_name));
+        if let Some(sha1) = &self.rom_sha1 {
+            header.push_str(&format!("SHA1 {}\n", sha1.iter().map(|byte| format!("{:02X}", byte)).collect::<String>()));
+        }
+        header.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        if self.region == Region::Pal {
+            header.push_str("PAL True\n");
+        }
+        if self.start_state.is_some() {
+            header.push_str("StartsFromSavestate True\n");
+        }
+
+        let mut input_log = String::from("[Input]\nLogKey:#Reset|Power|");
+        for player in 1..=2 {
+            input_log.push('#');
+            for (name, _, _) in BK2_BUTTONS {
+                input_log.push_str(&format!("P{} {}|", player, name));
+            }
+        }
+        input_log.push('\n');
+        for frame in &self.frames {
+            input_log.push('|');
+            input_log.push(if frame.commands.contains(MovieCommands::SoftReset) { 'r' } else { '.' });
+            input_log.push(if frame.commands.contains(MovieCommands::HardReset) { 'P' } else { '.' });
+            input_log.push('|');
+            for buttons in frame.buttons {
+                input_log.extend(
+                    BK2_BUTTONS
+                        .iter()
+                        .map(|(_, mnemonic, button)| if buttons.contains(*button) { *mnemonic } else { '.' }),
+                );
+                input_log.push('|');
+            }
+            input_log.push('\n');
+        }
+        input_log.push_str("[/Input]\n");
+
+        let comments = self.comments.join("\n");
+        let hashes: String = self.frame_hashes.iter().map(|hash| format!("{:08X}\n", hash)).collect();
+        let mut files: Vec<(&str, &[u8])> = vec![(BK2_HEADER, header.as_bytes()), (BK2_INPUT_LOG, input_log.as_bytes())];
+        if !self.comments.is_empty() {
+            files.push((BK2_COMMENTS, comments.as_bytes()));
+        }
+        if let Some(state) = &self.start_state {
+            files.push((BK2_CORE_STATE, state));
+        }
+        if !hashes.is_empty() {
+            files.push((BK2_FRAME_HASHES, hashes.as_bytes()));
+        }
+        write_zip(&files)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MovieMode {
+    Recording,
+    Playing,
+    // Playback reached the end of the movie, the controllers are back to the live input
+    Finished,
+}
+
+// A movie being recorded or played back on a machine
+pub struct MovieSession {
+    pub movie: Movie,
+    pub mode: MovieMode,
+    // The index of the next frame
+    pub frame: usize,
+    // The first frame whose picture didn't match the recording
+    pub desync: Option<usize>,
+    // The ppu's frame count when the movie's first frame started
+    start_frame: u64,
+}
+
+impl MovieSession {
+    // Records from the machine as it is now, which should be either just powered on or the movie's start state
+    pub fn record(movie: Movie, nes: &Nes) -> Self {
+        MovieSession {
+            movie,
+            mode: MovieMode::Recording,
+            frame: 0,
+            desync: None,
+            start_frame: nes.frame_count(),
+        }
+    }
+
+    // Plays from the movie's start state, or from the machine as it is now which should be just powered on
+    pub fn play(movie: Movie, nes: &mut Nes) -> Result<Self, MovieError> {
+        match &movie.start_state {
+            Some(state) => nes.load_state(state).map_err(MovieError::State)?,
+            // Movies only record whether they're PAL, so Dendy and NTSC movies play on whichever of the two the console is set to
+            None if (movie.region == Region::Pal) != (nes.region() == Region::Pal) => nes.set_region(movie.region),
+            None => {}
+        }
+        let mode = if movie.frames.is_empty() {
+            MovieMode::Finished
+        } else {
+            MovieMode::Playing
+        };
+        Ok(MovieSession {
+            movie,
+            mode,
+            frame: 0,
+            desync: None,
+            start_frame: nes.frame_count(),
+        })
+    }
+
+    // Runs a frame with the movie's input, or records the live input when recording
+    pub fn run_frame(&mut self, nes: &mut Nes, live_buttons: [Buttons; 2]) {
+        let input = match self.mode {
+            MovieMode::Playing => self.movie.frames[self.frame],
+            MovieMode::Recording | MovieMode::Finished => MovieFrame {
+                commands: MovieCommands::empty(),
+                buttons: live_buttons,
+            },
+        };
+        // Power cycling isn't emulated, a reset is the closest thing
+        if input.commands.intersects(MovieCommands::SoftReset | MovieCommands::HardReset) {
+            nes.reset();
+        }
+        for (port, buttons) in input.buttons.into_iter().enumerate() {
+            nes.set_buttons(port, buttons);
+        }
+        nes.run_frame();
+
+        let hash = nes.frame().crc32();
+        match self.mode {
+            MovieMode::Recording => {
+                self.movie.frames.push(input);
+                self.movie.frame_hashes.push(hash);
+                self.frame += 1;
+            }
+            MovieMode::Playing => {
+                let recorded = self.movie.frame_hashes.get(self.frame);
+                if self.desync.is_none() && recorded.is_some_and(|recorded| *recorded != hash) {
+                    self.desync = Some(self.frame);
+                }
+                self.frame += 1;
+                if self.frame >= self.movie.frames.len() {
+                    self.mode = MovieMode::Finished;
+                }
+            }
+            MovieMode::Finished => {}
+        }
+    }
+
+    // Called after a save state or rewind snapshot is loaded. Recording carries on from the loaded frame,
+    // dropping the input after it and counting a rerecord
+    pub fn state_loaded(&mut self, nes: &Nes) {
+        let frame = nes.frame_count().checked_sub(self.start_frame).map(|frame| frame as usize);
+        match (self.mode, frame) {
+            (MovieMode::Recording, Some(frame)) if frame <= self.movie.frames.len() => {
+                self.movie.frames.truncate(frame);
+                self.movie.frame_hashes.truncate(frame);
+                self.movie.rerecord_count += 1;
+                self.frame = frame;
+            }
+            (MovieMode::Playing, Some(frame)) if frame < self.movie.frames.len() => {
+                self.frame = frame;
+                self.desync = self.desync.filter(|desync| *desync < frame);
+            }
+            // The state is from before the movie started or after the part that exists
+            _ => self.mode = MovieMode::Finished,
+        }
+    }
+}
+
+// |commands|RLDUTSBA|RLDUTSBA|port 2|
+fn parse_fm2_frame(line: &str) -> Option<MovieFrame> {
+    let mut fields = line.split('|').skip(1);
+    let commands = MovieCommands::from_bits_truncate(fields.next()?.trim().parse().ok()?);
+    let mut buttons = [Buttons::empty(); 2];
+    for (pad, field) in buttons.iter_mut().zip(fields) {
+        for (character, (_, button)) in field.chars().zip(FM2_BUTTONS) {
+            pad.set(button, character != '.' && character != ' ');
+        }
+    }
+    Some(MovieFrame { commands, buttons })
+}
+
+fn parse_bk2_frame(log_key: &[Vec<&str>], columns: &str) -> MovieFrame {
+    let mut frame = MovieFrame::default();
+    for (names, group) in log_key.iter().zip(columns.split('|')) {
+        for (name, character) in names.iter().zip(group.chars()) {
+            if character == '.' {
+                continue;
+            }
+            match *name {
+                "Reset" => frame.commands |= MovieCommands::SoftReset,
+                "Power" => frame.commands |= MovieCommands::HardReset,
+                _ => {
+                    let Some((player, button)) = name.split_once(' ') else {
+                        continue;
+                    };
+                    let port = match player {
+                        "P1" => 0,
+                        "P2" => 1,
+                        _ => continue,
+                    };
+                    if let Some((_, _, button)) = BK2_BUTTONS.iter().find(|(button_name, _, _)| *button_name == button) {
+                        frame.buttons[port] |= *button;
+                    }
+                }
+            }
+        }
+    }
+    frame
+}
+
+fn parse_number(key: &str, value: &str) -> Result<u32, MovieError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| MovieError::Malformed(format!("{} is not a number", key)))
+}
+
+fn decode_base64_value(key: &str, value: &str) -> Result<Vec<u8>, MovieError> {
+    value
+        .strip_prefix("base64:")
+        .and_then(decode_base64)
+        .ok_or_else(|| MovieError::Malformed(format!("{} is not base64", key)))
+}
+
+fn format_guid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(BASE64_ALPHABET[(group >> (18 - 6 * index) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim().trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut group = 0u32;
+    let mut bits = 0;
+    for character in text.bytes() {
+        let value = BASE64_ALPHABET.iter().position(|letter| *letter == character)? as u32;
+        group = group << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads the first controller every frame and uses the buttons as the background colour
+    fn input_test_rom() -> Rom {
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01];
+        bytes.resize(16, 0);
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x08, 0x8D, 0x01, 0x20, // LDA #$08, STA $2001
+            0xA9, 0x01, 0x8D, 0x16, 0x40, // loop: LDA #$01, STA $4016
+            0xA9, 0x00, 0x8D, 0x16, 0x40, // LDA #$00, STA $4016
+            0xA2, 0x08,                   // LDX #$08
+            0xAD, 0x16, 0x40,             // read: LDA $4016
+            0x4A,                         // LSR A
+            0x26, 0x00,                   // ROL $00
+            0xCA,                         // DEX
+            0xD0, 0xF7,                   // BNE read
+            0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F, STA $2006
+            0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00, STA $2006
+            0xA5, 0x00,                   // LDA $00
+            0x8D, 0x07, 0x20,             // STA $2007
+            0x8D, 0x05, 0x20,             // STA $2005
+            0x8D, 0x05, 0x20,             // STA $2005
+            0x4C, 0x05, 0x80,             // JMP loop
+        ];
+        let mut prg_rom = program.to_vec();
+        prg_rom.resize(0x4000, 0);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        bytes.extend(prg_rom);
+        bytes.extend((0..0x2000).map(|i| (i * 5) as u8));
+        Rom::new(&bytes).unwrap()
+    }
+
+    fn power_on() -> Nes {
+        Nes::new(input_test_rom()).unwrap()
+    }
+
+    fn test_input(frame: usize) -> [Buttons; 2] {
+        [Buttons::from_bits_truncate((frame * 37) as u8), Buttons::from_bits_truncate(frame as u8)]
+    }
+
+    fn test_movie() -> Movie {
+        let rom = RomIdentity::new("test.nes", &input_test_rom());
+        let mut movie = Movie::new(&rom, Region::Pal, Some(vec![1, 2, 3, 4, 5]), 1);
+        movie.rerecord_count = 12;
+        movie.comments = vec!["author someone".to_owned()];
+        movie.frames = (0..20)
+            .map(|frame| MovieFrame {
+                commands: MovieCommands::from_bits_truncate(frame as u8 % 5),
+                buttons: test_input(frame),
+            })
+            .collect();
+        movie.frame_hashes = (0..20).map(|frame| frame * 0x0101_0101).collect();
+        movie
+    }
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let movie = test_movie();
+        let mut expected = movie.clone();
+        expected.rom_sha1 = None;
+        assert_eq!(Movie::load(movie.to_fm2().as_bytes()), Ok(expected));
+
+        let fm2 = "version 3\nemuVersion 20604\nrerecordCount 5\npalFlag 0\nromFilename smb\nromChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n\
+                   guid 1A2B\nfourscore 0\nport0 1\nport1 1\nport2 0\n|0|........|........||\n|1|R..U...A|.L..T.B.||\n|2|||\n";
+        let movie = Movie::from_fm2(fm2).unwrap();
+        assert_eq!(movie.rerecord_count, 5);
+        assert_eq!(movie.region, Region::Ntsc);
+        assert_eq!(movie.rom_md5.unwrap()[..3], [0x8E, 0x36, 0x30]);
+        assert_eq!(movie.frames.len(), 3);
+        assert_eq!(movie.frames[1].commands, MovieCommands::SoftReset);
+        assert_eq!(
+            movie.frames[1].buttons,
+            [Buttons::Right | Buttons::Up | Buttons::A, Buttons::Left | Buttons::Start | Buttons::B]
+        );
+        assert_eq!(movie.frames[2].commands, MovieCommands::HardReset);
+
+        assert_eq!(Movie::from_fm2("version 2\n"), Err(MovieError::UnsupportedVersion("2".to_owned())));
+        assert!(matches!(Movie::from_fm2("version 3\n|x|"), Err(MovieError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_bk2_round_trip() {
+        let movie = test_movie();
+        let mut expected = movie.clone();
+        expected.rom_md5 = None;
+        expected.guid.clear();
+        assert_eq!(Movie::load(&movie.to_bk2()), Ok(expected));
+
+        // Columns are matched to the log key, so other layouts and unknown buttons are fine
+        let input_log = "[Input]\nLogKey:#P1 A|P1 B|P1 Turbo|#Power|\n|A.T|.|\n|.B.|P|\n[/Input]\n";
+        let zip = write_zip(&[(BK2_HEADER, b"Platform NES\nrerecordCount 3\n"), (BK2_INPUT_LOG, input_log.as_bytes())]);
+        let movie = Movie::from_bk2(&zip).unwrap();
+        assert_eq!(movie.rerecord_count, 3);
+        assert_eq!(
+            movie.frames[0],
+            MovieFrame {
+                commands: MovieCommands::empty(),
+                buttons: [Buttons::A, Buttons::empty()]
+            }
+        );
+        assert_eq!(
+            movie.frames[1],
+            MovieFrame {
+                commands: MovieCommands::HardReset,
+                buttons: [Buttons::B, Buttons::empty()]
+            }
+        );
+
+        let zip = write_zip(&[(BK2_HEADER, b"Platform SNES\n"), (BK2_INPUT_LOG, input_log.as_bytes())]);
+        assert!(matches!(Movie::from_bk2(&zip), Err(MovieError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_playback_sync() {
+        let rom = RomIdentity::new("test.nes", &input_test_rom());
+        let mut nes = power_on();
+        let mut recording = MovieSession::record(Movie::new(&rom, Region::Ntsc, None, 2), &nes);
+        for frame in 0..30 {
+            recording.run_frame(&mut nes, test_input(frame));
+        }
+        let movie = Movie::load(&recording.movie.to_bytes(MovieFormat::Fm2)).unwrap();
+        assert!(movie.matches_rom(&rom));
+        assert_eq!(movie.frames.len(), 30);
+        assert!(movie.frame_hashes.iter().skip(1).any(|hash| *hash != movie.frame_hashes[0]));
+
+        let mut nes = power_on();
+        let mut playback = MovieSession::play(movie.clone(), &mut nes).unwrap();
+        while playback.mode == MovieMode::Playing {
+            playback.run_frame(&mut nes, [Buttons::empty(); 2]);
+        }
+        assert_eq!(playback.frame, 30);
+        assert_eq!(playback.desync, None);
+
+        // Different input draws a different picture
+        let mut edited = movie;
+        edited.frames[10].buttons[0] ^= Buttons::A | Buttons::Up;
+        let mut nes = power_on();
+        let mut playback = MovieSession::play(edited, &mut nes).unwrap();
+        while playback.mode == MovieMode::Playing {
+            playback.run_frame(&mut nes, [Buttons::empty(); 2]);
+        }
+        assert!(playback.desync.is_some_and(|frame| frame >= 10));
+    }
+
+    #[test]
+    fn test_rerecord() {
+        let rom = RomIdentity::new("test.nes", &input_test_rom());
+        let mut nes = power_on();
+        for _ in 0..3 {
+            nes.run_frame();
+        }
+        let start_state = nes.save_state();
+        let mut recording = MovieSession::record(Movie::new(&rom, Region::Ntsc, Some(start_state), 3), &nes);
+        let mut state = Vec::new();
+        for frame in 0..10 {
+            if frame == 5 {
+                state = nes.save_state();
+            }
+            recording.run_frame(&mut nes, [Buttons::Start, Buttons::empty()]);
+        }
+        nes.load_state(&state).unwrap();
+        recording.state_loaded(&nes);
+        assert_eq!((recording.frame, recording.movie.frames.len(), recording.movie.rerecord_count), (5, 5, 1));
+        for frame in 5..10 {
+            recording.run_frame(&mut nes, test_input(frame));
+        }
+
+        let movie = Movie::load(&recording.movie.to_bytes(MovieFormat::Bk2)).unwrap();
+        assert_eq!(movie.rerecord_count, 1);
+        let mut nes = power_on();
+        let mut playback = MovieSession::play(movie, &mut nes).unwrap();
+        while playback.mode == MovieMode::Playing {
+            playback.run_frame(&mut nes, [Buttons::empty(); 2]);
+        }
+        assert_eq!((playback.frame, playback.desync), (10, None));
+
+        let other_rom = RomIdentity::new("other.nes", &Rom::default());
+        assert!(!playback.movie.matches_rom(&other_rom));
+    }
+
+    #[test]
+    fn test_base64() {
+        for length in 0..8 {
+            let bytes: Vec<u8> = (0..length).map(|i: u8| i.wrapping_mul(71)).collect();
+            assert_eq!(decode_base64(&encode_base64(&bytes)), Some(bytes));
+        }
+        assert_eq!(encode_base64(b"Man"), "TWFu");
+        assert_eq!(encode_base64(b"Ma"), "TWE=");
+        assert_eq!(decode_base64("not base64!"), None);
+    }
+}
@@ -0,0 +1,191 @@
+// Namco 163 expansion audio, up to 8 wavetable channels that play 4 bit samples from 128 bytes of internal RAM.
+// See https://www.nesdev.org/wiki/Namco_163_audio
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+const RAM_SIZE: usize = 128;
+// The channel registers fill the top of the RAM, 8 bytes per channel
+const CHANNEL_REGISTERS_START: usize = 0x40;
+const CHANNEL_COUNT_REGISTER: usize = 0x7F;
+// Only one channel is updated at a time, each update takes 15 cpu cycles
+const CYCLES_PER_UPDATE: u8 = 15;
+// A channel at full volume swings about as far as two 2A03 pulses, the channels take turns so their sum is averaged
+const OUTPUT_LEVEL_PER_STEP: f32 = 0.3 / (15.0 * 15.0);
+
+#[derive(Clone)]
+pub struct Namco163Audio {
+    ram: [u8; RAM_SIZE],
+    address: u8,
+    auto_increment: bool,
+    cycle: u8,
+    // The channel being updated next, counts down from 7
+    current_channel: usize,
+    outputs: [i32; 8],
+}
+
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Namco163Audio {
+            ram: [0; RAM_SIZE],
+            address: 0,
+            auto_increment: false,
+            cycle: 0,
+            current_channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    // $4800, the data port
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0x4800..=0x4FFF => self.ram[self.address as usize],
+            _ => 0,
+        }
+    }
+
+    // Reads by the cpu move the address along when auto increment is on
+    pub fn read_mut(&mut self, address: u16) -> u8 {
+        let value = self.read(address);
+        if (0x4800..=0x4FFF).contains(&address) {
+            self.increment_address();
+        }
+        value
+    }
+
+    // $4800 is the data port, $F800 the address port
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4800..=0x4FFF => {
+                self.ram[self.address as usize] = value;
+                self.increment_address();
+            }
+            0xF800..=0xFFFF => {
+                self.address = value & 0x7F;
+                self.auto_increment = value & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn increment_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+
+    // The highest channels are enabled first, channel 7 is always on
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[CHANNEL_COUNT_REGISTER] >> 4) & 0b111) as usize + 1
+    }
+
+    // Runs the chip for one cpu cycle
+    pub fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_UPDATE {
+            return;
+        }
+        self.cycle = 0;
+
+        self.update_channel(self.current_channel);
+        if self.current_channel == 0 || self.current_channel <= 8 - self.enabled_channels() {
+            self.current_channel = 7;
+        } else {
+            self.current_channel -= 1;
+        }
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let registers = CHANNEL_REGISTERS_START + channel * 8;
+        let register = |offset: usize| self.ram[registers + offset] as u32;
+
+        let frequency = register(0) | register(2) << 8 | (register(4) & 0b11) << 16;
+        let length = 256 - (register(4) & 0xFC);
+        let mut phase = register(1) | register(3) << 8 | register(5) << 16;
+        phase = (phase + frequency) % (length << 16);
+
+        let sample_address = ((phase >> 16) + register(6)) & 0xFF;
+        let sample = (self.ram[(sample_address / 2) as usize] >> ((sample_address & 1) * 4)) & 0x0F;
+        self.outputs[channel] = (sample as i32 - 8) * (register(7) & 0x0F) as i32;
+
+        self.ram[registers + 1] = phase as u8;
+        self.ram[registers + 3] = (phase >> 8) as u8;
+        self.ram[registers + 5] = (phase >> 16) as u8;
+    }
+
+    pub fn output(&self) -> f32 {
+        let enabled_channels = self.enabled_channels();
+        let sum: i32 = self.outputs[8 - enabled_channels..].iter().sum();
+        sum as f32 / enabled_channels as f32 * OUTPUT_LEVEL_PER_STEP
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.ram);
+        state.write(&self.address);
+        state.write(&self.auto_increment);
+        state.write(&self.cycle);
+        state.write(&self.current_channel);
+        state.write(&self.outputs);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram = state.read()?;
+        self.address = state.read::<u8>()? & 0x7F;
+        self.auto_increment = state.read()?;
+        self.cycle = state.read()?;
+        self.current_channel = state.read::<usize>()? % self.outputs.len();
+        self.outputs = state.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_ram_ports() {
+        let mut audio = Namco163Audio::new();
+        audio.write(0xF800, 0x80 | 0x7E);
+        audio.write(0x4800, 0x12);
+        audio.write(0x4800, 0x34);
+        audio.write(0x4800, 0x56);
+        assert_eq!(audio.ram[0x7E..], [0x12, 0x34]);
+        assert_eq!(audio.ram[0x00], 0x56);
+        audio.write(0xF800, 0x7F);
+        assert_eq!(audio.read_mut(0x4800), 0x34);
+        assert_eq!(audio.read_mut(0x4800), 0x34);
+        audio.write(0xF800, 0x80 | 0x7F);
+        assert_eq!(audio.read_mut(0x4800), 0x34);
+        assert_eq!(audio.read(0x4800), 0x56);
+    }
+
+    #[test]
+    fn test_channel_plays_wave() {
+        let mut audio = Namco163Audio::new();
+        // A 4 sample wave of 15, 0, 15, 0 at address 0
+        audio.ram[0] = 0x0F;
+        audio.ram[1] = 0x0F;
+        // Channel 7 steps one sample per update with a length of 4 samples at full volume
+        audio.ram[0x78] = 0x00;
+        audio.ram[0x7A] = 0x00;
+        audio.ram[0x7C] = 0xFC | 0x01;
+        audio.ram[0x7E] = 0x00;
+        audio.ram[0x7F] = 0x0F;
+
+        let mut outputs = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..CYCLES_PER_UPDATE {
+                audio.clock();
+            }
+            outputs.push(audio.outputs[7]);
+        }
+        assert_eq!(outputs, [-120, 105, -120, 105]);
+    }
+}
@@ -0,0 +1,209 @@
+// The whole console behind one api, so frontends, tests and tools don't need to reach into the cpu, bus and ppu
+use alloc::vec::Vec;
+
+use crate::apu::AudioSampler;
+use crate::controller::Buttons;
+use crate::cpu::Cpu;
+use crate::fds::DiskDrive;
+use crate::frame::Frame;
+use crate::mapper::Mapper;
+use crate::memory_bus::MemoryBus;
+use crate::ppu::Ppu;
+use crate::region::Region;
+use crate::rom::{Rom, RomError};
+use crate::state::StateError;
+
+pub struct Nes {
+    cpu: Cpu,
+    // The cpu runs whole instructions, so step_cycle runs one and then waits out the rest of its cycles
+    pending_cycles: u32,
+}
+
+impl Nes {
+    // Powers on with the cartridge inserted
+    pub fn new(rom: Rom) -> Result<Nes, RomError> {
+        Ok(Nes {
+            cpu: Cpu::new(MemoryBus::new(rom)?),
+            pending_cycles: 0,
+        })
+    }
+
+    // The reset button, which only resets the cpu, everything else carries on from where it was
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.pending_cycles = 0;
+    }
+
+    // Runs until the ppu finishes the frame it's on
+    pub fn run_frame(&mut self) {
+        self.pending_cycles = 0;
+        self.cpu.run_frame();
+    }
+
+    // Runs one instruction, or an interrupt and the first instruction of its handler, and returns the cycles it took
+    pub fn step_instruction(&mut self) -> u32 {
+        self.pending_cycles = 0;
+        let cycles = self.cpu.cycles;
+        self.cpu.instruction_cycle();
+        self.cpu.cycles.wrapping_sub(cycles)
+    }
+
+    // Advances one cpu cycle. An instruction takes effect all at once on its first cycle
+    pub fn step_cycle(&mut self) {
+        if self.pending_cycles == 0 {
+            self.pending_cycles = self.step_instruction();
+        }
+        self.pending_cycles -= 1;
+    }
+
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.cpu.memory_bus.controllers[port].buttons = buttons;
+    }
+
+    pub fn buttons(&self, port: usize) -> Buttons {
+        self.cpu.memory_bus.controllers[port].buttons
+    }
+
+    // Starts collecting audio samples at the sample rate, or stops with None
+    pub fn set_audio_sample_rate(&mut self, sample_rate: Option<u32>) {
+        let clock_rate = self.region().cpu_clock_rate();
+        self.cpu.memory_bus.audio = sample_rate.map(|sample_rate| AudioSampler::new(sample_rate, clock_rate));
+    }
+
+    // The mono samples produced since the last call, empty if audio isn't being collected
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.cpu
+            .memory_bus
+            .audio
+            .as_mut()
+            .map_or_else(Vec::new, |audio| core::mem::take(&mut audio.samples))
+    }
+
+    // The picture being drawn, which holds the last whole frame right after run_frame
+    pub fn frame(&self) -> &Frame {
+        &self.cpu.memory_bus.ppu.frame
+    }
+
+    // Frames finished since power on
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.memory_bus.ppu.frame_count
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.memory_bus.ppu.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu.memory_bus.set_region(region);
+        if let Some(audio) = &self.cpu.memory_bus.audio {
+            let sample_rate = audio.sample_rate();
+            self.set_audio_sample_rate(Some(sample_rate));
+        }
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    // Leaves the machine as it was if the state can't be loaded
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        self.pending_cycles = 0;
+        self.cpu.load_state(bytes)
+    }
+
+    // The cartridge, for its battery backed RAM
+    pub fn mapper(&self) -> &dyn Mapper {
+        self.cpu.memory_bus.mapper.as_ref()
+    }
+
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        self.cpu.memory_bus.mapper.as_mut()
+    }
+
+    // Restores battery backed RAM from a save without losing the trainer, if the rom has one
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        self.cpu.memory_bus.load_save_ram(data);
+    }
+
+    // The Famicom Disk System's drive, if the cartridge is one
+    pub fn disk_drive(&mut self) -> Option<&mut DiskDrive> {
+        self.cpu.memory_bus.mapper.disk_drive()
+    }
+
+    // For debuggers, these are read only so nothing can change the machine behind the api's back
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.cpu.memory_bus.ppu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // Turns on red emphasis through $2001 while the first controller's A button is held, and keeps a pulse channel playing
+    fn test_rom() -> Rom {
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01];
+        bytes.resize(16, 0);
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, // loop: LDA #$01, STA $4016
+            0xA9, 0x00, 0x8D, 0x16, 0x40, // LDA #$00, STA $4016
+            0xAD, 0x16, 0x40,             // LDA $4016
+            0x29, 0x01,                   // AND #$01
+            0x0A, 0x0A, 0x0A, 0x0A, 0x0A, // ASL A x5
+            0x09, 0x08,                   // ORA #$08
+            0x8D, 0x01, 0x20,             // STA $2001
+            0xA9, 0x01, 0x8D, 0x15, 0x40, // LDA #$01, STA $4015
+            0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF, STA $4000
+            0xA9, 0x08, 0x8D, 0x03, 0x40, // LDA #$08, STA $4003
+            0x4C, 0x00, 0x80,             // JMP loop
+        ];
+        let mut prg_rom = program.to_vec();
+        prg_rom.resize(0x4000, 0);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        bytes.extend(prg_rom);
+        bytes.extend((0..0x2000).map(|i| (i * 3) as u8));
+        Rom::new(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_step_cycle() {
+        let mut nes = Nes::new(test_rom()).unwrap();
+        let mut stepped = Nes::new(test_rom()).unwrap();
+        let cycles: u32 = (0..100).map(|_| nes.step_instruction()).sum();
+        for _ in 0..cycles {
+            stepped.step_cycle();
+        }
+        assert_eq!(stepped.cpu().pc, nes.cpu().pc);
+        assert_eq!(stepped.save_state(), nes.save_state());
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut nes = Nes::new(test_rom()).unwrap();
+        nes.set_audio_sample_rate(Some(44100));
+        nes.run_frame();
+        nes.run_frame();
+        assert_eq!(nes.frame_count(), 2);
+        let released = nes.frame().crc32();
+        // The ppu powers on at the end of a frame, so the first one is short and this is about one frame's worth
+        assert!((600..=800).contains(&nes.take_audio_samples().len()));
+        assert!(nes.take_audio_samples().is_empty());
+
+        nes.set_buttons(0, Buttons::A);
+        nes.run_frame();
+        nes.run_frame();
+        assert_eq!(nes.buttons(0), Buttons::A);
+        assert_ne!(nes.frame().crc32(), released);
+
+        nes.set_region(Region::Pal);
+        nes.run_frame();
+        assert_eq!(nes.region(), Region::Pal);
+        assert!(!nes.take_audio_samples().is_empty());
+    }
+}
@@ -0,0 +1,54 @@
+#[cfg(test)]
+mod tests {
+    use crate::headless::HeadlessRunner;
+    use crate::movie::{Movie, MovieSession};
+    use crate::nes::Nes;
+    use crate::rom::Rom;
+    use alloc::format;
+    use alloc::vec::Vec;
+    use std::fs;
+    use std::path::Path;
+
+    //#[test]
+    //fn test_nestest() {
+    //    let test_bin_path = "nestest.nes";
+
+    //    let rom = fs::read(test_bin_path).expect("Invalid file");
+    //    let mut cpu = Cpu::new(MemoryBus::new(Rom::new(rom.as_slice()).unwrap()).unwrap());
+    //    cpu.pc = 0xC000;
+
+    //    for i in 0..500 {
+    //        cpu.step();
+    //    }
+    //}
+
+    // Runs every line of tests/golden/frame_hashes.txt and checks the picture still hashes the same
+    #[test]
+    fn test_golden_frame_hashes() {
+        let tests_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let golden = fs::read_to_string(tests_path.join("golden/frame_hashes.txt")).unwrap();
+        let mut mismatches = Vec::new();
+        for line in golden.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [rom_path, movie_path, frame, expected] = fields[..] else {
+                panic!("Bad golden hash line: {}", line);
+            };
+            let rom = Rom::new(&fs::read(tests_path.join(rom_path)).unwrap()).unwrap();
+            let mut nes = Nes::new(rom).unwrap();
+            let movie = (movie_path != "-").then(|| {
+                let movie = Movie::load(&fs::read(tests_path.join(movie_path)).unwrap()).unwrap();
+                MovieSession::play(movie, &mut nes).unwrap()
+            });
+            let mut runner = HeadlessRunner::new(nes, movie);
+            let hash = format!("{:08x}", runner.frame_hashes(&[frame.parse().unwrap()])[0]);
+            if hash != expected {
+                mismatches.push(format!("{} {} {} {}", rom_path, movie_path, frame, hash));
+            }
+        }
+        assert!(
+            mismatches.is_empty(),
+            "Frames differ from the golden hashes, if the change is intended the lines should now be:\n{}",
+            mismatches.join("\n")
+        );
+    }
+}
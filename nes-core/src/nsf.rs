@@ -0,0 +1,790 @@
+// NSF and NSFe music rips, and a player that calls their INIT and PLAY routines like an NSF player cartridge would.
+// See https://www.nesdev.org/wiki/NSF and https://www.nesdev.org/wiki/NSFe
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use bitflags::bitflags;
+
+use crate::apu::AudioSampler;
+use crate::cpu::Cpu;
+use crate::fds_audio::FdsAudio;
+use crate::mapper::Mapper;
+use crate::memory_bus::MemoryBus;
+use crate::mmc5_audio::Mmc5Audio;
+use crate::n163_audio::Namco163Audio;
+use crate::region::Region;
+use crate::rom::Mirroring;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::sunsoft5b_audio::Sunsoft5bAudio;
+use crate::vrc6_audio::Vrc6Audio;
+
+const NSF_MAGIC: [u8; 5] = *b"NESM\x1A";
+const NSFE_MAGIC: [u8; 4] = *b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+const NSFE_CHUNK_HEADER_SIZE: usize = 8;
+const BANK_SIZE: usize = 0x1000;
+// The rates most NSFs use, 1/60 and 1/50 of a second in microseconds
+const DEFAULT_NTSC_SPEED: u16 = 16639;
+const DEFAULT_PAL_SPEED: u16 = 19997;
+// Tracks without a length in the file play for this long and then fade out
+pub const DEFAULT_TRACK_LENGTH_MS: u32 = 150_000;
+pub const DEFAULT_FADE_MS: u32 = 5_000;
+// INIT and PLAY return to a JMP to itself at this otherwise unused address, where the cpu waits for the next PLAY call
+const IDLE_LOOP_ADDRESS: u16 = 0x4100;
+const IDLE_LOOP: [u8; 3] = [0x4C, IDLE_LOOP_ADDRESS as u8, (IDLE_LOOP_ADDRESS >> 8) as u8];
+const PRG_RAM_SIZE: usize = 0x2000;
+// FDS tunes run from RAM at $6000-$FFFF
+const FDS_RAM_SIZE: usize = 0xA000;
+const MMC5_EXRAM_SIZE: usize = 0x400;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+bitflags! {
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct ExpansionChips: u8 {
+        const Vrc6 = 0b0000_0001;
+        const Vrc7 = 0b0000_0010;
+        const Fds = 0b0000_0100;
+        const Mmc5 = 0b0000_1000;
+        const Namco163 = 0b0001_0000;
+        const Sunsoft5b = 0b0010_0000;
+    }
+}
+
+impl ExpansionChips {
+    // The chips the player can't emulate, their channels stay silent
+    pub const UNSUPPORTED: ExpansionChips = ExpansionChips::Vrc7;
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.iter()
+            .map(|chip| match chip {
+                ExpansionChips::Vrc6 => "VRC6",
+                ExpansionChips::Vrc7 => "VRC7",
+                ExpansionChips::Fds => "FDS",
+                ExpansionChips::Mmc5 => "MMC5",
+                ExpansionChips::Namco163 => "Namco 163",
+                _ => "Sunsoft 5B",
+            })
+            .collect()
+    }
+}
+
+pub fn is_nsf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&NSF_MAGIC) || bytes.starts_with(&NSFE_MAGIC)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum NsfError {
+    Truncated,
+    BadMagic,
+    NoTracks,
+    // An NSFe file without its INFO or DATA chunk
+    MissingChunk(&'static str),
+    // NSFe chunks with an uppercase first letter have to be understood to play the file
+    UnknownRequiredChunk(String),
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NsfError::Truncated => write!(f, "NSF file ends early"),
+            NsfError::BadMagic => write!(f, "File is not in NSF or NSFe format"),
+            NsfError::NoTracks => write!(f, "NSF file has no tracks"),
+            NsfError::MissingChunk(id) => write!(f, "NSFe file has no {} chunk", id),
+            NsfError::UnknownRequiredChunk(id) => write!(f, "NSFe chunk {} is not supported", id),
+        }
+    }
+}
+
+impl core::error::Error for NsfError {}
+
+// Metadata from an NSFe file or an NSF2 file's metadata chunks
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct TrackInfo {
+    pub name: Option<String>,
+    pub length_ms: Option<u32>,
+    pub fade_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Nsf {
+    pub track_count: usize,
+    // Counted from 0, unlike in the header
+    pub starting_track: usize,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: Option<String>,
+    // How often PLAY is called in microseconds
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    pub region: Region,
+    // The initial values of the bank registers at $5FF8-$5FFF, all 0 if the tune doesn't bankswitch
+    pub bank_init: [u8; 8],
+    pub expansion_chips: ExpansionChips,
+    pub data: Vec<u8>,
+    pub tracks: Vec<TrackInfo>,
+}
+
+impl Nsf {
+    pub fn new(bytes: &[u8]) -> Result<Nsf, NsfError> {
+        if bytes.starts_with(&NSFE_MAGIC) {
+            return Self::from_nsfe(bytes);
+        }
+        if bytes.len() < NSF_HEADER_SIZE {
+            return Err(NsfError::Truncated);
+        }
+        if !bytes.starts_with(&NSF_MAGIC) {
+            return Err(NsfError::BadMagic);
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let mut nsf = Nsf {
+            track_count: bytes[0x06] as usize,
+            starting_track: (bytes[0x07] as usize).saturating_sub(1),
+            load_address: word(0x08),
+            init_address: word(0x0A),
+            play_address: word(0x0C),
+            title: Self::string(&bytes[0x0E..0x2E]),
+            artist: Self::string(&bytes[0x2E..0x4E]),
+            copyright: Self::string(&bytes[0x4E..0x6E]),
+            ripper: None,
+            ntsc_speed: word(0x6E),
+            pal_speed: word(0x78),
+            region: Self::region(bytes[0x7A]),
+            bank_init: bytes[0x70..0x78].try_into().unwrap(),
+            expansion_chips: ExpansionChips::from_bits_truncate(bytes[0x7B]),
+            data: bytes[NSF_HEADER_SIZE..].to_vec(),
+            tracks: Vec::new(),
+        };
+
+        // NSF2 files can give the program's length and follow it with metadata in NSFe chunks
+        let version = bytes[0x05];
+        let program_length = u32::from_le_bytes([bytes[0x7D], bytes[0x7E], bytes[0x7F], 0]) as usize;
+        if version >= 2 && program_length > 0 {
+            let metadata = bytes.get(NSF_HEADER_SIZE + program_length..).ok_or(NsfError::Truncated)?;
+            nsf.data.truncate(program_length);
+            if bytes[0x7C] & 0x80 != 0 {
+                nsf.parse_chunks(metadata)?;
+            }
+        }
+        nsf.finish()
+    }
+
+    // NSFe files are made of chunks of a 32 bit length, a 4 character id and the data
+    fn from_nsfe(bytes: &[u8]) -> Result<Nsf, NsfError> {
+        let mut nsf = Nsf {
+            track_count: 0,
+            starting_track: 0,
+            load_address: 0,
+            init_address: 0,
+            play_address: 0,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: None,
+            ntsc_speed: 0,
+            pal_speed: 0,
+            region: Region::Ntsc,
+            bank_init: [0; 8],
+            expansion_chips: ExpansionChips::empty(),
+            data: Vec::new(),
+            tracks: Vec::new(),
+        };
+        let chunks = nsf.parse_chunks(&bytes[NSFE_MAGIC.len()..])?;
+        if !chunks.contains(b"INFO") {
+            return Err(NsfError::MissingChunk("INFO"));
+        }
+        if !chunks.contains(b"DATA") {
+            return Err(NsfError::MissingChunk("DATA"));
+        }
+        nsf.finish()
+    }
+
+    // Returns the ids of the chunks that were read
+    fn parse_chunks(&mut self, mut bytes: &[u8]) -> Result<Vec<[u8; 4]>, NsfError> {
+        let mut ids = Vec::new();
+        while bytes.len() >= NSFE_CHUNK_HEADER_SIZE {
+            let length = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+            let id: [u8; 4] = bytes[4..8].try_into().unwrap();
+            let data = bytes
+                .get(NSFE_CHUNK_HEADER_SIZE..NSFE_CHUNK_HEADER_SIZE + length)
+                .ok_or(NsfError::Truncated)?;
+            bytes = &bytes[NSFE_CHUNK_HEADER_SIZE + length..];
+            ids.push(id);
+
+            let word = |offset: usize| data.get(offset..offset + 2).map(|word| u16::from_le_bytes([word[0], word[1]]));
+            let strings = || data.split(|byte| *byte == 0).map(Self::string);
+            match &id {
+                b"INFO" => {
+                    self.load_address = word(0).ok_or(NsfError::Truncated)?;
+                    self.init_address = word(2).ok_or(NsfError::Truncated)?;
+                    self.play_address = word(4).ok_or(NsfError::Truncated)?;
+                    self.region = Self::region(data.get(6).copied().unwrap_or(0));
+                    self.expansion_chips = ExpansionChips::from_bits_truncate(data.get(7).copied().unwrap_or(0));
+                    self.track_count = data.get(8).copied().unwrap_or(1) as usize;
+                    self.starting_track = data.get(9).copied().unwrap_or(0) as usize;
+                }
+                b"DATA" => self.data = data.to_vec(),
+                b"NEND" => break,
+                b"BANK" => {
+                    for (bank, value) in self.bank_init.iter_mut().zip(data) {
+                        *bank = *value;
+                    }
+                }
+                b"RATE" => {
+                    self.ntsc_speed = word(0).unwrap_or(0);
+                    self.pal_speed = word(2).unwrap_or(0);
+                }
+                b"auth" => {
+                    let mut strings = strings();
+                    self.title = strings.next().unwrap_or_default();
+                    self.artist = strings.next().unwrap_or_default();
+                    self.copyright = strings.next().unwrap_or_default();
+                    self.ripper = strings.next().filter(|ripper| !ripper.is_empty());
+                }
+                b"tlbl" => {
+                    for (track, name) in strings().enumerate() {
+                        self.track_info(track).name = Some(name);
+                    }
+                }
+                b"time" | b"fade" => {
+                    for (track, milliseconds) in data.chunks_exact(4).enumerate() {
+                        // Negative times mean the track uses the default
+                        let milliseconds = u32::try_from(i32::from_le_bytes(milliseconds.try_into().unwrap())).ok();
+                        let info = self.track_info(track);
+                        if &id == b"time" {
+                            info.length_ms = milliseconds;
+                        } else {
+                            info.fade_ms = milliseconds;
+                        }
+                    }
+                }
+                _ if id[0].is_ascii_uppercase() => return Err(NsfError::UnknownRequiredChunk(String::from_utf8_lossy(&id).into_owned())),
+                // Optional chunks such as playlists and text are skipped
+                _ => {}
+            }
+        }
+        Ok(ids)
+    }
+
+    fn track_info(&mut self, track: usize) -> &mut TrackInfo {
+        if self.tracks.len() <= track {
+            self.tracks.resize(track + 1, TrackInfo::default());
+        }
+        &mut self.tracks[track]
+    }
+
+    fn finish(mut self) -> Result<Nsf, NsfError> {
+        if self.track_count == 0 {
+            return Err(NsfError::NoTracks);
+        }
+        self.starting_track = self.starting_track.min(self.track_count - 1);
+        self.tracks.resize(self.track_count, TrackInfo::default());
+        Ok(self)
+    }
+
+    // Bit 0 is set for PAL tunes and bit 1 for tunes that work on both, which play as NTSC
+    fn region(flags: u8) -> Region {
+        if flags & 0b11 == 0b01 {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        }
+    }
+
+    fn string(bytes: &[u8]) -> String {
+        let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).trim().to_owned()
+    }
+
+    pub fn bankswitched(&self) -> bool {
+        self.bank_init.iter().any(|bank| *bank != 0)
+    }
+
+    // Cpu cycles between calls to PLAY
+    pub fn play_period(&self) -> f64 {
+        let speed = match self.region {
+            Region::Pal => Some(self.pal_speed).filter(|speed| *speed != 0).unwrap_or(DEFAULT_PAL_SPEED),
+            _ => Some(self.ntsc_speed).filter(|speed| *speed != 0).unwrap_or(DEFAULT_NTSC_SPEED),
+        };
+        speed as f64 * self.region.cpu_clock_rate() / 1_000_000.0
+    }
+
+    pub fn track_name(&self, track: usize) -> String {
+        self.tracks[track]
+            .name
+            .clone()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| format!("Track {}", track + 1))
+    }
+
+    // How long the track plays before fading out, and how long the fade takes
+    pub fn track_length_ms(&self, track: usize) -> (u32, u32) {
+        let info = &self.tracks[track];
+        (info.length_ms.unwrap_or(DEFAULT_TRACK_LENGTH_MS), info.fade_ms.unwrap_or(DEFAULT_FADE_MS))
+    }
+}
+
+// The NSF player's hardware, 4 KiB banks at $8000-$FFFF switched through $5FF8-$5FFF, 8 KiB of RAM and the tune's expansion chips
+pub struct NsfMapper {
+    // The tune's data, padded so it starts at the right offset within its first bank
+    rom: Vec<u8>,
+    banks: [usize; 8],
+    // $6000-$7FFF, or $6000-$FFFF for FDS tunes
+    ram: Vec<u8>,
+    fds: bool,
+    vrc6: Option<Vrc6Audio>,
+    fds_audio: Option<FdsAudio>,
+    mmc5: Option<Mmc5Audio>,
+    mmc5_exram: Vec<u8>,
+    mmc5_multiplier: [u8; 2],
+    n163: Option<Namco163Audio>,
+    sunsoft5b: Option<Sunsoft5bAudio>,
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &Nsf) -> Self {
+        let chips = nsf.expansion_chips;
+        let fds = chips.contains(ExpansionChips::Fds);
+        let bankswitched = nsf.bankswitched();
+
+        // Tunes that don't bankswitch are loaded at their load address in a flat 32 KiB
+        let padding = if bankswitched {
+            nsf.load_address as usize & (BANK_SIZE - 1)
+        } else {
+            (nsf.load_address as usize).saturating_sub(0x8000)
+        };
+        let mut rom = vec![0; padding];
+        rom.extend_from_slice(&nsf.data);
+        let bank_count = rom.len().div_ceil(BANK_SIZE).max(if bankswitched { 1 } else { 8 });
+        rom.resize(bank_count * BANK_SIZE, 0);
+
+        let mut mapper = NsfMapper {
+            rom,
+            banks: [0, 1, 2, 3, 4, 5, 6, 7],
+            ram: vec![0; if fds { FDS_RAM_SIZE } else { PRG_RAM_SIZE }],
+            fds,
+            vrc6: chips.contains(ExpansionChips::Vrc6).then(Vrc6Audio::new),
+            fds_audio: fds.then(FdsAudio::new),
+            mmc5: chips.contains(ExpansionChips::Mmc5).then(Mmc5Audio::new),
+            mmc5_exram: vec![0; MMC5_EXRAM_SIZE],
+            mmc5_multiplier: [0xFF; 2],
+            n163: chips.contains(ExpansionChips::Namco163).then(Namco163Audio::new),
+            sunsoft5b: chips.contains(ExpansionChips::Sunsoft5b).then(Sunsoft5bAudio::new),
+        };
+
+        match (bankswitched, fds) {
+            (true, false) => {
+                for (slot, bank) in nsf.bank_init.iter().enumerate() {
+                    mapper.write(0x5FF8 + slot as u16, *bank);
+                }
+            }
+            // FDS tunes bankswitch by copying banks into RAM, $6000 and $7000 start out with the same banks as $E000 and $F000
+            (true, true) => {
+                for (slot, bank) in [nsf.bank_init[6], nsf.bank_init[7]].iter().chain(&nsf.bank_init).enumerate() {
+                    mapper.write(0x5FF6 + slot as u16, *bank);
+                }
+            }
+            (false, false) => {}
+            (false, true) => {
+                let start = (nsf.load_address as usize).saturating_sub(0x6000);
+                let length = nsf.data.len().min(FDS_RAM_SIZE.saturating_sub(start));
+                mapper.ram[start..start + length].copy_from_slice(&nsf.data[..length]);
+            }
+        }
+        mapper
+    }
+
+    fn bank(&self, bank: u8) -> &[u8] {
+        let start = (bank as usize % (self.rom.len() / BANK_SIZE)) * BANK_SIZE;
+        &self.rom[start..start + BANK_SIZE]
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            IDLE_LOOP_ADDRESS..=0x4102 => IDLE_LOOP[(address - IDLE_LOOP_ADDRESS) as usize],
+            0x4040..=0x4092 if self.fds_audio.is_some() => self.fds_audio.as_ref().unwrap().read(address),
+            0x4800..=0x4FFF if self.n163.is_some() => self.n163.as_ref().unwrap().read(address),
+            0x5015 if self.mmc5.is_some() => self.mmc5.as_ref().unwrap().read_status(),
+            0x5205 => (self.mmc5_multiplier[0] as u16 * self.mmc5_multiplier[1] as u16) as u8,
+            0x5206 => ((self.mmc5_multiplier[0] as u16 * self.mmc5_multiplier[1] as u16) >> 8) as u8,
+            0x5C00..=0x5FF5 if self.mmc5.is_some() => self.mmc5_exram[(address - 0x5C00) as usize],
+            0x6000..=0xFFFF if self.fds => self.ram[(address - 0x6000) as usize],
+            0x6000..=0x7FFF => self.ram[(address - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                let slot = (address as usize - 0x8000) / BANK_SIZE;
+                self.rom[self.banks[slot] * BANK_SIZE + (address as usize & (BANK_SIZE - 1))]
+            }
+            _ => 0,
+        }
+    }
+
+    fn read_mut(&mut self, address: u16) -> u8 {
+        match (address, &mut self.n163) {
+            (0x4800..=0x4FFF, Some(n163)) => n163.read_mut(address),
+            _ => self.read(address),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        // The expansion chips' registers can share addresses with RAM and ROM
+        if let Some(fds_audio) = &mut self.fds_audio {
+            fds_audio.write(address, value);
+        }
+        if let (0x9000..=0xB002, Some(vrc6)) = (address, &mut self.vrc6) {
+            vrc6.write(address, value);
+        }
+        if let (0x5000..=0x5015, Some(mmc5)) = (address, &mut self.mmc5) {
+            mmc5.write(address, value);
+        }
+        if let (0x4800..=0x4FFF | 0xF800..=0xFFFF, Some(n163)) = (address, &mut self.n163) {
+            n163.write(address, value);
+        }
+        if let (0xC000..=0xFFFF, Some(sunsoft5b)) = (address, &mut self.sunsoft5b) {
+            sunsoft5b.write(address, value);
+        }
+
+        match address {
+            0x5205 | 0x5206 => self.mmc5_multiplier[(address - 0x5205) as usize] = value,
+            0x5C00..=0x5FF5 => self.mmc5_exram[(address - 0x5C00) as usize] = value,
+            0x5FF6..=0x5FFF if self.fds => {
+                let start = (address - 0x5FF6) as usize * BANK_SIZE;
+                let bank = self.bank(value).to_vec();
+                self.ram[start..start + BANK_SIZE].copy_from_slice(&bank);
+            }
+            0x5FF8..=0x5FFF => self.banks[(address - 0x5FF8) as usize] = value as usize % (self.rom.len() / BANK_SIZE),
+            // The FDS BIOS would be at $E000, tunes can't write there
+            0x6000..=0xDFFF if self.fds => self.ram[(address - 0x6000) as usize] = value,
+            0x6000..=0x7FFF => self.ram[(address - 0x6000) as usize] = value,
+            _ => {}
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.banks);
+        state.write(&self.ram);
+        if let Some(vrc6) = &self.vrc6 {
+            vrc6.save_state(state);
+        }
+        if let Some(fds_audio) = &self.fds_audio {
+            fds_audio.save_state(state);
+        }
+        if let Some(mmc5) = &self.mmc5 {
+            mmc5.save_state(state);
+        }
+        state.write(&self.mmc5_exram);
+        state.write(&self.mmc5_multiplier);
+        if let Some(n163) = &self.n163 {
+            n163.save_state(state);
+        }
+        if let Some(sunsoft5b) = &self.sunsoft5b {
+            sunsoft5b.save_state(state);
+        }
+    }
+
+    // The chips come from the NSF's header, so a state only loads into a mapper made from the same tune
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let banks: [usize; 8] = state.read()?;
+        if banks.iter().any(|bank| *bank >= self.rom.len() / BANK_SIZE) {
+            return Err(StateError::Mismatch("bank"));
+        }
+        self.banks = banks;
+        state.read_into(&mut self.ram)?;
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.load_state(state)?;
+        }
+        if let Some(fds_audio) = &mut self.fds_audio {
+            fds_audio.load_state(state)?;
+        }
+        if let Some(mmc5) = &mut self.mmc5 {
+            mmc5.load_state(state)?;
+        }
+        state.read_into(&mut self.mmc5_exram)?;
+        self.mmc5_multiplier = state.read()?;
+        if let Some(n163) = &mut self.n163 {
+            n163.load_state(state)?;
+        }
+        if let Some(sunsoft5b) = &mut self.sunsoft5b {
+            sunsoft5b.load_state(state)?;
+        }
+        Ok(())
+    }
+
+    fn clock(&mut self, cpu_cycles: u32) {
+        for _ in 0..cpu_cycles {
+            if let Some(vrc6) = &mut self.vrc6 {
+                vrc6.clock();
+            }
+            if let Some(fds_audio) = &mut self.fds_audio {
+                fds_audio.clock();
+            }
+            if let Some(mmc5) = &mut self.mmc5 {
+                mmc5.clock();
+            }
+            if let Some(n163) = &mut self.n163 {
+                n163.clock();
+            }
+            if let Some(sunsoft5b) = &mut self.sunsoft5b {
+                sunsoft5b.clock();
+            }
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.vrc6.as_ref().map_or(0.0, Vrc6Audio::output)
+            + self.fds_audio.as_ref().map_or(0.0, FdsAudio::output)
+            + self.mmc5.as_ref().map_or(0.0, Mmc5Audio::output)
+            + self.n163.as_ref().map_or(0.0, Namco163Audio::output)
+            + self.sunsoft5b.as_ref().map_or(0.0, Sunsoft5bAudio::output)
+    }
+}
+
+// Plays an NSF's tracks, producing audio samples at the given sample rate
+pub struct NsfPlayer {
+    pub nsf: Nsf,
+    pub cpu: Cpu,
+    pub track: usize,
+    sample_rate: u32,
+    play_period: f64,
+    cycles_until_play: f64,
+    // Samples rendered since the track started
+    samples_rendered: u64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf, sample_rate: u32) -> Self {
+        let track = nsf.starting_track;
+        NsfPlayer {
+            cpu: Self::start_track(&nsf, track, sample_rate),
+            play_period: nsf.play_period(),
+            cycles_until_play: nsf.play_period(),
+            nsf,
+            track,
+            sample_rate,
+            samples_rendered: 0,
+        }
+    }
+
+    // Every track gets a freshly powered on console, so nothing carries over from the previous track
+    fn start_track(nsf: &Nsf, track: usize, sample_rate: u32) -> Cpu {
+        let mut memory_bus = MemoryBus::from_mapper(Box::new(NsfMapper::new(nsf)), vec![0; CHR_RAM_SIZE], Mirroring::Horizontal, nsf.region);
+        memory_bus.audio = Some(AudioSampler::new(sample_rate, nsf.region.cpu_clock_rate()));
+        for address in 0x4000..=0x4013 {
+            memory_bus.write(address, 0);
+        }
+        memory_bus.write(0x4015, 0x00);
+        memory_bus.write(0x4015, 0x0F);
+        memory_bus.write(0x4017, 0x40);
+
+        let mut cpu = Cpu::new(memory_bus);
+        cpu.a = track as u8;
+        cpu.x = (nsf.region == Region::Pal) as u8;
+        Self::call(&mut cpu, nsf.init_address);
+        cpu
+    }
+
+    // Calls a routine that returns to the idle loop with RTS
+    fn call(cpu: &mut Cpu, address: u16) {
+        cpu.push_word(IDLE_LOOP_ADDRESS - 1);
+        cpu.pc = address;
+    }
+
+    pub fn select_track(&mut self, track: usize) {
+        self.track = track % self.nsf.track_count;
+        self.cpu = Self::start_track(&self.nsf, self.track, self.sample_rate);
+        self.cycles_until_play = self.play_period;
+        self.samples_rendered = 0;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // The samples in the whole track including its fade out
+    pub fn track_sample_count(&self) -> u64 {
+        let (length, fade) = self.nsf.track_length_ms(self.track);
+        (length + fade) as u64 * self.sample_rate as u64 / 1000
+    }
+
+    pub fn track_finished(&self) -> bool {
+        self.samples_rendered >= self.track_sample_count()
+    }
+
+    // Runs the tune until it has produced `count` samples, which fade out at the end of the track
+    pub fn render(&mut self, count: usize) -> Vec<f32> {
+        while self.audio().samples.len() < count {
+            self.step();
+        }
+        let mut samples: Vec<f32> = self.audio().samples.drain(..count).collect();
+
+        let (length, fade) = self.nsf.track_length_ms(self.track);
+        let fade_start = length as u64 * self.sample_rate as u64 / 1000;
+        let fade_samples = (fade as u64 * self.sample_rate as u64 / 1000).max(1);
+        for sample in &mut samples {
+            if self.samples_rendered >= fade_start {
+                *sample *= 1.0 - ((self.samples_rendered - fade_start) as f32 / fade_samples as f32).min(1.0);
+            }
+            self.samples_rendered += 1;
+        }
+        samples
+    }
+
+    fn audio(&mut self) -> &mut AudioSampler {
+        self.cpu.memory_bus.audio.as_mut().expect("NSF player always samples audio")
+    }
+
+    fn step(&mut self) {
+        if self.cpu.pc == IDLE_LOOP_ADDRESS && self.cycles_until_play <= 0.0 {
+            self.cycles_until_play += self.play_period;
+            // PLAY took longer than its period, so the missed calls are dropped
+            if self.cycles_until_play <= 0.0 {
+                self.cycles_until_play = self.play_period;
+            }
+            Self::call(&mut self.cpu, self.nsf.play_address);
+        }
+        let cycles = self.cpu.cycles;
+        self.cpu.instruction_cycle();
+        self.cycles_until_play -= self.cpu.cycles.wrapping_sub(cycles) as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nsf_bytes(load_address: u16, bank_init: [u8; 8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; NSF_HEADER_SIZE];
+        bytes[..5].copy_from_slice(&NSF_MAGIC);
+        bytes[0x05] = 1;
+        bytes[0x06] = 3;
+        bytes[0x07] = 2;
+        bytes[0x08..0x0A].copy_from_slice(&load_address.to_le_bytes());
+        bytes[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        bytes[0x0C..0x0E].copy_from_slice(&0x8020u16.to_le_bytes());
+        bytes[0x0E..0x12].copy_from_slice(b"Song");
+        bytes[0x2E..0x34].copy_from_slice(b"Artist");
+        bytes[0x6E..0x70].copy_from_slice(&DEFAULT_NTSC_SPEED.to_le_bytes());
+        bytes[0x70..0x78].copy_from_slice(&bank_init);
+        bytes[0x7B] = 0b0000_0001;
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_nsf_header() {
+        let nsf = Nsf::new(&nsf_bytes(0x8000, [0; 8], &[0xEA; 16])).unwrap();
+        assert_eq!((nsf.track_count, nsf.starting_track), (3, 1));
+        assert_eq!((nsf.load_address, nsf.init_address, nsf.play_address), (0x8000, 0x8000, 0x8020));
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str(), nsf.copyright.as_str()), ("Song", "Artist", ""));
+        assert_eq!(nsf.region, Region::Ntsc);
+        assert_eq!(nsf.expansion_chips, ExpansionChips::Vrc6);
+        assert!(!nsf.bankswitched());
+        assert_eq!(nsf.data.len(), 16);
+        assert_eq!(nsf.track_name(2), "Track 3");
+        assert!((nsf.play_period() - 29780.0).abs() < 1.0);
+        assert_eq!(Nsf::new(b"NESM\x1A").err(), Some(NsfError::Truncated));
+    }
+
+    #[test]
+    fn test_nsfe_chunks() {
+        let mut bytes = NSFE_MAGIC.to_vec();
+        bytes.extend(chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x20, 0x80, 0x01, 0x04, 2, 1]));
+        bytes.extend(chunk(b"BANK", &[0, 1, 2]));
+        bytes.extend(chunk(b"DATA", &[0xEA; 0x3000]));
+        bytes.extend(chunk(b"auth", b"Game\0Composer\0 2024 \0Ripper\0"));
+        bytes.extend(chunk(b"tlbl", b"Title\0Ending\0"));
+        bytes.extend(chunk(b"time", &[(60_000i32).to_le_bytes(), (-1i32).to_le_bytes()].concat()));
+        bytes.extend(chunk(b"fade", &(1_000i32).to_le_bytes()));
+        bytes.extend(chunk(b"plst", &[1, 0]));
+        bytes.extend(chunk(b"NEND", &[]));
+
+        let nsf = Nsf::new(&bytes).unwrap();
+        assert_eq!((nsf.track_count, nsf.starting_track), (2, 1));
+        assert_eq!(nsf.region, Region::Pal);
+        assert_eq!(nsf.expansion_chips, ExpansionChips::Fds);
+        assert_eq!(nsf.bank_init, [0, 1, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            (nsf.title.as_str(), nsf.artist.as_str(), nsf.copyright.as_str()),
+            ("Game", "Composer", "2024")
+        );
+        assert_eq!(nsf.ripper.as_deref(), Some("Ripper"));
+        assert_eq!(nsf.track_name(1), "Ending");
+        assert_eq!(nsf.track_length_ms(0), (60_000, 1_000));
+        assert_eq!(nsf.track_length_ms(1), (DEFAULT_TRACK_LENGTH_MS, DEFAULT_FADE_MS));
+
+        let mut unknown = NSFE_MAGIC.to_vec();
+        unknown.extend(chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x20, 0x80]));
+        unknown.extend(chunk(b"VRC7", &[]));
+        assert_eq!(Nsf::new(&unknown).err(), Some(NsfError::UnknownRequiredChunk("VRC7".to_owned())));
+        assert_eq!(Nsf::new(&unknown[..unknown.len() - 8]).err(), Some(NsfError::MissingChunk("DATA")));
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        // Three banks filled with their number, loaded $100 bytes into the first bank
+        let data: Vec<u8> = (0..3u8).flat_map(|bank| vec![bank; BANK_SIZE]).collect();
+        let nsf = Nsf::new(&nsf_bytes(0x8100, [0, 1, 2, 2, 2, 2, 2, 1], &data)).unwrap();
+        let mut mapper = NsfMapper::new(&nsf);
+        assert_eq!((mapper.read(0x80FF), mapper.read(0x8100)), (0, 0));
+        assert_eq!((mapper.read(0x90FF), mapper.read(0x9100)), (0, 1));
+        assert_eq!(mapper.read(0xF100), 1);
+        mapper.write(0x5FFF, 3);
+        assert_eq!((mapper.read(0xF0FF), mapper.read(0xF100)), (2, 0));
+        // Bank numbers past the end wrap around
+        mapper.write(0x5FF8, 6);
+        assert_eq!((mapper.read(0x80FF), mapper.read(0x8100)), (1, 2));
+    }
+
+    #[test]
+    fn test_player_calls_init_and_play() {
+        #[rustfmt::skip]
+        let mut program = vec![
+            0x85, 0x00,             // STA $00
+            0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF, STA $4000
+            0xA9, 0xFD, 0x8D, 0x02, 0x40, // LDA #$FD, STA $4002
+            0xA9, 0x08, 0x8D, 0x03, 0x40, // LDA #$08, STA $4003
+            0x60,                   // RTS
+        ];
+        program.resize(0x20, 0xEA);
+        program.extend([0xE6, 0x01, 0x60]); // INC $01, RTS
+
+        let mut nsf = Nsf::new(&nsf_bytes(0x8000, [0; 8], &program)).unwrap();
+        nsf.expansion_chips = ExpansionChips::empty();
+        nsf.tracks[2].length_ms = Some(500);
+        nsf.tracks[2].fade_ms = Some(250);
+        let mut player = NsfPlayer::new(nsf, 44100);
+        assert_eq!(player.track, 1);
+        player.select_track(2);
+
+        let samples = player.render(44100);
+        assert_eq!(player.cpu.memory_bus.cpu_vram[0], 2);
+        assert!((59..=61).contains(&player.cpu.memory_bus.cpu_vram[1]));
+        // The pulse plays until the track fades out
+        assert!(samples[..22050].iter().any(|sample| sample.abs() > 0.01));
+        assert!(samples[33075..].iter().all(|sample| *sample == 0.0));
+        assert!(player.track_finished());
+
+        // Track numbers wrap around
+        player.select_track(3);
+        assert_eq!(player.track, 0);
+        assert!(!player.track_finished());
+    }
+}
@@ -0,0 +1,232 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+
+use libm::{cosf, sinf};
+
+use crate::filter::VideoFilter;
+use crate::frame::Frame;
+use crate::palette::{composite_level, yiq_to_rgb, COMPOSITE_HUE_OFFSET, PALETTE_ENTRIES};
+
+// The ppu outputs 8 samples of its composite signal per pixel, and the colour subcarrier repeats every 12 samples
+const SAMPLES_PER_PIXEL: usize = 8;
+const SUBCARRIER_PHASES: usize = 12;
+// Each scanline is 341 dots, which moves the subcarrier phase of the next line by 341 * 8 % 12 = 4 samples
+const LINE_PHASE_STEP: usize = 4;
+// Likewise every frame of 262 lines starts 4 samples further along, which is what makes the artifacts crawl
+const FRAME_PHASE_STEP: usize = 4;
+// Black padding on each side of a line so the decoding windows never go out of bounds
+const PADDING: usize = 2 * SUBCARRIER_PHASES;
+const LINE_SAMPLES: usize = Frame::WIDTH * SAMPLES_PER_PIXEL + 2 * PADDING;
+
+// Two output pixels for every pixel, enough to show the half pixel wide artifact colours
+pub const NTSC_OUTPUT_WIDTH: usize = Frame::WIDTH * 2;
+const OUTPUT_SAMPLE_STEP: usize = SAMPLES_PER_PIXEL * Frame::WIDTH / NTSC_OUTPUT_WIDTH;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct NtscSettings {
+    // Rotation of every colour in degrees
+    pub hue: f32,
+    // Multiplier for the chroma, 0.0 is black and white
+    pub saturation: f32,
+    // 0.0 separates luma with a full subcarrier cycle of blur, 1.0 keeps more detail but lets the chroma bleed into the luma
+    pub sharpness: f32,
+    // 0.0 decodes every pixel's colour on its own, 1.0 decodes the colour from the composite signal including its fringes
+    pub artifacts: f32,
+    // Keeps the subcarrier phase fixed between frames instead of letting the artifacts crawl
+    pub merge_fields: bool,
+}
+
+impl Default for NtscSettings {
+    fn default() -> Self {
+        Self {
+            hue: 0.0,
+            saturation: 1.0,
+            sharpness: 0.0,
+            artifacts: 1.0,
+            merge_fields: false,
+        }
+    }
+}
+
+// Simulates encoding the ppu's output as an ntsc composite signal and decoding it again like a tv would.
+// See https://www.nesdev.org/wiki/NTSC_video
+pub struct NtscFilter {
+    pub settings: NtscSettings,
+    frame_phase: usize,
+    // Signal level of each 9 bit pixel at each subcarrier phase
+    levels: Vec<[f32; SUBCARRIER_PHASES]>,
+    // Y, I and Q of each 9 bit pixel decoded without any interference from its neighbours
+    clean_yiq: Vec<(f32, f32, f32)>,
+    signal: Vec<f32>,
+    // Prefix sums of the signal, and of the signal multiplied by the decoding carriers
+    signal_sums: Vec<f32>,
+    i_sums: Vec<f32>,
+    q_sums: Vec<f32>,
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        Self::new(NtscSettings::default())
+    }
+}
+
+impl NtscFilter {
+    pub fn new(settings: NtscSettings) -> Self {
+        let levels: Vec<[f32; SUBCARRIER_PHASES]> = (0..PALETTE_ENTRIES as u16)
+            .map(|pixel| {
+                let mut levels = [0.0; SUBCARRIER_PHASES];
+                for (phase, level) in levels.iter_mut().enumerate() {
+                    *level = composite_level(pixel, phase as u16);
+                }
+                levels
+            })
+            .collect();
+        let clean_yiq = levels
+            .iter()
+            .map(|levels| {
+                let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+                for (phase, level) in levels.iter().enumerate() {
+                    let angle = carrier_angle(phase, 0.0);
+                    y += level / SUBCARRIER_PHASES as f32;
+                    i += level * cosf(angle) * 2.0 / SUBCARRIER_PHASES as f32;
+                    q += level * sinf(angle) * 2.0 / SUBCARRIER_PHASES as f32;
+                }
+                (y, i, q)
+            })
+            .collect();
+
+        Self {
+            settings,
+            frame_phase: 0,
+            levels,
+            clean_yiq,
+            signal: vec![0.0; LINE_SAMPLES],
+            signal_sums: vec![0.0; LINE_SAMPLES + 1],
+            i_sums: vec![0.0; LINE_SAMPLES + 1],
+            q_sums: vec![0.0; LINE_SAMPLES + 1],
+        }
+    }
+
+    fn encode_line(&mut self, frame: &Frame, y: usize, line_phase: usize) {
+        self.signal.fill(0.0);
+        for x in 0..Frame::WIDTH {
+            let levels = &self.levels[frame.pixel(x, y) as usize & 0x1FF];
+            for sample in 0..SAMPLES_PER_PIXEL {
+                let phase = (line_phase + x * SAMPLES_PER_PIXEL + sample) % SUBCARRIER_PHASES;
+                self.signal[PADDING + x * SAMPLES_PER_PIXEL + sample] = levels[phase];
+            }
+        }
+
+        let hue = self.settings.hue / 30.0;
+        for (index, signal) in self.signal.iter().enumerate() {
+            // The padding starts PADDING samples before the first pixel, which is a whole number of subcarrier cycles
+            let angle = carrier_angle((line_phase + index) % SUBCARRIER_PHASES, hue);
+            self.signal_sums[index + 1] = self.signal_sums[index] + signal;
+            self.i_sums[index + 1] = self.i_sums[index] + signal * cosf(angle);
+            self.q_sums[index + 1] = self.q_sums[index] + signal * sinf(angle);
+        }
+    }
+
+    // The average of a prefix summed signal over the samples centered on center
+    fn window_average(sums: &[f32], center: usize, width: usize) -> f32 {
+        let start = center - width / 2;
+        (sums[start + width] - sums[start]) / width as f32
+    }
+
+    fn decode_sample(&self, center: usize, pixel: u16) -> (f32, f32, f32) {
+        let settings = &self.settings;
+
+        // A window of one subcarrier cycle cancels the chroma out completely, a narrower one keeps sharper edges
+        let blurred_luma = Self::window_average(&self.signal_sums, center, SUBCARRIER_PHASES);
+        let sharp_luma = Self::window_average(&self.signal_sums, center, SUBCARRIER_PHASES / 3);
+        let luma = blurred_luma + settings.sharpness * (sharp_luma - blurred_luma);
+
+        // Chroma has far less bandwidth than luma, so it's decoded over two subcarrier cycles
+        let composite_i = Self::window_average(&self.i_sums, center, 2 * SUBCARRIER_PHASES) * 2.0;
+        let composite_q = Self::window_average(&self.q_sums, center, 2 * SUBCARRIER_PHASES) * 2.0;
+        let (_, clean_i, clean_q) = self.clean_yiq[pixel as usize & 0x1FF];
+        let hue = settings.hue.to_radians();
+        let (clean_i, clean_q) = (clean_i * cosf(hue) - clean_q * sinf(hue), clean_i * sinf(hue) + clean_q * cosf(hue));
+        let i = clean_i + settings.artifacts * (composite_i - clean_i);
+        let q = clean_q + settings.artifacts * (composite_q - clean_q);
+
+        (luma, i * settings.saturation, q * settings.saturation)
+    }
+}
+
+// The angle of the decoder's reference carrier at a subcarrier phase, hue_offset is in subcarrier phases
+fn carrier_angle(phase: usize, hue_offset: f32) -> f32 {
+    PI * (phase as f32 + COMPOSITE_HUE_OFFSET + hue_offset) / 6.0
+}
+
+impl VideoFilter for NtscFilter {
+    fn output_size(&self) -> (usize, usize) {
+        (NTSC_OUTPUT_WIDTH, Frame::HEIGHT)
+    }
+
+    fn apply(&mut self, frame: &Frame, output: &mut [u8]) {
+        for y in 0..Frame::HEIGHT {
+            let line_phase = (self.frame_phase + y * LINE_PHASE_STEP) % SUBCARRIER_PHASES;
+            self.encode_line(frame, y, line_phase);
+
+            let row = &mut output[y * NTSC_OUTPUT_WIDTH * 4..(y + 1) * NTSC_OUTPUT_WIDTH * 4];
+            for (x, rgba) in row.chunks_exact_mut(4).enumerate() {
+                let center = PADDING + x * OUTPUT_SAMPLE_STEP + OUTPUT_SAMPLE_STEP / 2;
+                let pixel = frame.pixel(x * Frame::WIDTH / NTSC_OUTPUT_WIDTH, y);
+                let (luma, i, q) = self.decode_sample(center, pixel);
+                let (r, g, b) = yiq_to_rgb(luma, i, q);
+                rgba.copy_from_slice(&[r, g, b, 255]);
+            }
+        }
+
+        if !self.settings.merge_fields {
+            self.frame_phase = (self.frame_phase + FRAME_PHASE_STEP) % SUBCARRIER_PHASES;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::Palette;
+
+    fn color_distance(a: &[u8], b: (u8, u8, u8)) -> i32 {
+        (a[0] as i32 - b.0 as i32).abs() + (a[1] as i32 - b.1 as i32).abs() + (a[2] as i32 - b.2 as i32).abs()
+    }
+
+    #[test]
+    fn test_flat_colour() {
+        let mut frame = Frame::default();
+        frame.pixels.fill(0x16);
+        let mut filter = NtscFilter::default();
+        let output = filter.apply_to_vec(&frame);
+        assert_eq!(output.len(), NTSC_OUTPUT_WIDTH * Frame::HEIGHT * 4);
+
+        // Away from the edges a flat colour decodes to the same colour as the composite palette
+        let expected = Palette::composite(COMPOSITE_HUE_OFFSET).color(0x16, 0);
+        let center = (120 * NTSC_OUTPUT_WIDTH + 256) * 4;
+        assert!(color_distance(&output[center..center + 4], expected) <= 3);
+    }
+
+    #[test]
+    fn test_dot_crawl() {
+        // Alternating columns of white and black produce artifact colours that change every frame
+        let mut frame = Frame::default();
+        for y in 0..Frame::HEIGHT {
+            for x in 0..Frame::WIDTH {
+                frame.set_pixel(x, y, if x % 2 == 0 { 0x30 } else { 0x0F });
+            }
+        }
+        let mut filter = NtscFilter::default();
+        let first = filter.apply_to_vec(&frame);
+        let second = filter.apply_to_vec(&frame);
+        assert_ne!(first, second);
+
+        filter.settings.artifacts = 0.0;
+        filter.settings.merge_fields = true;
+        let first = filter.apply_to_vec(&frame);
+        let second = filter.apply_to_vec(&frame);
+        assert_eq!(first, second);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::cpu::AddressingMode;
 use crate::instructions::InstructionSet;
-use once_cell::sync::Lazy;
+use crate::lazy::Lazy;
 
 pub static CPU_OPCODES: Lazy<[Option<Instruction>; MAX_OPCODES]> = Lazy::new(initialize_opcodes);
 const MAX_OPCODES: usize = 0xFF;
@@ -33,7 +33,7 @@ impl Instruction {
 }
 
 fn initialize_opcodes() -> [Option<Instruction>; MAX_OPCODES] {
-    let mut opcodes: [Option<Instruction>; MAX_OPCODES] = std::array::from_fn(|_| None);
+    let mut opcodes: [Option<Instruction>; MAX_OPCODES] = core::array::from_fn(|_| None);
 
     let mut add_opcode = |opcode: u8,
                           name: &'static str,
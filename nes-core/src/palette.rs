@@ -0,0 +1,268 @@
+use alloc::format;
+use alloc::string::String;
+
+use libm::{cosf, powf, roundf, sinf};
+
+use crate::lazy::Lazy;
+use crate::region::Region;
+
+pub type Rgb = (u8, u8, u8);
+
+// 64 colours for each of the 8 combinations of the colour emphasis bits in PPUMASK
+pub const PALETTE_ENTRIES: usize = 64 * 8;
+const PAL_FILE_SIZE: usize = 64 * 3;
+const PAL_FILE_WITH_EMPHASIS_SIZE: usize = PALETTE_ENTRIES * 3;
+
+// How much an emphasis bit darkens the two colour channels it doesn't emphasize
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+#[rustfmt::skip]
+static NTSC_2C02_COLORS: [Rgb; 64] = [
+   (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
+   (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
+   (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
+   (0x05, 0x05, 0x05), (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
+   (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00), (0xC4, 0x62, 0x00),
+   (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55), (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21),
+   (0x09, 0x09, 0x09), (0x09, 0x09, 0x09), (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF),
+   (0xD4, 0x80, 0xFF), (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
+   (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4), (0x05, 0xFB, 0xFF),
+   (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D), (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF),
+   (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0),
+   (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
+   (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
+];
+
+// Each digit is the 3 bit intensity of the red, green and blue output
+#[rustfmt::skip]
+static RGB_2C03_LEVELS: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420, 0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630, 0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750, 0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772, 0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+static RGB_2C03: Lazy<Palette> = Lazy::new(|| {
+    let mut colors = [(0, 0, 0); PALETTE_ENTRIES];
+    for (index, color) in colors.iter_mut().enumerate() {
+        let levels = RGB_2C03_LEVELS[index % 64];
+        let emphasis = index / 64;
+        // The RGB ppu drives an emphasized channel at full intensity instead of darkening the others
+        let channel = |shift: u16, emphasis_bit: usize| {
+            if emphasis & emphasis_bit != 0 {
+                255
+            } else {
+                (((levels >> shift) & 0o7) * 255 / 7) as u8
+            }
+        };
+        *color = (channel(6, 0b001), channel(3, 0b010), channel(0, 0b100));
+    }
+    Palette { colors }
+});
+static NTSC_2C02: Lazy<Palette> = Lazy::new(|| Palette::from_colors(&NTSC_2C02_COLORS));
+// The 2C07's chroma is rotated 15 degrees relative to the 2C02
+static PAL_2C07: Lazy<Palette> = Lazy::new(|| Palette::composite(COMPOSITE_HUE_OFFSET - 0.5));
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BuiltinPalette {
+    Ntsc2C02,
+    Rgb2C03,
+    Pal2C07,
+}
+
+impl BuiltinPalette {
+    pub const ALL: [BuiltinPalette; 3] = [BuiltinPalette::Ntsc2C02, BuiltinPalette::Rgb2C03, BuiltinPalette::Pal2C07];
+
+    // The palette of the PPU a console of the region shipped with
+    pub fn for_region(region: Region) -> BuiltinPalette {
+        match region {
+            Region::Ntsc => BuiltinPalette::Ntsc2C02,
+            Region::Pal | Region::Dendy => BuiltinPalette::Pal2C07,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuiltinPalette::Ntsc2C02 => "2C02 (NTSC)",
+            BuiltinPalette::Rgb2C03 => "2C03 (RGB)",
+            BuiltinPalette::Pal2C07 => "2C07 (PAL)",
+        }
+    }
+
+    pub fn palette(&self) -> Palette {
+        match self {
+            BuiltinPalette::Ntsc2C02 => NTSC_2C02.clone(),
+            BuiltinPalette::Rgb2C03 => RGB_2C03.clone(),
+            BuiltinPalette::Pal2C07 => PAL_2C07.clone(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Palette {
+    // Indexed by emphasis bits << 6 | colour index
+    pub colors: [Rgb; PALETTE_ENTRIES],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        BuiltinPalette::Ntsc2C02.palette()
+    }
+}
+
+impl Palette {
+    // Emphasis is PPUMASK bits 5-7 shifted down, so bit 0 is red, bit 1 green and bit 2 blue
+    pub fn color(&self, color_index: u8, emphasis: u8) -> Rgb {
+        self.colors[((emphasis as usize & 0b111) << 6) | (color_index as usize & 0x3F)]
+    }
+
+    // Loads a .pal file, either 64 colours or 512 colours which include every emphasis combination
+    pub fn from_pal_bytes(bytes: &[u8]) -> Result<Palette, String> {
+        match bytes.len() {
+            PAL_FILE_SIZE => {
+                let mut colors = [(0, 0, 0); 64];
+                for (color, rgb) in colors.iter_mut().zip(bytes.chunks_exact(3)) {
+                    *color = (rgb[0], rgb[1], rgb[2]);
+                }
+                Ok(Palette::from_colors(&colors))
+            }
+            PAL_FILE_WITH_EMPHASIS_SIZE => {
+                let mut colors = [(0, 0, 0); PALETTE_ENTRIES];
+                for (color, rgb) in colors.iter_mut().zip(bytes.chunks_exact(3)) {
+                    *color = (rgb[0], rgb[1], rgb[2]);
+                }
+                Ok(Palette { colors })
+            }
+            size => Err(format!(
+                "Palette file is {} bytes, expected {} or {} bytes",
+                size, PAL_FILE_SIZE, PAL_FILE_WITH_EMPHASIS_SIZE
+            )),
+        }
+    }
+
+    // Builds the emphasis tables from 64 base colours by darkening the channels that aren't emphasized
+    pub fn from_colors(base_colors: &[Rgb; 64]) -> Palette {
+        let mut colors = [(0, 0, 0); PALETTE_ENTRIES];
+        for (index, color) in colors.iter_mut().enumerate() {
+            let (r, g, b) = base_colors[index % 64];
+            let emphasis = index / 64;
+            let mut channels = [r as f32, g as f32, b as f32];
+            for (channel_index, channel) in channels.iter_mut().enumerate() {
+                // Each set emphasis bit darkens the other two channels
+                let darkening_bits = emphasis & !(1 << channel_index);
+                *channel *= powf(EMPHASIS_ATTENUATION, darkening_bits.count_ones() as f32);
+            }
+            *color = (roundf(channels[0]) as u8, roundf(channels[1]) as u8, roundf(channels[2]) as u8);
+        }
+        Palette { colors }
+    }
+
+    // Generates a palette by decoding the composite signal the ppu outputs for each colour.
+    // See https://www.nesdev.org/wiki/NTSC_video
+    pub fn composite(hue_offset: f32) -> Palette {
+        let mut colors = [(0, 0, 0); PALETTE_ENTRIES];
+        for (index, color) in colors.iter_mut().enumerate() {
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let level = composite_level(index as u16, phase) / 12.0;
+                let angle = core::f32::consts::PI * (phase as f32 + hue_offset) / 6.0;
+                y += level;
+                i += level * cosf(angle) * 2.0;
+                q += level * sinf(angle) * 2.0;
+            }
+            *color = yiq_to_rgb(y, i, q);
+        }
+        Palette { colors }
+    }
+}
+
+// Phase of the colour subcarrier that puts the 2C02's hues where the ntsc decoder expects them
+pub const COMPOSITE_HUE_OFFSET: f32 = 3.9;
+
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = SIGNAL_LOW[1];
+const SIGNAL_WHITE: f32 = SIGNAL_HIGH[3];
+const SIGNAL_EMPHASIS_ATTENUATION: f32 = 0.746;
+
+fn in_color_phase(color: u16, phase: u16) -> bool {
+    (color + phase) % 12 < 6
+}
+
+// The ppu's output for a 9 bit pixel (emphasis << 6 | colour) at one of the 12 subcarrier phases,
+// normalized so that black is 0.0 and white is 1.0
+pub fn composite_level(pixel: u16, phase: u16) -> f32 {
+    let color = pixel & 0x0F;
+    let emphasis = pixel >> 6;
+    // Colours $xE and $xF output black
+    let luma = if color > 13 { 1 } else { ((pixel >> 4) & 0b11) as usize };
+
+    let low = SIGNAL_LOW[luma];
+    let high = SIGNAL_HIGH[luma];
+    let (low, high) = match color {
+        0 => (high, high),
+        13.. => (low, low),
+        _ => (low, high),
+    };
+    let mut signal = if in_color_phase(color, phase) { high } else { low };
+
+    let emphasized = (emphasis & 0b001 != 0 && in_color_phase(0, phase))
+        || (emphasis & 0b010 != 0 && in_color_phase(4, phase))
+        || (emphasis & 0b100 != 0 && in_color_phase(8, phase));
+    if emphasized && color < 0x0E {
+        signal *= SIGNAL_EMPHASIS_ATTENUATION;
+    }
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32) -> Rgb {
+    let to_byte = |value: f32| roundf(value * 255.0).clamp(0.0, 255.0) as u8;
+    (
+        to_byte(y + 0.946882 * i + 0.623557 * q),
+        to_byte(y - 0.274788 * i - 0.635691 * q),
+        to_byte(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_load_pal_file() {
+        let bytes: Vec<u8> = (0..PAL_FILE_SIZE).map(|i| i as u8).collect();
+        let palette = Palette::from_pal_bytes(&bytes).unwrap();
+        assert_eq!(palette.color(0x01, 0), (3, 4, 5));
+        assert_eq!(palette.color(0x3F, 0), (189, 190, 191));
+        // Emphasizing red darkens green and blue
+        let (r, g, b) = palette.color(0x3F, 0b001);
+        assert_eq!(r, 189);
+        assert!(g < 190 && b < 191);
+
+        let bytes: Vec<u8> = (0..PAL_FILE_WITH_EMPHASIS_SIZE).map(|i| (i / 3) as u8).collect();
+        let palette = Palette::from_pal_bytes(&bytes).unwrap();
+        assert_eq!(palette.color(0x05, 0b010), (0x85, 0x85, 0x85));
+
+        assert!(Palette::from_pal_bytes(&[0; 100]).is_err());
+    }
+
+    #[test]
+    fn test_composite_matches_2c02() {
+        let ntsc = BuiltinPalette::Ntsc2C02.palette();
+        let composite = Palette::composite(COMPOSITE_HUE_OFFSET);
+        for color_index in [0x16, 0x1A, 0x12, 0x30, 0x0F] {
+            let (r1, g1, b1) = ntsc.color(color_index, 0);
+            let (r2, g2, b2) = composite.color(color_index, 0);
+            let distance = (r1 as i32 - r2 as i32).abs() + (g1 as i32 - g2 as i32).abs() + (b1 as i32 - b2 as i32).abs();
+            assert!(distance < 120, "colour {:X} differs by {}", color_index, distance);
+        }
+    }
+
+    #[test]
+    fn test_rgb_emphasis() {
+        let rgb = BuiltinPalette::Rgb2C03.palette();
+        assert_eq!(rgb.color(0x0D, 0), (0, 0, 0));
+        assert_eq!(rgb.color(0x0D, 0b101), (255, 0, 255));
+    }
+}
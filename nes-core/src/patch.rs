@@ -0,0 +1,434 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::hash::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+// UPS and BPS patches end with the crc32s of the source, the target and the patch itself
+const CHECKSUM_FOOTER_SIZE: usize = 12;
+// How many times bigger than the source and the patch together a UPS or BPS target can say it is
+const MAX_TARGET_SIZE_FACTOR: usize = 4;
+
+// File extensions of patches, in the order they are looked for next to a rom
+pub const PATCH_EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PatchError {
+    UnknownFormat,
+    // The patch ends in the middle of a record
+    Truncated,
+    // A record writes or reads outside of the file
+    OutOfBounds,
+    // The target size in the header is far more than the source and the patch could make
+    TargetTooLarge { size: usize },
+    // The patch is for a different rom, or a different version of it
+    SourceChecksum { expected: u32, actual: u32 },
+    TargetChecksum { expected: u32, actual: u32 },
+    PatchChecksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "File is not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "Patch ends unexpectedly"),
+            PatchError::OutOfBounds => write!(f, "Patch refers to data outside of the rom"),
+            PatchError::TargetTooLarge { size } => write!(f, "Patch claims the patched rom is {} bytes, which is too large", size),
+            PatchError::SourceChecksum { expected, actual } => {
+                write!(f, "Patch is for a rom with crc32 {:08X} but this rom's is {:08X}", expected, actual)
+            }
+            PatchError::TargetChecksum { expected, actual } => {
+                write!(f, "Patched rom has crc32 {:08X} instead of {:08X}", actual, expected)
+            }
+            PatchError::PatchChecksum { expected, actual } => {
+                write!(f, "Patch has crc32 {:08X} instead of {:08X}, it's probably corrupt", actual, expected)
+            }
+        }
+    }
+}
+
+impl core::error::Error for PatchError {}
+
+impl PatchFormat {
+    pub fn detect(patch: &[u8]) -> Option<Self> {
+        if patch.starts_with(IPS_MAGIC) {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(UPS_MAGIC) {
+            Some(PatchFormat::Ups)
+        } else if patch.starts_with(BPS_MAGIC) {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+// Patches the whole rom file, header included, like other emulators and patching tools do
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match PatchFormat::detect(patch).ok_or(PatchError::UnknownFormat)? {
+        PatchFormat::Ips => apply_ips(rom, patch),
+        PatchFormat::Ups => apply_ups(rom, patch),
+        PatchFormat::Bps => apply_bps(rom, patch),
+    }
+}
+
+struct PatchReader<'a> {
+    patch: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.position.checked_add(count).ok_or(PatchError::Truncated)?;
+        let bytes = self.patch.get(self.position..end).ok_or(PatchError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, count: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(count)?.iter().fold(0, |value, byte| value << 8 | *byte as usize))
+    }
+
+    // The variable length numbers used by UPS and BPS, 7 bits per byte where the top bit marks the last byte.
+    // Each continuation adds one to the next group so every number has a single encoding
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let (mut value, mut shift) = (0usize, 1usize);
+        loop {
+            let byte = self.byte()?;
+            let group = ((byte & 0x7F) as usize).checked_mul(shift).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(group).ok_or(PatchError::OutOfBounds)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+}
+
+// See https://zerosoft.zophar.net/ips.php
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = PatchReader {
+        patch,
+        position: IPS_MAGIC.len(),
+    };
+    let mut output = rom.to_vec();
+
+    loop {
+        let offset_bytes = reader.bytes(3)?;
+        if offset_bytes == IPS_EOF {
+            break;
+        }
+        let offset = offset_bytes.iter().fold(0, |value, byte| value << 8 | *byte as usize);
+        let size = reader.big_endian(2)?;
+        // A size of 0 is a run of a single repeated byte
+        let (size, run_byte) = if size == 0 {
+            (reader.big_endian(2)?, Some(reader.byte()?))
+        } else {
+            (size, None)
+        };
+        if output.len() < offset + size {
+            output.resize(offset + size, 0);
+        }
+        match run_byte {
+            Some(byte) => output[offset..offset + size].fill(byte),
+            None => output[offset..offset + size].copy_from_slice(reader.bytes(size)?),
+        }
+    }
+
+    // An extension some tools write after EOF, the size to truncate the file to
+    if let Ok(size) = reader.big_endian(3) {
+        output.truncate(size);
+    }
+    Ok(output)
+}
+
+// Checks the crc32s in a UPS or BPS footer, returns the expected crc32 of the target
+fn check_footer(source: &[u8], patch: &[u8]) -> Result<u32, PatchError> {
+    if patch.len() < CHECKSUM_FOOTER_SIZE + 4 {
+        return Err(PatchError::Truncated);
+    }
+    let footer = &patch[patch.len() - CHECKSUM_FOOTER_SIZE..];
+    let read_crc = |offset: usize| u32::from_le_bytes([footer[offset], footer[offset + 1], footer[offset + 2], footer[offset + 3]]);
+
+    let (expected, actual) = (read_crc(8), crc32(&patch[..patch.len() - 4]));
+    if expected != actual {
+        return Err(PatchError::PatchChecksum { expected, actual });
+    }
+    let (expected, actual) = (read_crc(0), crc32(source));
+    if expected != actual {
+        return Err(PatchError::SourceChecksum { expected, actual });
+    }
+    Ok(read_crc(4))
+}
+
+fn check_target(target: Vec<u8>, expected: u32) -> Result<Vec<u8>, PatchError> {
+    let actual = crc32(&target);
+    if expected != actual {
+        return Err(PatchError::TargetChecksum { expected, actual });
+    }
+    Ok(target)
+}
+
+// See https://www.romhacking.net/documents/392/
+// The target size is read before anything is checked against the target crc, so it's limited before allocating for it
+fn check_target_size(source: &[u8], patch: &[u8], size: usize) -> Result<usize, PatchError> {
+    if size > (source.len() + patch.len()).saturating_mul(MAX_TARGET_SIZE_FACTOR) {
+        return Err(PatchError::TargetTooLarge { size });
+    }
+    Ok(size)
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_footer(rom, patch)?;
+    let mut reader = PatchReader {
+        patch: &patch[..patch.len() - CHECKSUM_FOOTER_SIZE],
+        position: UPS_MAGIC.len(),
+    };
+    let _source_size = reader.number()?;
+    let target_size = check_target_size(rom, patch, reader.number()?)?;
+
+    let mut output = rom.to_vec();
+    output.resize(target_size, 0);
+    let mut position = 0usize;
+    while reader.position < reader.patch.len() {
+        position = position.checked_add(reader.number()?).ok_or(PatchError::OutOfBounds)?;
+        // Bytes are xored with the source until a 0, which also counts as one unchanged byte
+        loop {
+            let byte = reader.byte()?;
+            if byte == 0 {
+                position = position.checked_add(1).ok_or(PatchError::OutOfBounds)?;
+                break;
+            }
+            *output.get_mut(position).ok_or(PatchError::OutOfBounds)? ^= byte;
+            position += 1;
+        }
+    }
+    check_target(output, target_crc)
+}
+
+// See https://www.romhacking.net/documents/746/
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_footer(rom, patch)?;
+    let mut reader = PatchReader {
+        patch: &patch[..patch.len() - CHECKSUM_FOOTER_SIZE],
+        position: BPS_MAGIC.len(),
+    };
+    let _source_size = reader.number()?;
+    let target_size = check_target_size(rom, patch, reader.number()?)?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+
+    let mut output = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0usize, 0usize);
+    // Copy offsets are stored relative to the previous one, with the sign in the lowest bit
+    let relative_offset = |reader: &mut PatchReader, offset: usize| -> Result<usize, PatchError> {
+        let value = reader.number()?;
+        let distance = value >> 1;
+        let offset = if value & 1 != 0 {
+            offset.checked_sub(distance)
+        } else {
+            offset.checked_add(distance)
+        };
+        offset.ok_or(PatchError::OutOfBounds)
+    };
+
+    while reader.position < reader.patch.len() {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        // Checked up front, as a target copy can keep feeding itself for as long as the length says
+        if output.len().checked_add(length).is_none_or(|end| end > target_size) {
+            return Err(PatchError::OutOfBounds);
+        }
+        match action & 0b11 {
+            // Source read, copies from the same position in the source
+            0 => {
+                let start = output.len();
+                output.extend_from_slice(rom.get(start..start + length).ok_or(PatchError::OutOfBounds)?);
+            }
+            // Target read, copies from the patch
+            1 => output.extend_from_slice(reader.bytes(length)?),
+            // Source copy
+            2 => {
+                source_offset = relative_offset(&mut reader, source_offset)?;
+                let source_end = source_offset.checked_add(length).ok_or(PatchError::OutOfBounds)?;
+                output.extend_from_slice(rom.get(source_offset..source_end).ok_or(PatchError::OutOfBounds)?);
+                source_offset = source_end;
+            }
+            // Target copy, which can overlap the bytes it writes
+            _ => {
+                target_offset = relative_offset(&mut reader, target_offset)?;
+                for _ in 0..length {
+                    let byte = *output.get(target_offset).ok_or(PatchError::OutOfBounds)?;
+                    output.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(PatchError::OutOfBounds);
+    }
+    check_target(output, target_crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_number(mut value: usize, output: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                output.push(byte | 0x80);
+                return;
+            }
+            output.push(byte);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(crc32(source).to_le_bytes());
+        patch.extend(crc32(target).to_le_bytes());
+        patch.extend(crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_number() {
+        for value in [0, 1, 127, 128, 300, 16511, 16512, 1 << 24] {
+            let mut encoded = Vec::new();
+            encode_number(value, &mut encoded);
+            let mut reader = PatchReader {
+                patch: &encoded,
+                position: 0,
+            };
+            assert_eq!(reader.number().unwrap(), value);
+            assert_eq!(reader.position, encoded.len());
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        // Write "AB" at 1, then a run of four 'Z's at 6, past the end of the rom
+        patch.extend([0, 0, 1, 0, 2, b'A', b'B']);
+        patch.extend([0, 0, 6, 0, 0, 0, 4, b'Z']);
+        patch.extend(b"EOF");
+        assert_eq!(apply_patch(b"abcdef", &patch).unwrap(), b"aABdefZZZZ");
+
+        // Truncated back to 3 bytes
+        patch.extend([0, 0, 3]);
+        assert_eq!(apply_patch(b"abcdef", &patch).unwrap(), b"aAB");
+    }
+
+    #[test]
+    fn test_ups() {
+        let (source, target) = (b"abcdef".as_slice(), b"abXdefgh".as_slice());
+        let mut patch = b"UPS1".to_vec();
+        encode_number(source.len(), &mut patch);
+        encode_number(target.len(), &mut patch);
+        // Skip "ab" and xor 'c' into 'X', the terminator leaves 'd' as it is, then skip "ef" and xor in "gh"
+        encode_number(2, &mut patch);
+        patch.extend([b'c' ^ b'X', 0]);
+        encode_number(2, &mut patch);
+        patch.extend([b'g', b'h', 0]);
+        let patch = with_footer(patch, source, target);
+        assert_eq!(apply_patch(source, &patch).unwrap(), target);
+
+        assert!(matches!(apply_patch(b"abcdeg", &patch), Err(PatchError::SourceChecksum { .. })));
+    }
+
+    #[test]
+    fn test_bps() {
+        let (source, target) = (b"abcdef".as_slice(), b"abQQQQdefab".as_slice());
+        let mut patch = b"BPS1".to_vec();
+        encode_number(source.len(), &mut patch);
+        encode_number(target.len(), &mut patch);
+        encode_number(0, &mut patch);
+        // Source read "ab"
+        encode_number((2 - 1) << 2, &mut patch);
+        // Target read "Q", then target copy it 3 more times from offset 2
+        encode_number(0b01, &mut patch);
+        patch.push(b'Q');
+        encode_number((3 - 1) << 2 | 0b11, &mut patch);
+        encode_number(2 << 1, &mut patch);
+        // Source copy "def" from offset 3, then "ab" from offset 0, 6 back
+        encode_number((3 - 1) << 2 | 0b10, &mut patch);
+        encode_number(3 << 1, &mut patch);
+        encode_number((2 - 1) << 2 | 0b10, &mut patch);
+        encode_number(6 << 1 | 1, &mut patch);
+        let mut patch = with_footer(patch, source, target);
+        assert_eq!(apply_patch(source, &patch).unwrap(), target);
+
+        patch[6] ^= 1;
+        assert!(matches!(apply_patch(source, &patch), Err(PatchError::PatchChecksum { .. })));
+    }
+
+    #[test]
+    fn test_huge_target_size() {
+        // Valid checksums but a target size of an exabyte, which shouldn't be allocated
+        let source = b"abcdef".as_slice();
+        for magic in [b"UPS1", b"BPS1"] {
+            let mut patch = magic.to_vec();
+            encode_number(source.len(), &mut patch);
+            encode_number(1 << 60, &mut patch);
+            encode_number(0, &mut patch);
+            let patch = with_footer(patch, source, source);
+            assert_eq!(apply_patch(source, &patch), Err(PatchError::TargetTooLarge { size: 1 << 60 }));
+        }
+
+        // A number that carries past usize::MAX
+        let mut encoded = [0x7F; 10];
+        encoded[9] = 0x80;
+        let mut reader = PatchReader {
+            patch: &encoded,
+            position: 0,
+        };
+        assert_eq!(reader.number(), Err(PatchError::OutOfBounds));
+    }
+
+    #[test]
+    fn test_overflowing_records() {
+        let source = b"abcdef".as_slice();
+        // A BPS target read of "Q" and then a target copy of it 2^50 times, in a target that's 8 bytes long
+        let mut patch = b"BPS1".to_vec();
+        encode_number(source.len(), &mut patch);
+        encode_number(8, &mut patch);
+        encode_number(0, &mut patch);
+        encode_number(0b01, &mut patch);
+        patch.push(b'Q');
+        encode_number(((1 << 50) - 1) << 2 | 0b11, &mut patch);
+        encode_number(0, &mut patch);
+        let patch = with_footer(patch, source, b"QQQQQQQQ");
+        assert_eq!(apply_patch(source, &patch), Err(PatchError::OutOfBounds));
+
+        // A UPS skip that carries the position past usize::MAX
+        let mut patch = b"UPS1".to_vec();
+        encode_number(source.len(), &mut patch);
+        encode_number(source.len(), &mut patch);
+        encode_number(1, &mut patch);
+        patch.extend([1, 0]);
+        encode_number(usize::MAX - 1, &mut patch);
+        patch.push(0);
+        let patch = with_footer(patch, source, source);
+        assert_eq!(apply_patch(source, &patch), Err(PatchError::OutOfBounds));
+    }
+}
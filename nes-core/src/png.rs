@@ -0,0 +1,94 @@
+// Writes PNG images, used to save frames from headless runs.
+// See https://www.w3.org/TR/png/
+use alloc::vec::Vec;
+
+use crate::hash::crc32_update;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_RGBA: u8 = 6;
+// zlib with a 32 KiB window and no preset dictionary
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x01];
+// Stored deflate blocks hold at most this many bytes
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+const ADLER32_MODULUS: u32 = 65521;
+
+// The image isn't compressed, which keeps the encoder small and is fine for the odd screenshot
+pub fn encode_png(rgba: &[u8], width: usize, height: usize) -> Vec<u8> {
+    // Every row starts with its filter type, 0 is no filtering
+    let mut rows = Vec::with_capacity((width * 4 + 1) * height);
+    for row in rgba.chunks_exact(width * 4).take(height) {
+        rows.push(0);
+        rows.extend_from_slice(row);
+    }
+
+    let mut zlib = ZLIB_HEADER.to_vec();
+    let mut blocks = rows.chunks(STORED_BLOCK_SIZE).peekable();
+    while let Some(block) = blocks.next() {
+        // The first byte marks the final block and the block type, 0 for stored
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&rows).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth, colour type, compression method, filter method and interlace method
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGBA, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// Chunks are their length, type, data and the crc32 of the type and data
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc32_update(crc32_update(0, kind), data).to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1, 0);
+    for byte in data {
+        a = (a + *byte as u32) % ADLER32_MODULUS;
+        b = (b + a) % ADLER32_MODULUS;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::crc32;
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    #[test]
+    fn test_encode_png() {
+        let rgba = [255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 1, 2, 3, 4];
+        let png = encode_png(&rgba, 2, 2);
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        assert_eq!(png[12..16], *b"IHDR");
+        assert_eq!(png[16..24], [0, 0, 0, 2, 0, 0, 0, 2]);
+        assert_eq!(crc32(&png[12..29]).to_be_bytes(), png[29..33]);
+
+        let idat_length = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(png[37..41], *b"IDAT");
+        let zlib = &png[41..41 + idat_length];
+        let rows = decompress_to_vec_zlib(zlib).unwrap();
+        assert_eq!(rows, [&[0][..], &rgba[..8], &[0], &rgba[8..]].concat());
+        assert_eq!(zlib[zlib.len() - 4..], adler32(&rows).to_be_bytes());
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
@@ -1,29 +1,19 @@
-use std::ops::BitAnd;
+use alloc::vec::Vec;
 
 use bitflags::{bitflags, Flags};
 
 use crate::frame::Frame;
+use crate::region::Region;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::{mapper::Mapper, rom::Mirroring};
 
-const VRAM_SIZE: usize = 2048;
+// 2 KiB of internal vram plus the 2 KiB a four screen cartridge adds
+const VRAM_SIZE: usize = 4096;
 const PALETTE_SIZE: usize = 0x20;
-
-#[rustfmt::skip]
-pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
-   (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
-   (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
-   (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
-   (0x05, 0x05, 0x05), (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
-   (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00), (0xC4, 0x62, 0x00),
-   (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55), (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21),
-   (0x09, 0x09, 0x09), (0x09, 0x09, 0x09), (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF),
-   (0xD4, 0x80, 0xFF), (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
-   (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4), (0x05, 0xFB, 0xFF),
-   (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D), (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF),
-   (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0),
-   (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
-   (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
+// Palette entries are 6 bits wide, the upper 2 bits don't exist in palette ram
+const PALETTE_VALUE_MASK: u8 = 0b0011_1111;
+// Each bit of the I/O latch fades back to 0 roughly 600ms after it was last driven high
+const IO_LATCH_DECAY_FRAMES: u8 = 36;
 
 // 0. Load Donkey Kong :)
 //
@@ -54,8 +44,6 @@ enum SpriteEvaluationState {
     OverflowLogic,
 }
 
-type Rgb = (u8, u8, u8);
-
 pub struct Ppu {
     pub frame: Frame,
 
@@ -63,12 +51,21 @@ pub struct Ppu {
     pub vram: [u8; VRAM_SIZE],
     pub palette_ram: [u8; PALETTE_SIZE],
     pub screen_mirroring: Mirroring,
+    pub region: Region,
 
     pub control_register: ControlFlags,
+    pub mask_register: MaskFlags,
     pub status_register: StatusFlags,
 
+    // The data bus between the cpu and the ppu registers, reads from write only registers return it
+    pub io_latch: u8,
+    pub io_latch_decay: [u8; 8], // Frames left until each bit of the latch decays
+    pub read_buffer: u8,         // Internal buffer returned by PPUDATA reads below the palette
+
     pub cycle: usize,
     pub scanline: usize,
+    // Frames finished since power on
+    pub frame_count: u64,
 
     // 0 to 3 is background palette, 4 to 7 is sprite palette
     pub oam: [u8; 256],
@@ -105,14 +102,17 @@ pub struct Ppu {
 
 #[allow(clippy::unusual_byte_groupings)]
 impl Ppu {
-    pub fn new(chr_rom: Vec<u8>, screen_mirroring: Mirroring) -> Self {
+    pub fn new(chr_rom: Vec<u8>, screen_mirroring: Mirroring, region: Region) -> Self {
         Ppu {
             vram: [0; VRAM_SIZE],
             control_register: ControlFlags::empty(),
+            mask_register: MaskFlags::empty(),
             chr_rom,
             screen_mirroring,
+            region,
             cycle: 0,
-            scanline: 261,
+            scanline: region.pre_render_scanline(),
+            frame_count: 0,
             nametable_byte: 0,
             attribute_byte: 0,
             pattern_table_low_byte: 0,
@@ -121,6 +121,9 @@ impl Ppu {
             oam_data: 0,
             sec_oam: [0; 32],
             status_register: StatusFlags::empty(),
+            io_latch: 0,
+            io_latch_decay: [0; 8],
+            read_buffer: 0,
             oam: [0; 256],
             oam_address: 0,
             sec_oam_address: 0,
@@ -148,22 +151,78 @@ impl Ppu {
     // NOTE: Read and writes to ppudata during rendering updates v in an odd way, but that
     // behaviour is rarely used. See https://www.nesdev.org/wiki/PPU_scrolling#$2007_(PPUDATA)_reads_and_writes for more info
     pub fn read_ppudata(&mut self) -> u8 {
-        let value = self.read(self.v);
-        self.v += if self.control_register.vram_address_increment() == 0 { 1 } else { 32 };
-        //println!("READ PPUDATA VRAM_ADDRESS: {:X}", self.v);
+        let value = self.peek_ppudata();
+        let address = self.v & 0x3FFF;
+        if address >= 0x3F00 {
+            // Palette reads bypass the buffer, which instead gets the nametable byte "underneath" the palette.
+            // Only the lower 6 bits are driven by palette ram, the upper 2 bits are open bus
+            self.read_buffer = self.read(address - 0x1000);
+            self.refresh_io_latch(value, 0b0011_1111);
+        } else {
+            self.read_buffer = self.read(address);
+            self.refresh_io_latch(value, 0xFF);
+        }
+        self.increment_vram_address();
         value
     }
 
+    // The value a PPUDATA read would return, without updating the read buffer or v
+    pub fn peek_ppudata(&self) -> u8 {
+        let address = self.v & 0x3FFF;
+        if address >= 0x3F00 {
+            (self.io_latch & 0b1100_0000) | (self.read(address) & 0b0011_1111)
+        } else {
+            self.read_buffer
+        }
+    }
+
     pub fn write_ppudata(&mut self, value: u8) {
-        self.write(self.v, value);
-        self.v += if self.control_register.vram_address_increment() == 0 { 1 } else { 32 };
-        //println!("WRITE PPUDATA VRAM_ADDRESS: {:X}", self.v);
+        self.write(self.v & 0x3FFF, value);
+        self.increment_vram_address();
+    }
+
+    fn increment_vram_address(&mut self) {
+        self.v = self.v.wrapping_add(self.control_register.vram_address_increment() as u16) & 0x7FFF;
     }
 
     pub fn read_status(&mut self) -> StatusFlags {
+        let status = self.peek_status();
         self.w = false;
         self.status_register.set(StatusFlags::VerticalBlankStarted, false);
-        self.status_register
+        self.refresh_io_latch(status.bits(), !StatusFlags::PpuOpenBus.bits());
+        status
+    }
+
+    // The lower 5 bits of PPUSTATUS are unused and return whatever is on the io latch
+    pub fn peek_status(&self) -> StatusFlags {
+        let open_bus = StatusFlags::from_bits_truncate(self.io_latch) & StatusFlags::PpuOpenBus;
+        (self.status_register - StatusFlags::PpuOpenBus) | open_bus
+    }
+
+    // Every write to a ppu register, including read only ones, fills the whole latch
+    pub fn write_io_latch(&mut self, value: u8) {
+        self.refresh_io_latch(value, 0xFF);
+    }
+
+    // Only the bits in mask are driven, the remaining bits keep their old (decaying) value
+    fn refresh_io_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_decay[bit] = IO_LATCH_DECAY_FRAMES;
+            }
+        }
+    }
+
+    fn decay_io_latch(&mut self) {
+        for bit in 0..8 {
+            if self.io_latch_decay[bit] > 0 {
+                self.io_latch_decay[bit] -= 1;
+                if self.io_latch_decay[bit] == 0 {
+                    self.io_latch &= !(1 << bit);
+                }
+            }
+        }
     }
 
     pub fn write_oam_dma(&mut self, oam_data: &[u8; 256]) {}
@@ -187,12 +246,30 @@ impl Ppu {
         let value_bits = (value as u16 & 0b11) << 10;
         self.t = (self.t & 0b111_00_11111_11111) | value_bits;
     }
+    // Switching region in the middle of a frame restarts it from the pre-render scanline
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        if self.scanline >= region.pre_render_scanline() {
+            self.scanline = region.pre_render_scanline();
+        }
+    }
+
+    pub fn write_mask(&mut self, value: u8) {
+        self.mask_register = MaskFlags::from_bits_truncate(value);
+    }
+
     pub fn write_oam_data(&mut self, value: u8) {
         self.oam[self.oam_address as usize] = value;
         self.oam_address += 1;
     }
 
     pub fn read_oam_data(&mut self) -> u8 {
+        let value = self.peek_oam_data();
+        self.refresh_io_latch(value, 0xFF);
+        value
+    }
+
+    pub fn peek_oam_data(&self) -> u8 {
         if self.cycle >= 1 && self.cycle <= 64 {
             return 0xFF;
         }
@@ -226,8 +303,7 @@ impl Ppu {
     fn nametable_byte(&mut self) -> u8 {
         // Here we & the vram address with a mask including nametable select, coarse y scroll, and coarse x scroll
         let address = 0x2000 | (self.v & 0b000_11_11111_11111);
-        let nametable_base = self.control_register.bitand(ControlFlags::BaseNametableMask).bits();
-        self.read(address | ((nametable_base as u16) << 12))
+        self.read(address)
     }
 
     fn pattern_table_address(&mut self) -> u16 {
@@ -284,22 +360,23 @@ impl Ppu {
 
     pub fn step(&mut self) {
         //println!("Scanline: {}, Cycle: {}", self.scanline, self.cycle);
+        let pre_render_scanline = self.region.pre_render_scanline();
+        let vblank_scanline = self.region.vblank_scanline();
         match self.scanline {
             0..=239 => self.render(), // Visible scanlines
-            240 => {}                 // Post-render scanline
-            241..=260 => {
-                // Vertical blanking lines
-                if self.scanline == 241 && self.cycle == 0 {
-                    self.status_register.set(StatusFlags::VerticalBlankStarted, true);
-                }
-            } // Vertical blanking lines
-            261 => {
+            scanline if scanline == pre_render_scanline => {
                 // Pre-render scanline
                 //self.frame.canvas.present();
                 if self.cycle == 0 {
                     self.status_register.set(StatusFlags::VerticalBlankStarted, false);
                 }
             }
+            scanline if scanline == vblank_scanline => {
+                if self.cycle == 0 {
+                    self.status_register.set(StatusFlags::VerticalBlankStarted, true);
+                }
+            }
+            scanline if scanline < pre_render_scanline => {} // Post-render and vertical blanking lines
             _ => unreachable!("scanline should never be {}", self.scanline),
         };
 
@@ -313,8 +390,10 @@ impl Ppu {
             }
 
             self.scanline += 1;
-            if self.scanline > 261 {
+            if self.scanline > pre_render_scanline {
                 self.scanline = 0;
+                self.frame_count += 1;
+                self.decay_io_latch();
             }
         }
     }
@@ -378,8 +457,17 @@ impl Ppu {
         let palette_index = (self.attribute_low_shift_register & 1) | ((self.attribute_low_shift_register & 1) << 1);
         let palette = self.get_palette(palette_index);
         let color_index = (self.pattern_low_shift_register & 1) | ((self.pattern_high_shift_register & 1) << 1);
-        let color = SYSTEM_PALLETE[palette[color_index as usize] as usize];
-        self.frame.set_pixel(self.cycle, self.scanline, color);
+        let mut palette_color = palette[color_index as usize];
+        if self.mask_register.contains(MaskFlags::Greyscale) {
+            palette_color &= 0x30;
+        }
+        let mut emphasis = self.mask_register.emphasis();
+        if self.region.swaps_emphasis_bits() {
+            // Swap the red and green bits so the pixel's emphasis bits always mean red, green, blue
+            emphasis = (emphasis & 0b100) | ((emphasis & 0b001) << 1) | ((emphasis & 0b010) >> 1);
+        }
+        let pixel = (emphasis as u16) << 6 | palette_color as u16;
+        self.frame.set_pixel(self.cycle - 1, self.scanline, pixel);
     }
 
     fn get_palette(&mut self, palette_index: u8) -> [u8; 4] {
@@ -514,11 +602,8 @@ impl Ppu {
         match address {
             0x0000..=0x0FFF => self.chr_rom[address as usize],
             0x1000..=0x1FFF => self.chr_rom[address as usize],
-            0x2000..=0x23FF => self.vram[address as usize - 0x2000], // Nametable 1
-            0x2400..=0x2FFF => self.vram[self.nametable_mirroring_address(address) as usize - 0x2000], // Nametable 2 through 4 can be mirrored
-            0x3000..=0x3EFF => self.vram[self.nametable_mirroring_address(address & 0x2FFF) as usize - 0x2000],
-            0x3F00..=0x3F1F => self.palette_ram[address as usize - 0x3F00],
-            0x3F20..=0x3FFF => self.palette_ram[(address as usize & 0x3F1F) - 0x3F00], // Mirror of 0x3F00 to 0x3F1F
+            0x2000..=0x3EFF => self.vram[self.nametable_vram_index(address)], // $3000-$3EFF mirror $2000-$2EFF
+            0x3F00..=0x3FFF => self.palette_ram[Self::palette_ram_index(address)],
             _ => panic!("Address {:X} is outside the ppus memory", address),
         }
     }
@@ -527,30 +612,131 @@ impl Ppu {
         match address {
             0x0000..=0x0FFF => self.chr_rom[address as usize] = value,
             0x1000..=0x1FFF => self.chr_rom[address as usize] = value,
-            0x2000..=0x23FF => self.vram[address as usize] = value, // Nametable 1
-            0x2400..=0x2FFF => self.vram[self.nametable_mirroring_address(address) as usize - 0x2000] = value, // Nametable 2 through 4 can be mirrored
-            0x3000..=0x3EFF => self.vram[self.nametable_mirroring_address(address & 0x2FFF) as usize - 0x2000] = value,
-            0x3F00..=0x3F1F => self.palette_ram[address as usize] = value,
-            0x3F20..=0x3FFF => self.palette_ram[address as usize & 0x3F1F] = value, // Mirror of 0x3F00 to 0x3F1F
+            0x2000..=0x3EFF => self.vram[self.nametable_vram_index(address)] = value, // $3000-$3EFF mirror $2000-$2EFF
+            0x3F00..=0x3FFF => self.palette_ram[Self::palette_ram_index(address)] = value & PALETTE_VALUE_MASK,
             _ => panic!("Address {:X} is outside the ppus memory", address),
         }
     }
 
-    fn nametable_mirroring_address(&self, address: u16) -> u16 {
-        //println!("{:X}", address);
-        match self.screen_mirroring {
-            Mirroring::FourScreen => address,
-            Mirroring::SingleScreen => address & 0x23FF,
-            Mirroring::Horizontal => match address {
-                0x2400..=0x27FF | 0x2C00..=0x2FFF => address - 0x400,
-                _ => address,
-            },
-            Mirroring::Vertical => match address {
-                0x2800..=0x2FFF => address - 0x800,
-                _ => address,
-            },
+    // $3F20-$3FFF mirror $3F00-$3F1F, and the first entry of each sprite palette ($3F10/$3F14/$3F18/$3F1C)
+    // is shared with the corresponding background palette entry
+    fn palette_ram_index(address: u16) -> usize {
+        let index = address as usize & (PALETTE_SIZE - 1);
+        if index & 0x13 == 0x10 {
+            index & !0x10
+        } else {
+            index
         }
     }
+
+    // Maps an address in $2000-$3EFF to an index into vram. The four 1 KiB nametables are backed by
+    // 1 KiB pages of vram chosen by the mirroring mode. See https://www.nesdev.org/wiki/Mirroring
+    pub fn nametable_vram_index(&self, address: u16) -> usize {
+        let nametable = (address as usize >> 10) & 0b11;
+        let offset = address as usize & 0x3FF;
+        let page = match self.screen_mirroring {
+            Mirroring::Horizontal => nametable >> 1,
+            Mirroring::Vertical => nametable & 1,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => nametable,
+            Mirroring::Custom(pages) => pages[nametable] as usize & 0b11,
+        };
+        page * 0x400 + offset
+    }
+
+    // The frame is saved too, so a state saved in the middle of a frame finishes drawing it the same way
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.frame.pixels);
+        state.write(&self.chr_rom);
+        state.write(&self.vram);
+        state.write(&self.palette_ram);
+        state.write(&self.screen_mirroring);
+        state.write(&self.region);
+        state.write(&self.control_register.bits());
+        state.write(&self.mask_register.bits());
+        state.write(&self.status_register.bits());
+        state.write(&self.io_latch);
+        state.write(&self.io_latch_decay);
+        state.write(&self.read_buffer);
+        state.write(&self.cycle);
+        state.write(&self.scanline);
+        state.write(&self.frame_count);
+        state.write(&self.oam);
+        state.write(&self.oam_address);
+        state.write(&self.oam_data);
+        state.write(&self.sec_oam);
+        state.write(&self.sec_oam_address);
+        state.write(&self.sec_oam_writes_disabled);
+        state.write(&self.copy_sprite_signal);
+        state.write(&self.oam_address_overflow);
+        state.write(&self.sec_oam_address_overflow);
+        state.write(&self.overflow_detection);
+        state.write(&self.nametable_byte);
+        state.write(&self.attribute_byte);
+        state.write(&self.pattern_table_low_byte);
+        state.write(&self.pattern_table_high_byte);
+        state.write(&self.pattern_low_shift_register);
+        state.write(&self.pattern_high_shift_register);
+        state.write(&self.attribute_low_shift_register);
+        state.write(&self.attribute_high_shift_register);
+        state.write(&self.attribute_low_bit_latch);
+        state.write(&self.attribute_high_bit_latch);
+        state.write(&self.v);
+        state.write(&self.t);
+        state.write(&self.x);
+        state.write(&self.w);
+        state.write(&self.x_scroll);
+        state.write(&self.y_scroll);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_into(&mut self.frame.pixels)?;
+        state.read_into(&mut self.chr_rom)?;
+        self.vram = state.read()?;
+        self.palette_ram = state.read()?;
+        self.screen_mirroring = state.read()?;
+        self.region = state.read()?;
+        self.control_register = ControlFlags::from_bits_truncate(state.read()?);
+        self.mask_register = MaskFlags::from_bits_truncate(state.read()?);
+        self.status_register = StatusFlags::from_bits_truncate(state.read()?);
+        self.io_latch = state.read()?;
+        self.io_latch_decay = state.read()?;
+        self.read_buffer = state.read()?;
+        self.cycle = state.read()?;
+        self.scanline = state.read()?;
+        self.frame_count = state.read()?;
+        if self.cycle > 340 || self.scanline > self.region.pre_render_scanline() {
+            return Err(StateError::Mismatch("ppu position"));
+        }
+        self.oam = state.read()?;
+        self.oam_address = state.read()?;
+        self.oam_data = state.read()?;
+        self.sec_oam = state.read()?;
+        self.sec_oam_address = state.read()?;
+        self.sec_oam_writes_disabled = state.read()?;
+        self.copy_sprite_signal = state.read()?;
+        self.oam_address_overflow = state.read()?;
+        self.sec_oam_address_overflow = state.read()?;
+        self.overflow_detection = state.read()?;
+        self.nametable_byte = state.read()?;
+        self.attribute_byte = state.read()?;
+        self.pattern_table_low_byte = state.read()?;
+        self.pattern_table_high_byte = state.read()?;
+        self.pattern_low_shift_register = state.read()?;
+        self.pattern_high_shift_register = state.read()?;
+        self.attribute_low_shift_register = state.read()?;
+        self.attribute_high_shift_register = state.read()?;
+        self.attribute_low_bit_latch = state.read()?;
+        self.attribute_high_bit_latch = state.read()?;
+        self.v = state.read()?;
+        self.t = state.read()?;
+        self.x = state.read()?;
+        self.w = state.read()?;
+        self.x_scroll = state.read()?;
+        self.y_scroll = state.read()?;
+        Ok(())
+    }
 }
 
 bitflags! {
@@ -576,12 +762,205 @@ bitflags! {
     }
 }
 
+bitflags! {
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct MaskFlags: u8 {
+        const Greyscale = 0b00000001;
+        const ShowBackgroundLeft = 0b00000010;
+        const ShowSpritesLeft = 0b00000100;
+        const ShowBackground = 0b00001000;
+        const ShowSprites = 0b00010000;
+        const EmphasizeRed = 0b00100000;
+        const EmphasizeGreen = 0b01000000;
+        const EmphasizeBlue = 0b10000000;
+    }
+}
+
+impl MaskFlags {
+    // The emphasis bits shifted down, as used to index the emphasis tables of a palette
+    pub fn emphasis(&self) -> u8 {
+        self.bits() >> 5
+    }
+}
+
 impl ControlFlags {
     // Method to extract the VRAM address increment value
     pub fn vram_address_increment(&self) -> u8 {
-        match *self {
-            Self::VramAddressIncrement => 32,
-            _ => 1,
+        if self.contains(Self::VramAddressIncrement) {
+            32
+        } else {
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn ppu() -> Ppu {
+        Ppu::new(vec![0; 0x2000], Mirroring::Vertical, Region::Ntsc)
+    }
+
+    fn set_address(ppu: &mut Ppu, address: u16) {
+        ppu.write_ppuaddr((address >> 8) as u8);
+        ppu.write_ppuaddr(address as u8);
+    }
+
+    #[test]
+    fn test_ppudata_read_buffer() {
+        let mut ppu = ppu();
+        ppu.vram[0x10] = 0xAB;
+        ppu.vram[0x11] = 0xCD;
+
+        set_address(&mut ppu, 0x2010);
+        // The first read returns the stale buffer contents
+        assert_eq!(ppu.read_ppudata(), 0x00);
+        assert_eq!(ppu.read_ppudata(), 0xAB);
+        assert_eq!(ppu.read_ppudata(), 0xCD);
+    }
+
+    #[test]
+    fn test_ppudata_palette_read() {
+        let mut ppu = ppu();
+        ppu.palette_ram[0x01] = 0x2A;
+        ppu.write(0x2F01, 0x77);
+
+        set_address(&mut ppu, 0x3F01);
+        ppu.write_io_latch(0b1100_0000);
+        // Palette reads return immediately with the upper 2 bits from open bus
+        assert_eq!(ppu.read_ppudata(), 0b1100_0000 | 0x2A);
+        // The buffer is filled with the nametable byte under the palette
+        assert_eq!(ppu.read_buffer, 0x77);
+    }
+
+    #[test]
+    fn test_palette_mirroring() {
+        let mut ppu = ppu();
+        for address in 0x3F00..=0x3FFF {
+            let index = (address & 0x1F) as usize;
+            let expected_index = match index {
+                0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+                _ => index,
+            };
+            assert_eq!(Ppu::palette_ram_index(address), expected_index, "address {:X}", address);
+        }
+
+        ppu.write(0x3F10, 0x12);
+        assert_eq!(ppu.read(0x3F00), 0x12);
+        ppu.write(0x3F04, 0x23);
+        assert_eq!(ppu.read(0x3F14), 0x23);
+        ppu.write(0x3FF8, 0x34);
+        assert_eq!(ppu.read(0x3F18), 0x34);
+        assert_eq!(ppu.read(0x3F08), 0x34);
+        ppu.write(0x3F11, 0x05);
+        assert_eq!(ppu.read(0x3F01), 0x00);
+        assert_eq!(ppu.read(0x3FF1), 0x05);
+    }
+
+    #[test]
+    fn test_palette_write_range() {
+        let mut ppu = ppu();
+        for address in 0x3F00..=0x3FFF {
+            ppu.write(address, address as u8);
+            assert_eq!(ppu.read(address), address as u8 & PALETTE_VALUE_MASK, "address {:X}", address);
+            for mirror in (0x3F00..=0x3FFF).filter(|mirror| Ppu::palette_ram_index(*mirror) == Ppu::palette_ram_index(address)) {
+                assert_eq!(ppu.read(mirror), address as u8 & PALETTE_VALUE_MASK, "address {:X}", mirror);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        let cases = [
+            (Mirroring::Horizontal, [0, 0, 1, 1]),
+            (Mirroring::Vertical, [0, 1, 0, 1]),
+            (Mirroring::SingleScreenLower, [0, 0, 0, 0]),
+            (Mirroring::SingleScreenUpper, [1, 1, 1, 1]),
+            (Mirroring::FourScreen, [0, 1, 2, 3]),
+            (Mirroring::Custom([3, 0, 2, 1]), [3, 0, 2, 1]),
+        ];
+        let mut ppu = ppu();
+        for (mirroring, pages) in cases {
+            ppu.screen_mirroring = mirroring;
+            for address in 0x2000..=0x3EFF {
+                let nametable = ((address - 0x2000) / 0x400) % 4;
+                let expected_index = pages[nametable as usize] * 0x400 + (address as usize & 0x3FF);
+                assert_eq!(ppu.nametable_vram_index(address), expected_index, "{:?} address {:X}", mirroring, address);
+            }
+        }
+    }
+
+    #[test]
+    fn test_nametable_write() {
+        let mut ppu = ppu();
+        ppu.screen_mirroring = Mirroring::Horizontal;
+        ppu.write(0x2005, 0x11);
+        assert_eq!(ppu.vram[0x005], 0x11);
+        assert_eq!(ppu.read(0x2405), 0x11);
+        assert_eq!(ppu.read(0x3005), 0x11);
+        ppu.write(0x2C05, 0x22);
+        assert_eq!(ppu.read(0x2805), 0x22);
+        assert_eq!(ppu.read(0x2005), 0x11);
+
+        ppu.screen_mirroring = Mirroring::Vertical;
+        assert_eq!(ppu.read(0x2805), 0x11);
+        assert_eq!(ppu.read(0x2405), 0x22);
+    }
+
+    #[test]
+    fn test_region_frame_timing() {
+        for (region, scanlines, vblank_scanline) in [(Region::Ntsc, 262, 241), (Region::Pal, 312, 241), (Region::Dendy, 312, 291)] {
+            let mut ppu = ppu();
+            ppu.set_region(region);
+            ppu.scanline = 0;
+            let mut dots = 0;
+            let mut vblank_started_at = None;
+            while dots == 0 || ppu.scanline != 0 || ppu.cycle != 0 {
+                ppu.step();
+                dots += 1;
+                if vblank_started_at.is_none() && ppu.status_register.contains(StatusFlags::VerticalBlankStarted) {
+                    vblank_started_at = Some(ppu.scanline);
+                }
+            }
+            assert_eq!(dots, scanlines * 341, "{:?}", region);
+            assert_eq!(vblank_started_at, Some(vblank_scanline), "{:?}", region);
+        }
+    }
+
+    #[test]
+    fn test_pal_emphasis_swap() {
+        let mut ppu = ppu();
+        ppu.set_region(Region::Pal);
+        ppu.scanline = 0;
+        ppu.cycle = 1;
+        ppu.write_mask(MaskFlags::EmphasizeRed.bits());
+        ppu.render_pixel();
+        // On PAL the "red" bit of PPUMASK emphasizes green
+        assert_eq!(ppu.frame.pixel(0, 0) >> 6, 0b010);
+    }
+
+    #[test]
+    fn test_status_open_bus() {
+        let mut ppu = ppu();
+        ppu.status_register.set(StatusFlags::VerticalBlankStarted, true);
+        ppu.write_io_latch(0b0101_0101);
+
+        assert_eq!(ppu.read_status().bits(), 0b1001_0101);
+        assert_eq!(ppu.io_latch, 0b1001_0101);
+        assert_eq!(ppu.read_status().bits(), 0b0001_0101);
+    }
+
+    #[test]
+    fn test_io_latch_decay() {
+        let mut ppu = ppu();
+        ppu.write_io_latch(0xFF);
+        for _ in 0..IO_LATCH_DECAY_FRAMES - 1 {
+            ppu.decay_io_latch();
         }
+        ppu.refresh_io_latch(0xFF, 0x0F);
+        ppu.decay_io_latch();
+        assert_eq!(ppu.io_latch, 0x0F);
     }
 }
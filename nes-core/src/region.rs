@@ -0,0 +1,113 @@
+// The console variant being emulated, which decides the video timing and the cpu to ppu clock ratio.
+// See https://www.nesdev.org/wiki/Cycle_reference_chart
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    // Famiclones such as the Dendy combine PAL video timing with NTSC cpu timing
+    Dendy,
+}
+
+#[rustfmt::skip]
+const NTSC_NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+#[rustfmt::skip]
+const PAL_NOISE_PERIODS: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+#[rustfmt::skip]
+const NTSC_DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+#[rustfmt::skip]
+const PAL_DMC_RATES: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+impl Region {
+    pub const ALL: [Region; 3] = [Region::Ntsc, Region::Pal, Region::Dendy];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        }
+    }
+
+    // Scanlines per frame including the pre-render scanline
+    pub fn scanlines(&self) -> usize {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    pub fn pre_render_scanline(&self) -> usize {
+        self.scanlines() - 1
+    }
+
+    // The scanline where the vblank flag gets set and the nmi fires
+    pub fn vblank_scanline(&self) -> usize {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            // The Dendy has 51 post-render scanlines so that its vblank is as long as on NTSC
+            Region::Dendy => 291,
+        }
+    }
+
+    // Ppu dots per cpu cycle as a fraction (numerator, denominator)
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (u32, u32) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    pub fn cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal => 50.0070,
+            Region::Dendy => 50.0,
+        }
+    }
+
+    // The 2C07 and the Dendy's ppu swap the red and green emphasis bits of PPUMASK
+    pub fn swaps_emphasis_bits(&self) -> bool {
+        matches!(self, Region::Pal | Region::Dendy)
+    }
+
+    // Apu noise channel timer periods in cpu cycles
+    pub fn noise_periods(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_NOISE_PERIODS,
+            Region::Pal => &PAL_NOISE_PERIODS,
+        }
+    }
+
+    // Apu delta modulation channel rates in cpu cycles
+    pub fn dmc_rates(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_RATES,
+            Region::Pal => &PAL_DMC_RATES,
+        }
+    }
+
+    // Cpu cycles at which the first four steps of the apu frame counter happen
+    pub fn frame_counter_steps(&self) -> [u32; 4] {
+        match self {
+            Region::Ntsc | Region::Dendy => [7457, 14913, 22371, 29829],
+            Region::Pal => [8313, 16627, 24939, 33252],
+        }
+    }
+
+    // Cpu cycle of the last step of the 5 step frame counter sequence, which replaces the fourth step
+    pub fn frame_counter_fifth_step(&self) -> u32 {
+        match self {
+            Region::Ntsc | Region::Dendy => 37281,
+            Region::Pal => 41565,
+        }
+    }
+}
@@ -0,0 +1,246 @@
+// Rewinding, a ring of save states captured every few frames that can be stepped back through.
+// Only the newest state is kept whole, every older one is stored as the bytes that differ from the state after it
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Deltas start with whether they hold the changed runs or, if the state changed size, the whole state
+const RUNS_DELTA: u8 = 0;
+const FULL_DELTA: u8 = 1;
+
+pub struct RewindBuffer {
+    // 0 is treated like 1, a snapshot every frame
+    pub frames_per_snapshot: u32,
+    // Snapshots are dropped oldest first to stay under this many bytes
+    pub memory_budget: usize,
+    frames_until_snapshot: u32,
+    newest: Option<Vec<u8>>,
+    // Oldest first, each one rebuilds the snapshot before the one after it
+    deltas: VecDeque<Vec<u8>>,
+    used_bytes: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(frames_per_snapshot: u32, memory_budget: usize) -> Self {
+        RewindBuffer {
+            frames_per_snapshot: frames_per_snapshot.max(1),
+            memory_budget,
+            frames_until_snapshot: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            used_bytes: 0,
+        }
+    }
+
+    // Called once per emulated frame, only takes a snapshot every frames_per_snapshot frames
+    pub fn push_frame(&mut self, snapshot: impl FnOnce() -> Vec<u8>) {
+        if self.frames_until_snapshot > 0 {
+            self.frames_until_snapshot -= 1;
+            return;
+        }
+        self.frames_until_snapshot = self.frames_per_snapshot.saturating_sub(1);
+        self.push(snapshot());
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            let delta = encode_delta(&snapshot, &previous);
+            self.used_bytes += delta.len();
+            self.used_bytes -= previous.len();
+            self.deltas.push_back(delta);
+        }
+        self.used_bytes += snapshot.len();
+        self.newest = Some(snapshot);
+
+        while self.used_bytes > self.memory_budget {
+            let Some(oldest) = self.deltas.pop_front() else {
+                break;
+            };
+            self.used_bytes -= oldest.len();
+        }
+    }
+
+    // Steps back one snapshot and returns it. Once the oldest snapshot is reached it keeps returning that one
+    pub fn rewind(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        let snapshot = match self.deltas.pop_back() {
+            Some(delta) => {
+                self.used_bytes -= delta.len() + newest.len();
+                let previous = apply_delta(&newest, &delta);
+                self.used_bytes += previous.len();
+                previous
+            }
+            None => newest,
+        };
+        self.newest = Some(snapshot.clone());
+        // The next snapshot is taken a whole interval after the one rewound to
+        self.frames_until_snapshot = self.frames_per_snapshot.saturating_sub(1);
+        Some(snapshot)
+    }
+
+    pub fn snapshot_count(&self) -> usize {
+        self.newest.iter().count() + self.deltas.len()
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+}
+
+// Encodes older as pairs of an unchanged run length and a changed run, followed by the changed bytes
+fn encode_delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    if newer.len() != older.len() {
+        let mut delta = vec![FULL_DELTA];
+        delta.extend_from_slice(older);
+        return delta;
+    }
+    let mut delta = vec![RUNS_DELTA];
+    let mut position = 0;
+    while position < older.len() {
+        let unchanged = newer[position..]
+            .iter()
+            .zip(&older[position..])
+            .take_while(|(new, old)| new == old)
+            .count();
+        position += unchanged;
+        let changed = newer[position..]
+            .iter()
+            .zip(&older[position..])
+            .take_while(|(new, old)| new != old)
+            .count();
+        write_varint(&mut delta, unchanged);
+        write_varint(&mut delta, changed);
+        delta.extend_from_slice(&older[position..position + changed]);
+        position += changed;
+    }
+    delta
+}
+
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    if delta[0] == FULL_DELTA {
+        return delta[1..].to_vec();
+    }
+    let mut older = newer.to_vec();
+    let mut position = 0;
+    let mut delta_position = 1;
+    while delta_position < delta.len() {
+        position += read_varint(delta, &mut delta_position);
+        let changed = read_varint(delta, &mut delta_position);
+        older[position..position + changed].copy_from_slice(&delta[delta_position..delta_position + changed]);
+        position += changed;
+        delta_position += changed;
+    }
+    older
+}
+
+// 7 bits per byte, the top bit is set on every byte but the last
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*position];
+        *position += 1;
+        value |= (byte as usize & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(frame: u8) -> Vec<u8> {
+        let mut snapshot = vec![0; 1000];
+        snapshot[10] = frame;
+        snapshot[500..520].fill(frame.wrapping_mul(3));
+        snapshot
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let newer: Vec<u8> = (0..=255).collect();
+        let mut older = newer.clone();
+        older[0] = 0xFF;
+        older[100..200].fill(0);
+        older[255] = 0;
+        let delta = encode_delta(&newer, &older);
+        assert!(delta.len() < 120);
+        assert_eq!(apply_delta(&newer, &delta), older);
+
+        assert_eq!(apply_delta(&newer, &encode_delta(&newer, &newer)), newer);
+        assert_eq!(apply_delta(&newer, &encode_delta(&newer, &[1, 2, 3])), [1, 2, 3]);
+
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 300_000);
+        assert_eq!(read_varint(&bytes, &mut 0), 300_000);
+    }
+
+    #[test]
+    fn test_rewind_order() {
+        let mut buffer = RewindBuffer::new(1, usize::MAX);
+        for frame in 0..10 {
+            buffer.push(snapshot(frame));
+        }
+        assert_eq!(buffer.snapshot_count(), 10);
+        // Deltas are much smaller than whole snapshots
+        assert!(buffer.used_bytes() < 1000 + 9 * 100);
+
+        for frame in (0..9).rev() {
+            assert_eq!(buffer.rewind(), Some(snapshot(frame)));
+        }
+        assert_eq!(buffer.rewind(), Some(snapshot(0)));
+        assert_eq!(buffer.snapshot_count(), 1);
+
+        buffer.push(snapshot(20));
+        assert_eq!(buffer.rewind(), Some(snapshot(0)));
+    }
+
+    #[test]
+    fn test_zero_frames_per_snapshot() {
+        let mut buffer = RewindBuffer::new(1, usize::MAX);
+        buffer.frames_per_snapshot = 0;
+        for frame in 0..3 {
+            buffer.push_frame(|| snapshot(frame));
+        }
+        assert_eq!(buffer.snapshot_count(), 3);
+        assert_eq!(buffer.rewind(), Some(snapshot(1)));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut buffer = RewindBuffer::new(1, 2000);
+        for frame in 0..100 {
+            buffer.push(snapshot(frame));
+            assert!(buffer.used_bytes() <= 2000);
+        }
+        let count = buffer.snapshot_count();
+        assert!(count > 2 && count < 100);
+        let mut oldest = None;
+        while buffer.snapshot_count() > 1 {
+            oldest = buffer.rewind();
+        }
+        assert_eq!(oldest, Some(snapshot(100 - count as u8)));
+    }
+
+    #[test]
+    fn test_snapshot_interval() {
+        let mut buffer = RewindBuffer::new(3, usize::MAX);
+        for frame in 0..10 {
+            buffer.push_frame(|| snapshot(frame));
+        }
+        // Frames 0, 3, 6 and 9
+        assert_eq!(buffer.snapshot_count(), 4);
+        assert_eq!(buffer.rewind(), Some(snapshot(6)));
+    }
+}
@@ -0,0 +1,836 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::database::{GameInfo, BUILTIN_DATABASE};
+use crate::fds;
+use crate::region::Region;
+
+const NES_MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_UNIT: usize = 16384;
+const CHR_ROM_UNIT: usize = 8192;
+const UNIF_MAGIC: [u8; 4] = *b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
+const UNIF_CHUNK_HEADER_SIZE: usize = 8;
+// UNIF files don't say how much PRG RAM the board has, boards with RAM almost all have 8 KiB
+const UNIF_PRG_RAM_SIZE: usize = 8192;
+
+// UNIF board names without their NES-, UNL-, HVC-, BTL- or BMC- prefix, and the mapper and submapper implementing them.
+// See https://www.nesdev.org/wiki/UNIF_to_NES_2.0_Mapping
+const UNIF_BOARDS: [(&str, u16, u8); 67] = [
+    ("NROM", 0, 0),
+    ("NROM-128", 0, 0),
+    ("NROM-256", 0, 0),
+    ("RROM", 0, 0),
+    ("SAROM", 1, 0),
+    ("SBROM", 1, 0),
+    ("SCROM", 1, 0),
+    ("SEROM", 1, 0),
+    ("SGROM", 1, 0),
+    ("SKROM", 1, 0),
+    ("SLROM", 1, 0),
+    ("SL1ROM", 1, 0),
+    ("SNROM", 1, 0),
+    ("SOROM", 1, 0),
+    ("UNROM", 2, 0),
+    ("UOROM", 2, 0),
+    ("CNROM", 3, 0),
+    ("TBROM", 4, 0),
+    ("TEROM", 4, 0),
+    ("TFROM", 4, 0),
+    ("TGROM", 4, 0),
+    ("TKROM", 4, 0),
+    ("TLROM", 4, 0),
+    ("TL1ROM", 4, 0),
+    ("TR1ROM", 4, 0),
+    ("TSROM", 4, 0),
+    ("TVROM", 4, 0),
+    ("EKROM", 5, 0),
+    ("ELROM", 5, 0),
+    ("ETROM", 5, 0),
+    ("EWROM", 5, 0),
+    ("AMROM", 7, 0),
+    ("ANROM", 7, 0),
+    ("AN1ROM", 7, 0),
+    ("AOROM", 7, 0),
+    ("PNROM", 9, 0),
+    ("FJROM", 10, 0),
+    ("FKROM", 10, 0),
+    ("CPROM", 13, 0),
+    ("GNROM", 66, 0),
+    ("MHROM", 66, 0),
+    ("CC-21", 27, 0),
+    ("Super700in1", 62, 0),
+    ("SuperHIK8in1", 45, 0),
+    ("H2288", 123, 0),
+    ("22211", 132, 0),
+    ("SA-72008", 133, 0),
+    ("Sachen-8259D", 137, 0),
+    ("Sachen-8259B", 138, 0),
+    ("Sachen-8259C", 139, 0),
+    ("Sachen-8259A", 141, 0),
+    ("SA-NROM", 143, 0),
+    ("SA-72007", 145, 0),
+    ("TC-U01-1.5M", 147, 0),
+    ("SA-0037", 148, 0),
+    ("SA-0036", 149, 0),
+    ("Super24in1SC03", 176, 0),
+    ("FK23C", 176, 0),
+    ("NovelDiamond9999999in1", 201, 0),
+    ("8237", 215, 0),
+    ("Ghostbusters63in1", 226, 0),
+    ("70in1", 236, 0),
+    ("70in1B", 236, 0),
+    ("KOF97", 263, 0),
+    ("T-262", 265, 0),
+    ("SMB2J", 304, 0),
+    ("12-IN-1", 331, 0),
+];
+const UNIF_BOARD_PREFIXES: [&str; 5] = ["NES-", "UNL-", "HVC-", "BTL-", "BMC-"];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mirroring {
+    // $2000 = $2400 and $2800 = $2C00
+    Horizontal,
+    // $2000 = $2800 and $2400 = $2C00
+    Vertical,
+    // Every nametable maps to the first page of vram
+    SingleScreenLower,
+    // Every nametable maps to the second page of vram
+    SingleScreenUpper,
+    // The cartridge provides an extra 2 KiB of vram so each nametable gets its own page
+    FourScreen,
+    // The vram page (0-3) used for each of the four nametables, set by mappers with unusual wiring
+    Custom([u8; 4]),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HeaderFormat {
+    INes,
+    Nes2,
+    // UNIF files name the board instead of giving a mapper number, see https://www.nesdev.org/wiki/UNIF
+    Unif,
+    // Famicom Disk System images, see https://www.nesdev.org/wiki/FDS_file_format
+    Fds,
+}
+
+// CPU/PPU timing the game was made for, NES 2.0 byte 12
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TimingMode {
+    Ntsc,
+    Pal,
+    // Runs on both NTSC and PAL consoles
+    MultiRegion,
+    Dendy,
+}
+
+impl TimingMode {
+    pub fn region(&self) -> Region {
+        match self {
+            TimingMode::Ntsc | TimingMode::MultiRegion => Region::Ntsc,
+            TimingMode::Pal => Region::Pal,
+            TimingMode::Dendy => Region::Dendy,
+        }
+    }
+}
+
+// Flags 7 bits 0-1, with the details from NES 2.0 byte 13
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    // One of the extended console types, such as the Famiclone with decimal mode or the VT01
+    Extended(u8),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RomError {
+    // The file is too short to hold a header
+    TruncatedHeader { size: usize },
+    // The file doesn't start with "NES<EOF>"
+    BadMagic,
+    // The header declares more data than the file holds
+    SizeMismatch { expected: usize, actual: usize },
+    NoPrgRom,
+    UnsupportedMapper { mapper: u16, submapper: u8 },
+    // A UNIF file without a MAPR chunk, or with a board that isn't in the mapping table
+    UnknownBoard(String),
+    // The Famicom Disk System BIOS isn't 8 KiB
+    BadBios { size: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::TruncatedHeader { size } => write!(f, "File is {} bytes, too short to hold an iNES header", size),
+            RomError::BadMagic => write!(f, "File is not in iNES file format"),
+            RomError::SizeMismatch { expected, actual } => {
+                write!(f, "Header declares {} bytes of data but the file is only {} bytes", expected, actual)
+            }
+            RomError::NoPrgRom => write!(f, "Header declares no PRG ROM"),
+            RomError::UnsupportedMapper { mapper, submapper } => write!(f, "Mapper {} (submapper {}) is not supported", mapper, submapper),
+            RomError::UnknownBoard(board) if board.is_empty() => write!(f, "UNIF file doesn't name its board"),
+            RomError::UnknownBoard(board) => write!(f, "UNIF board {} is not known", board),
+            RomError::BadBios { size } => write!(f, "FDS BIOS is {} bytes but should be {} bytes", size, fds::BIOS_SIZE),
+        }
+    }
+}
+
+impl core::error::Error for RomError {}
+
+pub struct Rom {
+    pub header_format: HeaderFormat,
+    // 512 bytes loaded into PRG RAM at $7000-$71FF on power on, empty if the rom has none
+    pub trainer: Vec<u8>,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    // Data that follows the CHR ROM, such as the PlayChoice-10 INST-ROM
+    pub misc_rom: Vec<u8>,
+    pub mapper: u16,
+    pub submapper: u8,
+    // Sizes in bytes of the different kinds of RAM on the cartridge, NVRAM is battery backed
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub has_battery: bool,
+    pub screen_mirroring: Mirroring,
+    pub timing: TimingMode,
+    pub region: Region,
+    pub console_type: ConsoleType,
+    pub misc_rom_count: u8,
+    // See https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+    pub default_expansion_device: u8,
+    // Bytes 7-15 of the header held garbage, such as "DiskDude!", and were ignored
+    pub dirty_header: bool,
+    // The rom database entry matching the PRG and CHR ROM, if there is one
+    pub game_info: Option<GameInfo>,
+    // The board name and game title from a UNIF file
+    pub board: Option<String>,
+    pub title: Option<String>,
+    // The sides of a Famicom Disk System disk, each as stored in a .fds file
+    pub disk_sides: Vec<Vec<u8>>,
+}
+
+impl Rom {
+    // See https://www.nesdev.org/wiki/INES and https://www.nesdev.org/wiki/NES_2.0 for the header layout
+    pub fn new(bytes: &[u8]) -> Result<Rom, RomError> {
+        if bytes.starts_with(&UNIF_MAGIC) {
+            return Self::from_unif(bytes);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(RomError::TruncatedHeader { size: bytes.len() });
+        }
+        if bytes[0..4] != NES_MAGIC {
+            return Err(RomError::BadMagic);
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&bytes[..HEADER_SIZE]);
+        let mut header_format = if header[7] & 0b1100 == 0b1000 {
+            HeaderFormat::Nes2
+        } else {
+            HeaderFormat::INes
+        };
+
+        // A NES 2.0 header that declares more data than the file holds is most likely a dirty iNES header
+        if header_format == HeaderFormat::Nes2 && Self::data_size(&header, HeaderFormat::Nes2) > bytes.len() {
+            header_format = HeaderFormat::INes;
+        }
+        // Old dumping tools wrote their name into the unused bytes at the end of iNES headers, which
+        // also corrupts the upper mapper nibble in byte 7. Bytes 7-15 can't be trusted when that happens
+        let dirty_header = header_format == HeaderFormat::INes && (&header[7..16] == b"DiskDude!" || header[12..16].iter().any(|byte| *byte != 0));
+        if dirty_header {
+            header[7..].fill(0);
+        }
+        let bytes = Self::with_header(bytes, &header);
+        let bytes = bytes.as_slice();
+
+        let has_trainer = bytes[6] & 0b100 != 0;
+        let has_battery = bytes[6] & 0b10 != 0;
+
+        let mirroring = if bytes[6] & 0b1000 != 0 {
+            Mirroring::FourScreen
+        } else if bytes[6] & 0b1 == 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        };
+
+        let console_type = match bytes[7] & 0b11 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem {
+                ppu_type: 0,
+                hardware_type: 0,
+            },
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(0),
+        };
+
+        let mut rom = Self {
+            header_format,
+            trainer: Vec::new(),
+            prg_rom: Vec::new(),
+            chr_rom: Vec::new(),
+            misc_rom: Vec::new(),
+            mapper: ((bytes[7] & 0b1111_0000) | bytes[6] >> 4) as u16,
+            submapper: 0,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            has_battery,
+            screen_mirroring: mirroring,
+            timing: TimingMode::Ntsc,
+            region: Region::Ntsc,
+            console_type,
+            misc_rom_count: 0,
+            default_expansion_device: 0,
+            dirty_header,
+            game_info: None,
+            board: None,
+            title: None,
+            disk_sides: Vec::new(),
+        };
+
+        let (prg_rom_size, chr_rom_size) = Self::rom_sizes(&header, header_format);
+        match header_format {
+            HeaderFormat::INes => {
+                // Flags 8 is the PRG RAM size in 8 KiB units, where 0 means 8 KiB for compatibility
+                let prg_ram_size = bytes[8].max(1) as usize * 8192;
+                if has_battery {
+                    rom.prg_nvram_size = prg_ram_size;
+                } else {
+                    rom.prg_ram_size = prg_ram_size;
+                }
+                // Flags 9 bit 0 marks PAL games, though few dumps set it
+                rom.timing = if bytes[9] & 0b1 != 0 { TimingMode::Pal } else { TimingMode::Ntsc };
+            }
+            HeaderFormat::Nes2 => {
+                rom.mapper |= ((bytes[8] & 0b1111) as u16) << 8;
+                rom.submapper = bytes[8] >> 4;
+                rom.prg_ram_size = Self::shift_count_size(bytes[10] & 0b1111);
+                rom.prg_nvram_size = Self::shift_count_size(bytes[10] >> 4);
+                rom.chr_ram_size = Self::shift_count_size(bytes[11] & 0b1111);
+                rom.chr_nvram_size = Self::shift_count_size(bytes[11] >> 4);
+                rom.timing = match bytes[12] & 0b11 {
+                    0 => TimingMode::Ntsc,
+                    1 => TimingMode::Pal,
+                    2 => TimingMode::MultiRegion,
+                    _ => TimingMode::Dendy,
+                };
+                rom.console_type = match console_type {
+                    ConsoleType::VsSystem { .. } => ConsoleType::VsSystem {
+                        ppu_type: bytes[13] & 0b1111,
+                        hardware_type: bytes[13] >> 4,
+                    },
+                    ConsoleType::Extended(_) => ConsoleType::Extended(bytes[13] & 0b1111),
+                    console_type => console_type,
+                };
+                rom.misc_rom_count = bytes[14] & 0b11;
+                rom.default_expansion_device = bytes[15] & 0b11_1111;
+            }
+            HeaderFormat::Unif => unreachable!("UNIF files are loaded by from_unif"),
+            HeaderFormat::Fds => unreachable!("FDS images are loaded by from_fds"),
+        }
+        rom.region = rom.timing.region();
+
+        if prg_rom_size == 0 {
+            return Err(RomError::NoPrgRom);
+        }
+        let data_size = Self::data_size(&header, header_format);
+        if data_size > bytes.len() {
+            return Err(RomError::SizeMismatch {
+                expected: data_size,
+                actual: bytes.len(),
+            });
+        }
+
+        let prg_rom_start = HEADER_SIZE + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+        let misc_rom_start = chr_rom_start + chr_rom_size;
+
+        rom.trainer = bytes[HEADER_SIZE..prg_rom_start].to_vec();
+        rom.prg_rom = bytes[prg_rom_start..chr_rom_start].to_vec();
+        rom.chr_rom = bytes[chr_rom_start..misc_rom_start].to_vec();
+        rom.misc_rom = bytes[misc_rom_start..].to_vec();
+
+        // iNES 1.0 can't express CHR RAM sizes, boards without CHR ROM have 8 KiB
+        if rom.header_format == HeaderFormat::INes && chr_rom_size == 0 {
+            rom.chr_ram_size = 8192;
+        }
+
+        // Many iNES dumps have the wrong mapper, mirroring or battery bits, NES 2.0 headers are trusted as is
+        rom.game_info = BUILTIN_DATABASE.find(&rom.prg_rom, &rom.chr_rom).cloned();
+        if let Some(game_info) = rom.game_info.clone() {
+            if rom.header_format == HeaderFormat::INes {
+                rom.apply_game_info(&game_info);
+            }
+        }
+
+        Ok(rom)
+    }
+
+    // FDS images only hold the disk, the BIOS from the Famicom's RAM adapter has to be supplied separately.
+    // The BIOS is loaded as the PRG ROM and the disk is run by mapper 20.
+    pub fn from_fds(bytes: &[u8], bios: &[u8]) -> Result<Rom, RomError> {
+        if bios.len() != fds::BIOS_SIZE {
+            return Err(RomError::BadBios { size: bios.len() });
+        }
+        Ok(Self {
+            header_format: HeaderFormat::Fds,
+            trainer: Vec::new(),
+            prg_rom: bios.to_vec(),
+            chr_rom: Vec::new(),
+            misc_rom: Vec::new(),
+            mapper: 20,
+            submapper: 0,
+            prg_ram_size: fds::PRG_RAM_SIZE,
+            prg_nvram_size: 0,
+            chr_ram_size: fds::CHR_RAM_SIZE,
+            chr_nvram_size: 0,
+            has_battery: false,
+            // The RAM adapter controls mirroring
+            screen_mirroring: Mirroring::Horizontal,
+            timing: TimingMode::Ntsc,
+            region: Region::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_rom_count: 0,
+            default_expansion_device: 0,
+            dirty_header: false,
+            game_info: None,
+            board: None,
+            title: None,
+            disk_sides: fds::parse_disk_sides(bytes)?,
+        })
+    }
+
+    // UNIF files are a header followed by chunks of a 4 character id, a 32 bit length and the data.
+    // See https://www.nesdev.org/wiki/UNIF
+    fn from_unif(bytes: &[u8]) -> Result<Rom, RomError> {
+        if bytes.len() < UNIF_HEADER_SIZE {
+            return Err(RomError::TruncatedHeader { size: bytes.len() });
+        }
+
+        let mut rom = Self {
+            header_format: HeaderFormat::Unif,
+            trainer: Vec::new(),
+            prg_rom: Vec::new(),
+            chr_rom: Vec::new(),
+            misc_rom: Vec::new(),
+            mapper: 0,
+            submapper: 0,
+            prg_ram_size: UNIF_PRG_RAM_SIZE,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            has_battery: false,
+            screen_mirroring: Mirroring::Horizontal,
+            timing: TimingMode::Ntsc,
+            region: Region::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_rom_count: 0,
+            default_expansion_device: 0,
+            dirty_header: false,
+            game_info: None,
+            board: None,
+            title: None,
+            disk_sides: Vec::new(),
+        };
+        // PRG0-PRGF and CHR0-CHRF are concatenated in order of their number, not the order they appear in
+        let mut prg_chunks: [&[u8]; 16] = [&[]; 16];
+        let mut chr_chunks: [&[u8]; 16] = [&[]; 16];
+
+        let mut offset = UNIF_HEADER_SIZE;
+        while offset + UNIF_CHUNK_HEADER_SIZE <= bytes.len() {
+            let id = &bytes[offset..offset + 4];
+            let length = u32::from_le_bytes([bytes[offset + 4], bytes[offset + 5], bytes[offset + 6], bytes[offset + 7]]) as usize;
+            let data_start = offset + UNIF_CHUNK_HEADER_SIZE;
+            let data_end = data_start.saturating_add(length);
+            let data = bytes.get(data_start..data_end).ok_or(RomError::SizeMismatch {
+                expected: data_end,
+                actual: bytes.len(),
+            })?;
+            offset = data_end;
+
+            let chunk_number = || (id[3] as char).to_digit(16).map(|number| number as usize);
+            match (&id[..3], id) {
+                (b"PRG", _) if chunk_number().is_some() => prg_chunks[chunk_number().unwrap()] = data,
+                (b"CHR", _) if chunk_number().is_some() => chr_chunks[chunk_number().unwrap()] = data,
+                (_, b"MAPR") => rom.board = Some(Self::unif_string(data)),
+                (_, b"NAME") => rom.title = Some(Self::unif_string(data)),
+                (_, b"MIRR") => {
+                    rom.screen_mirroring = match data.first() {
+                        Some(1) => Mirroring::Vertical,
+                        Some(2) => Mirroring::SingleScreenLower,
+                        Some(3) => Mirroring::SingleScreenUpper,
+                        Some(4) => Mirroring::FourScreen,
+                        // 0 is hardwired horizontal mirroring and 5 is controlled by the mapper
+                        _ => Mirroring::Horizontal,
+                    }
+                }
+                (_, b"BATR") => rom.has_battery = true,
+                (_, b"TVCI") => {
+                    rom.timing = match data.first() {
+                        Some(1) => TimingMode::Pal,
+                        Some(2) => TimingMode::MultiRegion,
+                        _ => TimingMode::Ntsc,
+                    }
+                }
+                (_, b"CTRL") => rom.default_expansion_device = Self::unif_expansion_device(data.first().copied().unwrap_or(0)),
+                _ => {}
+            }
+        }
+
+        let board = rom.board.clone().unwrap_or_default();
+        let board_name = UNIF_BOARD_PREFIXES.iter().find_map(|prefix| board.strip_prefix(prefix)).unwrap_or(&board);
+        let (_, mapper, submapper) = UNIF_BOARDS
+            .iter()
+            .find(|(name, _, _)| name.eq_ignore_ascii_case(board_name))
+            .ok_or_else(|| RomError::UnknownBoard(board.clone()))?;
+        rom.mapper = *mapper;
+        rom.submapper = *submapper;
+
+        rom.prg_rom = prg_chunks.concat();
+        rom.chr_rom = chr_chunks.concat();
+        if rom.prg_rom.is_empty() {
+            return Err(RomError::NoPrgRom);
+        }
+        if rom.chr_rom.is_empty() {
+            rom.chr_ram_size = 8192;
+        }
+        if rom.has_battery {
+            rom.prg_nvram_size = rom.prg_ram_size;
+            rom.prg_ram_size = 0;
+        }
+        rom.region = rom.timing.region();
+        rom.game_info = BUILTIN_DATABASE.find(&rom.prg_rom, &rom.chr_rom).cloned();
+
+        Ok(rom)
+    }
+
+    // Zero terminated UTF-8 strings
+    fn unif_string(data: &[u8]) -> String {
+        let length = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+        String::from_utf8_lossy(&data[..length]).into_owned()
+    }
+
+    // The CTRL chunk is a bit per supported controller, picks the NES 2.0 expansion device of the most specific one
+    fn unif_expansion_device(controllers: u8) -> u8 {
+        const DEVICES: [(u8, u8); 5] = [(0b10_0000, 0x02), (0b1_0000, 0x0B), (0b1000, 0x0F), (0b10, 0x08), (0b1, 0x01)];
+        DEVICES.iter().find(|(bit, _)| controllers & bit != 0).map_or(0, |(_, device)| *device)
+    }
+
+    // Replaces the header fields with the ones from a rom database entry
+    pub fn apply_game_info(&mut self, game_info: &GameInfo) {
+        self.mapper = game_info.mapper;
+        self.submapper = game_info.submapper;
+        if let Some(mirroring) = game_info.mirroring {
+            self.screen_mirroring = mirroring;
+        }
+        match (game_info.prg_ram_size, game_info.prg_nvram_size) {
+            // The header's RAM is kept, but moved to NVRAM or back depending on the corrected battery flag
+            (None, None) => {
+                let size = self.prg_ram_size + self.prg_nvram_size;
+                (self.prg_ram_size, self.prg_nvram_size) = if game_info.has_battery { (0, size) } else { (size, 0) };
+            }
+            (prg_ram_size, prg_nvram_size) => {
+                if let Some(prg_ram_size) = prg_ram_size {
+                    self.prg_ram_size = prg_ram_size;
+                }
+                self.prg_nvram_size = prg_nvram_size.unwrap_or(0);
+            }
+        }
+        self.has_battery = game_info.has_battery;
+        self.chr_ram_size = game_info.chr_ram_size;
+        self.chr_nvram_size = game_info.chr_nvram_size;
+        self.timing = game_info.timing;
+        self.region = game_info.timing.region();
+        self.console_type = game_info.console_type;
+        self.default_expansion_device = game_info.expansion_device;
+    }
+
+    // The file with its header replaced by a cleaned up one
+    fn with_header(bytes: &[u8], header: &[u8; HEADER_SIZE]) -> Vec<u8> {
+        let mut cleaned = bytes.to_vec();
+        cleaned[..HEADER_SIZE].copy_from_slice(header);
+        cleaned
+    }
+
+    // PRG and CHR ROM sizes in bytes
+    fn rom_sizes(header: &[u8; HEADER_SIZE], header_format: HeaderFormat) -> (usize, usize) {
+        match header_format {
+            HeaderFormat::INes => (header[4] as usize * PRG_ROM_UNIT, header[5] as usize * CHR_ROM_UNIT),
+            HeaderFormat::Nes2 => (
+                Self::nes2_rom_size(header[4], header[9] & 0b1111, PRG_ROM_UNIT),
+                Self::nes2_rom_size(header[5], header[9] >> 4, CHR_ROM_UNIT),
+            ),
+            HeaderFormat::Unif | HeaderFormat::Fds => unreachable!("UNIF files and FDS images don't have an iNES header"),
+        }
+    }
+
+    // Size of the header, trainer, PRG ROM and CHR ROM together, saturating so absurd headers fail the size check instead of overflowing
+    fn data_size(header: &[u8; HEADER_SIZE], header_format: HeaderFormat) -> usize {
+        let (prg_rom_size, chr_rom_size) = Self::rom_sizes(header, header_format);
+        let trainer_size = if header[6] & 0b100 != 0 { TRAINER_SIZE } else { 0 };
+        (HEADER_SIZE + trainer_size).saturating_add(prg_rom_size).saturating_add(chr_rom_size)
+    }
+
+    // NES 2.0 RAM sizes are stored as a shift count, 64 << shift bytes, where 0 means no RAM
+    fn shift_count_size(shift_count: u8) -> usize {
+        if shift_count == 0 {
+            0
+        } else {
+            64 << shift_count
+        }
+    }
+
+    // NES 2.0 ROM sizes are either a 12 bit count of units, or an exponent-multiplier when the upper 4 bits are all set.
+    // The exponent goes up to 2^63, sizes that don't fit in a usize saturate
+    fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+        if msb == 0b1111 {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0b11) as usize * 2 + 1;
+            1usize
+                .checked_shl(exponent)
+                .and_then(|size| size.checked_mul(multiplier))
+                .unwrap_or(usize::MAX)
+        } else {
+            (((msb as usize) << 8) | lsb as usize).saturating_mul(unit)
+        }
+    }
+}
+
+impl Default for Rom {
+    fn default() -> Self {
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01];
+        bytes.resize(24592, 0x00);
+        Self::new(&bytes).expect("Failed to create default rom")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::borrow::ToOwned;
+
+    fn rom_bytes(header: [u8; 16], data_size: usize) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        bytes.resize(HEADER_SIZE + data_size, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let header = [0x4E, 0x45, 0x53, 0x1A, 2, 0, 0b0001_0011, 0b0100_0000, 0, 1, 0, 0, 0, 0, 0, 0];
+        let rom = Rom::new(&rom_bytes(header, 2 * PRG_ROM_UNIT)).unwrap();
+        assert_eq!(rom.header_format, HeaderFormat::INes);
+        assert_eq!(rom.mapper, 0x41);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_UNIT);
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.chr_ram_size, 8192);
+        assert!(rom.has_battery);
+        assert_eq!(rom.prg_nvram_size, 8192);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert_eq!(rom.region, Region::Pal);
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        let header = [
+            0x4E,
+            0x45,
+            0x53,
+            0x1A,
+            0x02,        // PRG ROM LSB
+            0x01,        // CHR ROM LSB
+            0b0001_0010, // Mapper D3..D0, battery
+            0b0010_1001, // Mapper D7..D4, NES 2.0, Vs. System
+            0b0101_0001, // Submapper 5, mapper D11..D8
+            0x10,        // PRG ROM MSB 0, CHR ROM MSB 1
+            0x97,        // PRG NVRAM 64 << 9, PRG RAM 64 << 7
+            0x07,        // CHR RAM 64 << 7
+            0x03,        // Dendy
+            0x23,        // Vs. hardware type 2, PPU type 3
+            0x01,        // One misc ROM
+            0x2A,        // Expansion device
+        ];
+        let rom = Rom::new(&rom_bytes(header, 2 * PRG_ROM_UNIT + 0x101 * CHR_ROM_UNIT + 16)).unwrap();
+        assert_eq!(rom.header_format, HeaderFormat::Nes2);
+        assert_eq!(rom.mapper, 0x121);
+        assert_eq!(rom.submapper, 5);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_UNIT);
+        assert_eq!(rom.chr_rom.len(), 0x101 * CHR_ROM_UNIT);
+        assert_eq!(rom.misc_rom.len(), 16);
+        assert_eq!(rom.prg_ram_size, 8192);
+        assert_eq!(rom.prg_nvram_size, 32768);
+        assert_eq!(rom.chr_ram_size, 8192);
+        assert_eq!(rom.chr_nvram_size, 0);
+        assert_eq!(rom.timing, TimingMode::Dendy);
+        assert_eq!(rom.region, Region::Dendy);
+        assert_eq!(
+            rom.console_type,
+            ConsoleType::VsSystem {
+                ppu_type: 3,
+                hardware_type: 2
+            }
+        );
+        assert_eq!(rom.misc_rom_count, 1);
+        assert_eq!(rom.default_expansion_device, 0x2A);
+    }
+
+    #[test]
+    fn test_trainer() {
+        let header = [0x4E, 0x45, 0x53, 0x1A, 1, 0, 0b100, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut bytes = rom_bytes(header, TRAINER_SIZE + PRG_ROM_UNIT);
+        bytes[HEADER_SIZE..HEADER_SIZE + TRAINER_SIZE].fill(0xAA);
+        bytes[HEADER_SIZE + TRAINER_SIZE] = 0xBB;
+        let rom = Rom::new(&bytes).unwrap();
+        assert_eq!(rom.trainer, vec![0xAA; TRAINER_SIZE]);
+        assert_eq!(rom.prg_rom[0], 0xBB);
+
+        let header = [0x4E, 0x45, 0x53, 0x1A, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(Rom::new(&rom_bytes(header, PRG_ROM_UNIT)).unwrap().trainer.is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Rom::new(&[0x4E, 0x45, 0x53]).err(), Some(RomError::TruncatedHeader { size: 3 }));
+        assert_eq!(Rom::new(&[0; 32]).err(), Some(RomError::BadMagic));
+
+        let header = [0x4E, 0x45, 0x53, 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            Rom::new(&rom_bytes(header, 2 * PRG_ROM_UNIT)).err(),
+            Some(RomError::SizeMismatch {
+                expected: HEADER_SIZE + 2 * PRG_ROM_UNIT + CHR_ROM_UNIT,
+                actual: HEADER_SIZE + 2 * PRG_ROM_UNIT
+            })
+        );
+
+        // The trainer counts towards the declared size
+        let header = [0x4E, 0x45, 0x53, 0x1A, 1, 0, 0b100, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(Rom::new(&rom_bytes(header, PRG_ROM_UNIT)), Err(RomError::SizeMismatch { .. })));
+
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(Rom::new(&rom_bytes(header, CHR_ROM_UNIT)).err(), Some(RomError::NoPrgRom));
+    }
+
+    #[test]
+    fn test_dirty_header() {
+        let mut header = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0b0001_0001, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        header[7..16].copy_from_slice(b"DiskDude!");
+        let rom = Rom::new(&rom_bytes(header, PRG_ROM_UNIT + CHR_ROM_UNIT)).unwrap();
+        assert!(rom.dirty_header);
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.header_format, HeaderFormat::INes);
+        assert_eq!(rom.console_type, ConsoleType::Nes);
+
+        // Garbage that happens to look like a NES 2.0 header, but declares far more CHR ROM than the file holds
+        let header = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0b0100_0000, b'x', b'y', b'z', 0, 0, b'a', b'b', b'c', b'd'];
+        let rom = Rom::new(&rom_bytes(header, PRG_ROM_UNIT + CHR_ROM_UNIT)).unwrap();
+        assert!(rom.dirty_header);
+        assert_eq!(rom.mapper, 4);
+        assert_eq!(rom.region, Region::Ntsc);
+    }
+
+    #[test]
+    fn test_nes2_exponent_multiplier_size() {
+        // 2^7 * (1 * 2 + 1) = 384 bytes of PRG ROM
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0b0001_1101, 0, 0, 0b1000, 0, 0x0F, 0, 0, 0, 0, 0, 0];
+        let rom = Rom::new(&rom_bytes(header, 384)).unwrap();
+        assert_eq!(rom.prg_rom.len(), 384);
+
+        // 2^63 * 7 bytes doesn't fit in a usize, which has to be an error rather than an overflow
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0xFF, 0xFF, 0, 0b1000, 0, 0xFF, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(Rom::new(&rom_bytes(header, 384)), Err(RomError::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn test_database_corrects_ines_header() {
+        // nestest with a wrong mapper, vertical mirroring and a battery
+        let mut bytes = include_bytes!("../tests/nestest.nes").to_vec();
+        bytes[6] = 0b0011_0011;
+        let rom = Rom::new(&bytes).unwrap();
+        assert_eq!(rom.game_info.as_ref().unwrap().title, "nestest");
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.screen_mirroring, Mirroring::Horizontal);
+        assert!(!rom.has_battery);
+        // The entry has no PRG RAM sizes, so the header's 8 KiB stays but isn't battery backed anymore
+        assert_eq!((rom.prg_ram_size, rom.prg_nvram_size), (8192, 0));
+
+        bytes[6] = 0b0011_0001;
+        let rom = Rom::new(&bytes).unwrap();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.prg_ram_size + rom.prg_nvram_size, 8192);
+        assert_eq!(rom.prg_ram_size, 8192);
+
+        // NES 2.0 headers are trusted
+        bytes[7] = 0b1000;
+        let rom = Rom::new(&bytes).unwrap();
+        assert!(rom.game_info.is_some());
+        assert_eq!(rom.mapper, 3);
+    }
+
+    fn unif_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    #[test]
+    fn test_unif() {
+        let mut bytes = b"UNIF".to_vec();
+        bytes.extend(7u32.to_le_bytes());
+        bytes.resize(UNIF_HEADER_SIZE, 0);
+        bytes.extend(unif_chunk(b"MAPR", b"UNL-Sachen-8259A\0"));
+        bytes.extend(unif_chunk(b"NAME", b"Test\0"));
+        // PRG1 comes before PRG0 in the file but after it in the rom
+        bytes.extend(unif_chunk(b"PRG1", &[1; 0x4000]));
+        bytes.extend(unif_chunk(b"PRG0", &[0; 0x4000]));
+        bytes.extend(unif_chunk(b"CHR0", &[2; 0x2000]));
+        bytes.extend(unif_chunk(b"MIRR", &[1]));
+        bytes.extend(unif_chunk(b"BATR", &[1]));
+        bytes.extend(unif_chunk(b"CTRL", &[0b11]));
+
+        let rom = Rom::new(&bytes).unwrap();
+        assert_eq!(rom.header_format, HeaderFormat::Unif);
+        assert_eq!(rom.board.as_deref(), Some("UNL-Sachen-8259A"));
+        assert_eq!(rom.title.as_deref(), Some("Test"));
+        assert_eq!(rom.mapper, 141);
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!((rom.prg_rom[0x3FFF], rom.prg_rom[0x4000]), (0, 1));
+        assert_eq!(rom.chr_rom.len(), 0x2000);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(rom.has_battery);
+        assert_eq!(rom.prg_nvram_size, UNIF_PRG_RAM_SIZE);
+        assert_eq!(rom.default_expansion_device, 0x08);
+
+        let mut unknown = bytes[..UNIF_HEADER_SIZE].to_vec();
+        unknown.extend(unif_chunk(b"MAPR", b"UNL-NotABoard\0"));
+        assert_eq!(Rom::new(&unknown).err(), Some(RomError::UnknownBoard("UNL-NotABoard".to_owned())));
+
+        // A chunk length near 4 GiB runs past the end of the file
+        let mut truncated = bytes[..UNIF_HEADER_SIZE].to_vec();
+        truncated.extend(b"PRG0");
+        truncated.extend(u32::MAX.to_le_bytes());
+        assert!(matches!(Rom::new(&truncated), Err(RomError::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn test_fds() {
+        let mut bytes = b"\x01*NINTENDO-HVC*".to_vec();
+        bytes.resize(fds::DISK_SIDE_SIZE * 2, 0);
+        bytes[fds::DISK_SIDE_SIZE..fds::DISK_SIDE_SIZE + 15].copy_from_slice(b"\x01*NINTENDO-HVC*");
+        let rom = Rom::from_fds(&bytes, &[0; fds::BIOS_SIZE]).unwrap();
+        assert_eq!(rom.header_format, HeaderFormat::Fds);
+        assert_eq!(rom.mapper, 20);
+        assert_eq!(rom.prg_rom.len(), fds::BIOS_SIZE);
+        assert_eq!(rom.disk_sides.len(), 2);
+        assert_eq!(Rom::from_fds(&bytes, &[0; 4096]).err(), Some(RomError::BadBios { size: 4096 }));
+    }
+}
@@ -0,0 +1,998 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use libm::{roundf, sqrtf};
+
+use crate::filter::VideoFilter;
+use crate::frame::Frame;
+
+// Pixel art upscalers, they work on row major RGBA8 images such as the output of a VideoFilter
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ScaleMode {
+    // Every pixel becomes a factor x factor block
+    Nearest(usize),
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Hq3x,
+    // Supports factors 2 to 4
+    Xbrz(usize),
+}
+
+impl ScaleMode {
+    pub const ALL: [ScaleMode; 9] = [
+        ScaleMode::Nearest(1),
+        ScaleMode::Nearest(2),
+        ScaleMode::Scale2x,
+        ScaleMode::Scale3x,
+        ScaleMode::Hq2x,
+        ScaleMode::Hq3x,
+        ScaleMode::Xbrz(2),
+        ScaleMode::Xbrz(3),
+        ScaleMode::Xbrz(4),
+    ];
+
+    pub fn factor(&self) -> usize {
+        match self {
+            ScaleMode::Nearest(factor) => *factor,
+            ScaleMode::Scale2x | ScaleMode::Hq2x => 2,
+            ScaleMode::Scale3x | ScaleMode::Hq3x => 3,
+            ScaleMode::Xbrz(factor) => (*factor).clamp(2, 4),
+        }
+    }
+
+    // Finds a mode by its name, ignoring case and spaces so "xbrz4x" picks xBRZ 4x. Nearest takes any factor
+    pub fn from_name(name: &str) -> Option<ScaleMode> {
+        let normalize = |name: &str| name.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+        let name = normalize(name);
+        if let Some(factor) = name.strip_prefix("nearest").and_then(|factor| factor.strip_suffix('x')) {
+            return factor.parse().ok().filter(|factor| *factor > 0).map(ScaleMode::Nearest);
+        }
+        ScaleMode::ALL.into_iter().find(|mode| normalize(&mode.name()) == name)
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            ScaleMode::Nearest(factor) => format!("Nearest {}x", factor),
+            ScaleMode::Scale2x => "Scale2x".to_string(),
+            ScaleMode::Scale3x => "Scale3x".to_string(),
+            ScaleMode::Hq2x => "HQ2x".to_string(),
+            ScaleMode::Hq3x => "HQ3x".to_string(),
+            ScaleMode::Xbrz(_) => format!("xBRZ {}x", self.factor()),
+        }
+    }
+}
+
+// Scales an RGBA8 image, the result is factor() times as wide and tall
+pub fn scale(mode: ScaleMode, input: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let image = Image {
+        pixels: input
+            .chunks_exact(4)
+            .map(|rgba| u32::from_le_bytes([rgba[0], rgba[1], rgba[2], rgba[3]]))
+            .collect(),
+        width,
+        height,
+    };
+    let factor = mode.factor();
+    let mut output = Image {
+        pixels: vec![0; width * factor * height * factor],
+        width: width * factor,
+        height: height * factor,
+    };
+
+    match mode {
+        ScaleMode::Nearest(_) => nearest(&image, &mut output, factor),
+        ScaleMode::Scale2x => scale2x(&image, &mut output),
+        ScaleMode::Scale3x => scale3x(&image, &mut output),
+        ScaleMode::Hq2x | ScaleMode::Hq3x => hqx(&image, &mut output, factor),
+        ScaleMode::Xbrz(_) => xbrz(&image, &mut output, factor),
+    }
+
+    output.pixels.iter().flat_map(|pixel| pixel.to_le_bytes()).collect()
+}
+
+// Runs another filter and scales its output
+pub struct ScaleFilter<F: VideoFilter> {
+    pub source: F,
+    pub mode: ScaleMode,
+}
+
+impl<F: VideoFilter> ScaleFilter<F> {
+    pub fn new(source: F, mode: ScaleMode) -> Self {
+        Self { source, mode }
+    }
+}
+
+impl<F: VideoFilter> VideoFilter for ScaleFilter<F> {
+    fn output_size(&self) -> (usize, usize) {
+        let (width, height) = self.source.output_size();
+        (width * self.mode.factor(), height * self.mode.factor())
+    }
+
+    fn apply(&mut self, frame: &Frame, output: &mut [u8]) {
+        let (width, height) = self.source.output_size();
+        let source_output = self.source.apply_to_vec(frame);
+        output.copy_from_slice(&scale(self.mode, &source_output, width, height));
+    }
+}
+
+struct Image {
+    pixels: Vec<u32>,
+    width: usize,
+    height: usize,
+}
+
+impl Image {
+    // Pixels outside the image repeat the closest edge pixel
+    fn get(&self, x: isize, y: isize) -> u32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    fn set(&mut self, x: usize, y: usize, pixel: u32) {
+        self.pixels[y * self.width + x] = pixel;
+    }
+}
+
+fn nearest(input: &Image, output: &mut Image, factor: usize) {
+    for y in 0..output.height {
+        for x in 0..output.width {
+            output.set(x, y, input.get((x / factor) as isize, (y / factor) as isize));
+        }
+    }
+}
+
+// Neighbours of a pixel e
+// a b c
+// d e f
+// g h i
+struct Neighbourhood {
+    a: u32,
+    b: u32,
+    c: u32,
+    d: u32,
+    e: u32,
+    f: u32,
+    g: u32,
+    h: u32,
+    i: u32,
+}
+
+impl Neighbourhood {
+    fn new(image: &Image, x: usize, y: usize) -> Self {
+        let (x, y) = (x as isize, y as isize);
+        Self {
+            a: image.get(x - 1, y - 1),
+            b: image.get(x, y - 1),
+            c: image.get(x + 1, y - 1),
+            d: image.get(x - 1, y),
+            e: image.get(x, y),
+            f: image.get(x + 1, y),
+            g: image.get(x - 1, y + 1),
+            h: image.get(x, y + 1),
+            i: image.get(x + 1, y + 1),
+        }
+    }
+}
+
+// See https://www.scale2x.it/algorithm
+fn scale2x(input: &Image, output: &mut Image) {
+    for y in 0..input.height {
+        for x in 0..input.width {
+            let Neighbourhood { b, d, e, f, h, .. } = Neighbourhood::new(input, x, y);
+            let (e0, e1, e2, e3) = if b != h && d != f {
+                (
+                    if d == b { d } else { e },
+                    if b == f { f } else { e },
+                    if d == h { d } else { e },
+                    if h == f { f } else { e },
+                )
+            } else {
+                (e, e, e, e)
+            };
+            output.set(x * 2, y * 2, e0);
+            output.set(x * 2 + 1, y * 2, e1);
+            output.set(x * 2, y * 2 + 1, e2);
+            output.set(x * 2 + 1, y * 2 + 1, e3);
+        }
+    }
+}
+
+fn scale3x(input: &Image, output: &mut Image) {
+    for y in 0..input.height {
+        for x in 0..input.width {
+            let Neighbourhood { a, b, c, d, e, f, g, h, i } = Neighbourhood::new(input, x, y);
+            let block = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                    e,
+                    if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                    if d == h { d } else { e },
+                    if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+            for (index, pixel) in block.into_iter().enumerate() {
+                output.set(x * 3 + index % 3, y * 3 + index / 3, pixel);
+            }
+        }
+    }
+}
+
+fn channels(pixel: u32) -> [f32; 4] {
+    pixel.to_le_bytes().map(|channel| channel as f32)
+}
+
+fn from_channels(channels: [f32; 4]) -> u32 {
+    u32::from_le_bytes(channels.map(|channel| roundf(channel).clamp(0.0, 255.0) as u8))
+}
+
+// Weighted average of pixels
+fn interpolate(pixels: &[(u32, u32)]) -> u32 {
+    let total_weight: u32 = pixels.iter().map(|(_, weight)| weight).sum();
+    let mut sum = [0.0; 4];
+    for (pixel, weight) in pixels {
+        for (sum, channel) in sum.iter_mut().zip(channels(*pixel)) {
+            *sum += channel * *weight as f32;
+        }
+    }
+    from_channels(sum.map(|sum| sum / total_weight as f32))
+}
+
+fn yuv(pixel: u32) -> (f32, f32, f32) {
+    let [r, g, b, _] = channels(pixel);
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.169 * r - 0.331 * g + 0.5 * b,
+        0.5 * r - 0.419 * g - 0.081 * b,
+    )
+}
+
+// hqx, see https://code.google.com/archive/p/hqx/
+// Every neighbour whose colour differs from the centre pixel sets a bit of an 8 bit pattern
+//   1   2   4
+//   8       16
+//  32  64  128
+// and the pattern picks the blend of every output pixel from a lookup table. Some entries also depend on whether
+// two of the neighbours differ from each other, like hqx's Diff(w[4], w[2]) checks
+
+// Two pixels are different when any of their Y, U or V differ by more than hqx's thresholds
+fn hqx_differ(first: u32, second: u32) -> bool {
+    if first == second {
+        return false;
+    }
+    let (y1, u1, v1) = yuv(first);
+    let (y2, u2, v2) = yuv(second);
+    (y1 - y2).abs() > 48.0 || (u1 - u2).abs() > 7.0 || (v1 - v2).abs() > 6.0
+}
+
+// The neighbourhood w1 to w9 in row major order, w5 being the pixel being scaled
+const W1: u8 = 0;
+const W2: u8 = 1;
+const W3: u8 = 2;
+const W4: u8 = 3;
+const W5: u8 = 4;
+const W6: u8 = 5;
+const W7: u8 = 6;
+const W8: u8 = 7;
+const W9: u8 = 8;
+
+// A weighted average of up to three pixels of the neighbourhood
+#[derive(Clone, Copy)]
+struct HqxBlend {
+    pixels: [u8; 3],
+    weights: [u8; 3],
+}
+
+impl HqxBlend {
+    const CENTRE: HqxBlend = HqxBlend::new([W5, W5, W5], [1, 0, 0]);
+
+    const fn new(pixels: [u8; 3], weights: [u8; 3]) -> Self {
+        Self { pixels, weights }
+    }
+
+    const fn rotated(self, rotation: usize) -> Self {
+        let mut pixels = self.pixels;
+        let mut index = 0;
+        while index < 3 {
+            pixels[index] = hqx_rotate(pixels[index], rotation);
+            index += 1;
+        }
+        Self { pixels, ..self }
+    }
+
+    fn apply(&self, neighbourhood: &[u32; 9]) -> u32 {
+        interpolate(&[0, 1, 2].map(|index| (neighbourhood[self.pixels[index] as usize], self.weights[index] as u32)))
+    }
+}
+
+// The blend of an output pixel for one pattern. The first case whose two neighbours are similar is used, and
+// blend when there is none
+#[derive(Clone, Copy)]
+struct HqxRule {
+    cases: [Option<([u8; 2], HqxBlend)>; 2],
+    blend: HqxBlend,
+}
+
+impl HqxRule {
+    const fn fixed(blend: HqxBlend) -> Self {
+        Self { cases: [None, None], blend }
+    }
+
+    const fn when_similar(pair: [u8; 2], similar: HqxBlend, different: HqxBlend) -> Self {
+        Self {
+            cases: [Some((pair, similar)), None],
+            blend: different,
+        }
+    }
+
+    const fn rotated(self, rotation: usize) -> Self {
+        let mut cases = self.cases;
+        let mut index = 0;
+        while index < 2 {
+            if let Some(([first, second], blend)) = cases[index] {
+                cases[index] = Some(([hqx_rotate(first, rotation), hqx_rotate(second, rotation)], blend.rotated(rotation)));
+            }
+            index += 1;
+        }
+        Self {
+            cases,
+            blend: self.blend.rotated(rotation),
+        }
+    }
+
+    fn apply(&self, neighbourhood: &[u32; 9]) -> u32 {
+        for ([first, second], blend) in self.cases.iter().flatten() {
+            if !hqx_differ(neighbourhood[*first as usize], neighbourhood[*second as usize]) {
+                return blend.apply(neighbourhood);
+            }
+        }
+        self.blend.apply(neighbourhood)
+    }
+}
+
+// Where a position of the neighbourhood, or of a 3x3 output block, ends up after rotating it clockwise by
+// rotation * 90 degrees. The rules are written for the top left of the output and rotated for the other corners
+const fn hqx_rotate(index: u8, rotation: usize) -> u8 {
+    let (mut row, mut column) = (index / 3, index % 3);
+    let mut turn = 0;
+    while turn < rotation {
+        let previous_row = row;
+        row = column;
+        column = 2 - previous_row;
+        turn += 1;
+    }
+    row * 3 + column
+}
+
+// Which neighbours set their bit in pattern, as seen after rotating the neighbourhood counterclockwise by
+// rotation * 90 degrees so that the corner being blended is the top left one
+const fn hqx_view(pattern: usize, rotation: usize) -> [bool; 9] {
+    let mut view = [false; 9];
+    let mut index = 0;
+    while index < 9 {
+        let neighbour = hqx_rotate(index as u8, rotation) as usize;
+        if neighbour != W5 as usize {
+            let bit = if neighbour < W5 as usize { neighbour } else { neighbour - 1 };
+            view[index] = pattern & (1 << bit) != 0;
+        }
+        index += 1;
+    }
+    view
+}
+
+// hq2x's rule for the top left quarter of the output
+const fn hq2x_top_left(differs: &[bool; 9]) -> HqxRule {
+    let (corner, left, up) = (differs[W1 as usize], differs[W4 as usize], differs[W2 as usize]);
+    let blend_sides = HqxBlend::new([W5, W4, W2], [2, 1, 1]);
+    // A diagonal edge that runs on past the top right or bottom left corner is shared with that quarter
+    let slope_end = HqxBlend::new([W5, W4, W2], [2, 3, 3]);
+    match (left, up) {
+        (false, false) => HqxRule::fixed(blend_sides),
+        (false, true) if corner => {
+            let side = HqxBlend::new([W5, W4, W5], [3, 1, 0]);
+            if differs[W6 as usize] && !differs[W3 as usize] && !differs[W9 as usize] {
+                HqxRule::when_similar([W2, W6], slope_end, side)
+            } else {
+                HqxRule::fixed(side)
+            }
+        }
+        (false, true) => HqxRule::fixed(HqxBlend::new([W5, W1, W4], [2, 1, 1])),
+        (true, false) if corner => {
+            let side = HqxBlend::new([W5, W2, W5], [3, 1, 0]);
+            if differs[W8 as usize] && !differs[W7 as usize] && !differs[W9 as usize] {
+                HqxRule::when_similar([W4, W8], slope_end, side)
+            } else {
+                HqxRule::fixed(side)
+            }
+        }
+        (true, false) => HqxRule::fixed(HqxBlend::new([W5, W1, W2], [2, 1, 1])),
+        (true, true) => {
+            // w4 and w2 differing from each other means they are separate edges and the corner stays sharp
+            let sharp = if corner || differs[W6 as usize] != differs[W8 as usize] {
+                HqxBlend::CENTRE
+            } else {
+                HqxBlend::new([W5, W1, W5], [3, 1, 0])
+            };
+            let smooth = match (corner, differs[W3 as usize], differs[W7 as usize]) {
+                (false, false, true) => HqxBlend::new([W5, W2, W4], [5, 2, 1]),
+                (false, true, false) => HqxBlend::new([W5, W4, W2], [5, 2, 1]),
+                (false, true, true) => HqxBlend::new([W5, W4, W2], [6, 1, 1]),
+                _ => blend_sides,
+            };
+            HqxRule::when_similar([W4, W2], smooth, sharp)
+        }
+    }
+}
+
+// hq3x's rule for the top left pixel of the output
+const fn hq3x_top_left(differs: &[bool; 9]) -> HqxRule {
+    let (corner, left, up) = (differs[W1 as usize], differs[W4 as usize], differs[W2 as usize]);
+    let blend_corner = HqxBlend::new([W5, W1, W5], [3, 1, 0]);
+    match (left, up) {
+        (false, false) => HqxRule::fixed(HqxBlend::new([W5, W4, W2], [2, 1, 1])),
+        (false, true) if corner => HqxRule::fixed(HqxBlend::new([W5, W4, W5], [3, 1, 0])),
+        (true, false) if corner => HqxRule::fixed(HqxBlend::new([W5, W2, W5], [3, 1, 0])),
+        (false, true) | (true, false) => HqxRule::fixed(blend_corner),
+        (true, true) => {
+            let sharp = if corner || differs[W6 as usize] != differs[W8 as usize] {
+                HqxBlend::CENTRE
+            } else {
+                blend_corner
+            };
+            let smooth = if corner {
+                HqxBlend::new([W5, W4, W2], [2, 1, 1])
+            } else if differs[W3 as usize] != differs[W7 as usize] {
+                // A shallow or steep edge covers the whole corner
+                HqxBlend::new([W4, W2, W5], [1, 1, 0])
+            } else {
+                HqxBlend::new([W5, W4, W2], [2, 7, 7])
+            };
+            HqxRule::when_similar([W4, W2], smooth, sharp)
+        }
+    }
+}
+
+// hq3x's rule for the top middle pixel of the output, which takes some of a diagonal edge across a top corner
+// that is rounded off, meaning the corner pixel is similar to w5
+const fn hq3x_top(differs: &[bool; 9]) -> HqxRule {
+    if !differs[W2 as usize] {
+        return HqxRule::fixed(HqxBlend::new([W5, W2, W5], [3, 1, 0]));
+    }
+    let edge = HqxBlend::new([W5, W2, W5], [7, 1, 0]);
+    // More of it when the edge is shallow and carries on past the other corner
+    let shallow_edge = HqxBlend::new([W5, W2, W5], [1, 3, 0]);
+    let (top_left, top_right) = (differs[W1 as usize], differs[W3 as usize]);
+    let left = if top_right && !differs[W7 as usize] { shallow_edge } else { edge };
+    let right = if top_left && !differs[W9 as usize] { shallow_edge } else { edge };
+    HqxRule {
+        cases: [
+            if differs[W4 as usize] && !top_left {
+                Some(([W4, W2], left))
+            } else {
+                None
+            },
+            if differs[W6 as usize] && !top_right {
+                Some(([W2, W6], right))
+            } else {
+                None
+            },
+        ],
+        blend: HqxBlend::CENTRE,
+    }
+}
+
+// Rules of the 2x2 output block in row major order for every pattern
+static HQ2X_RULES: [[HqxRule; 4]; 256] = {
+    let mut rules = [[HqxRule::fixed(HqxBlend::CENTRE); 4]; 256];
+    let mut pattern = 0;
+    while pattern < 256 {
+        let mut rotation = 0;
+        while rotation < 4 {
+            let corner = hqx_rotate(0, rotation) as usize;
+            rules[pattern][corner / 3 / 2 * 2 + corner % 3 / 2] = hq2x_top_left(&hqx_view(pattern, rotation)).rotated(rotation);
+            rotation += 1;
+        }
+        pattern += 1;
+    }
+    rules
+};
+
+// Rules of the 3x3 output block in row major order for every pattern, the centre is always w5
+static HQ3X_RULES: [[HqxRule; 9]; 256] = {
+    let mut rules = [[HqxRule::fixed(HqxBlend::CENTRE); 9]; 256];
+    let mut pattern = 0;
+    while pattern < 256 {
+        let mut rotation = 0;
+        while rotation < 4 {
+            let differs = hqx_view(pattern, rotation);
+            rules[pattern][hqx_rotate(0, rotation) as usize] = hq3x_top_left(&differs).rotated(rotation);
+            rules[pattern][hqx_rotate(1, rotation) as usize] = hq3x_top(&differs).rotated(rotation);
+            rotation += 1;
+        }
+        pattern += 1;
+    }
+    rules
+};
+
+fn hqx(input: &Image, output: &mut Image, factor: usize) {
+    for y in 0..input.height {
+        for x in 0..input.width {
+            let Neighbourhood { a, b, c, d, e, f, g, h, i } = Neighbourhood::new(input, x, y);
+            let neighbourhood = [a, b, c, d, e, f, g, h, i];
+            let pattern = [a, b, c, d, f, g, h, i]
+                .iter()
+                .enumerate()
+                .filter(|(_, neighbour)| hqx_differ(e, **neighbour))
+                .fold(0, |pattern, (bit, _)| pattern | 1 << bit);
+            let rules: &[HqxRule] = if factor == 2 { &HQ2X_RULES[pattern] } else { &HQ3X_RULES[pattern] };
+            for (index, rule) in rules.iter().enumerate() {
+                output.set(x * factor + index % factor, y * factor + index / factor, rule.apply(&neighbourhood));
+            }
+        }
+    }
+}
+
+// xBRZ, see https://sourceforge.net/projects/xbrz/
+const XBRZ_EQUAL_COLOR_TOLERANCE: f32 = 30.0;
+const XBRZ_DOMINANT_DIRECTION_THRESHOLD: f32 = 3.6;
+const XBRZ_STEEP_DIRECTION_THRESHOLD: f32 = 2.2;
+
+#[derive(PartialEq, Eq, Clone, Copy, Default)]
+enum BlendType {
+    #[default]
+    None,
+    Normal,
+    Dominant,
+}
+
+// Corners are indexed clockwise starting from the top left, which is also how a rotation by 90 degrees moves them
+const TOP_LEFT: usize = 0;
+const TOP_RIGHT: usize = 1;
+const BOTTOM_RIGHT: usize = 2;
+const BOTTOM_LEFT: usize = 3;
+
+fn xbrz_distance(first: u32, second: u32) -> f32 {
+    let [r1, g1, b1, _] = channels(first);
+    let [r2, g2, b2, _] = channels(second);
+    let (r, g, b) = (r1 - r2, g1 - g2, b1 - b2);
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let cb = 0.5 / (1.0 - 0.0722) * (b - y);
+    let cr = 0.5 / (1.0 - 0.2126) * (r - y);
+    sqrtf(y * y + cb * cb + cr * cr)
+}
+
+fn xbrz_equal(first: u32, second: u32) -> bool {
+    xbrz_distance(first, second) < XBRZ_EQUAL_COLOR_TOLERANCE
+}
+
+// Decides how the four pixels f, g, j and k of a 4x4 kernel blend into the corner they share
+// a b c d
+// e f g h
+// i j k l
+// m n o p
+// Returns the blend type for the shared corner of f, g, j and k in that order
+fn xbrz_preprocess_corners(image: &Image, x: isize, y: isize) -> [BlendType; 4] {
+    let pixel = |dx: isize, dy: isize| image.get(x + dx, y + dy);
+    let (b, c) = (pixel(0, -1), pixel(1, -1));
+    let (e, f, g, h) = (pixel(-1, 0), pixel(0, 0), pixel(1, 0), pixel(2, 0));
+    let (i, j, k, l) = (pixel(-1, 1), pixel(0, 1), pixel(1, 1), pixel(2, 1));
+    let (n, o) = (pixel(0, 2), pixel(1, 2));
+
+    let mut result = [BlendType::None; 4];
+    if (f == g && j == k) || (f == j && g == k) {
+        return result;
+    }
+
+    let distance = xbrz_distance;
+    let jg = distance(i, f) + distance(f, c) + distance(n, k) + distance(k, h) + 4.0 * distance(j, g);
+    let fk = distance(e, j) + distance(j, o) + distance(b, g) + distance(g, l) + 4.0 * distance(f, k);
+
+    let blend_type = |dominant: bool| if dominant { BlendType::Dominant } else { BlendType::Normal };
+    if jg < fk {
+        let dominant = XBRZ_DOMINANT_DIRECTION_THRESHOLD * jg < fk;
+        if f != g && f != j {
+            result[0] = blend_type(dominant);
+        }
+        if k != j && k != g {
+            result[3] = blend_type(dominant);
+        }
+    } else if fk < jg {
+        let dominant = XBRZ_DOMINANT_DIRECTION_THRESHOLD * fk < jg;
+        if j != f && j != k {
+            result[2] = blend_type(dominant);
+        }
+        if g != f && g != k {
+            result[1] = blend_type(dominant);
+        }
+    }
+    result
+}
+
+// An output block of a pixel, accessed as if it was rotated clockwise by rotation * 90 degrees so that the
+// corner being blended is always the bottom right one
+struct RotatedBlock<'a> {
+    output: &'a mut Image,
+    x: usize,
+    y: usize,
+    factor: usize,
+    rotation: usize,
+}
+
+impl RotatedBlock<'_> {
+    fn position(&self, row: usize, column: usize) -> (usize, usize) {
+        let last = self.factor - 1;
+        let (mut row, mut column) = (row, column);
+        for _ in 0..self.rotation {
+            (row, column) = (last - column, row);
+        }
+        (self.x * self.factor + column, self.y * self.factor + row)
+    }
+
+    fn blend(&mut self, row: usize, column: usize, numerator: u32, denominator: u32, color: u32) {
+        let (x, y) = self.position(row, column);
+        let pixel = self.output.pixels[y * self.output.width + x];
+        self.output
+            .set(x, y, interpolate(&[(pixel, denominator - numerator), (color, numerator)]));
+    }
+
+    fn set(&mut self, row: usize, column: usize, color: u32) {
+        let (x, y) = self.position(row, column);
+        self.output.set(x, y, color);
+    }
+
+    fn blend_line_shallow(&mut self, color: u32) {
+        let last = self.factor - 1;
+        match self.factor {
+            2 => {
+                self.blend(last, 0, 1, 4, color);
+                self.blend(last, 1, 3, 4, color);
+            }
+            3 => {
+                self.blend(last, 0, 1, 4, color);
+                self.blend(last - 1, 2, 1, 4, color);
+                self.blend(last, 1, 3, 4, color);
+                self.set(last, 2, color);
+            }
+            _ => {
+                self.blend(last, 0, 1, 4, color);
+                self.blend(last - 1, 2, 1, 4, color);
+                self.blend(last, 1, 3, 4, color);
+                self.blend(last - 1, 3, 3, 4, color);
+                self.set(last, 2, color);
+                self.set(last, 3, color);
+            }
+        }
+    }
+
+    fn blend_line_steep(&mut self, color: u32) {
+        let last = self.factor - 1;
+        match self.factor {
+            2 => {
+                self.blend(0, last, 1, 4, color);
+                self.blend(1, last, 3, 4, color);
+            }
+            3 => {
+                self.blend(0, last, 1, 4, color);
+                self.blend(2, last - 1, 1, 4, color);
+                self.blend(1, last, 3, 4, color);
+                self.set(2, last, color);
+            }
+            _ => {
+                self.blend(0, last, 1, 4, color);
+                self.blend(2, last - 1, 1, 4, color);
+                self.blend(1, last, 3, 4, color);
+                self.blend(3, last - 1, 3, 4, color);
+                self.set(2, last, color);
+                self.set(3, last, color);
+            }
+        }
+    }
+
+    fn blend_line_steep_and_shallow(&mut self, color: u32) {
+        match self.factor {
+            2 => {
+                self.blend(1, 0, 1, 4, color);
+                self.blend(0, 1, 1, 4, color);
+                self.blend(1, 1, 5, 6, color);
+            }
+            3 => {
+                self.blend(2, 0, 1, 4, color);
+                self.blend(0, 2, 1, 4, color);
+                self.blend(2, 1, 3, 4, color);
+                self.blend(1, 2, 3, 4, color);
+                self.set(2, 2, color);
+            }
+            _ => {
+                self.blend(3, 1, 3, 4, color);
+                self.blend(1, 3, 3, 4, color);
+                self.blend(3, 0, 1, 4, color);
+                self.blend(0, 3, 1, 4, color);
+                self.blend(2, 2, 1, 3, color);
+                self.set(3, 3, color);
+                self.set(3, 2, color);
+                self.set(2, 3, color);
+            }
+        }
+    }
+
+    fn blend_line_diagonal(&mut self, color: u32) {
+        match self.factor {
+            2 => self.blend(1, 1, 1, 2, color),
+            3 => {
+                self.blend(1, 2, 1, 8, color);
+                self.blend(2, 1, 1, 8, color);
+                self.blend(2, 2, 7, 8, color);
+            }
+            _ => {
+                self.blend(3, 2, 1, 2, color);
+                self.blend(2, 3, 1, 2, color);
+                self.set(3, 3, color);
+            }
+        }
+    }
+
+    fn blend_corner(&mut self, color: u32) {
+        match self.factor {
+            2 => self.blend(1, 1, 21, 100, color),
+            3 => self.blend(2, 2, 45, 100, color),
+            _ => {
+                self.blend(3, 3, 68, 100, color);
+                self.blend(3, 2, 9, 100, color);
+                self.blend(2, 3, 9, 100, color);
+            }
+        }
+    }
+}
+
+fn xbrz(input: &Image, output: &mut Image, factor: usize) {
+    // Blend types of the corners of every pixel, the kernels are evaluated with f at every pixel
+    // including the ones above and to the left of the image so that every corner is covered
+    let mut corners = vec![[BlendType::None; 4]; input.width * input.height];
+    for y in -1..input.height as isize {
+        for x in -1..input.width as isize {
+            let blend = xbrz_preprocess_corners(input, x, y);
+            let mut set_corner = |x: isize, y: isize, corner: usize, blend_type: BlendType| {
+                if x >= 0 && y >= 0 && (x as usize) < input.width && (y as usize) < input.height {
+                    corners[y as usize * input.width + x as usize][corner] = blend_type;
+                }
+            };
+            set_corner(x, y, BOTTOM_RIGHT, blend[0]);
+            set_corner(x + 1, y, BOTTOM_LEFT, blend[1]);
+            set_corner(x, y + 1, TOP_RIGHT, blend[2]);
+            set_corner(x + 1, y + 1, TOP_LEFT, blend[3]);
+        }
+    }
+
+    for y in 0..input.height {
+        for x in 0..input.width {
+            let e = input.get(x as isize, y as isize);
+            for row in 0..factor {
+                for column in 0..factor {
+                    output.set(x * factor + column, y * factor + row, e);
+                }
+            }
+
+            let pixel_corners = corners[y * input.width + x];
+            if pixel_corners.iter().all(|corner| *corner == BlendType::None) {
+                continue;
+            }
+            for rotation in 0..4 {
+                xbrz_blend_pixel(input, output, x, y, factor, rotation, &pixel_corners);
+            }
+        }
+    }
+}
+
+// Blends the corner of the pixel that ends up at the bottom right after rotating clockwise by rotation * 90 degrees
+fn xbrz_blend_pixel(input: &Image, output: &mut Image, x: usize, y: usize, factor: usize, rotation: usize, corners: &[BlendType; 4]) {
+    // Rotating the image clockwise moves the corner at index (corner + 4 - rotation) % 4 to corner
+    let corner = |corner: usize| corners[(corner + 4 - rotation) % 4];
+    if corner(BOTTOM_RIGHT) == BlendType::None {
+        return;
+    }
+
+    // The neighbourhood as seen after the rotation
+    let pixel = |row: isize, column: isize| {
+        let (mut row, mut column) = (row, column);
+        for _ in 0..rotation {
+            (row, column) = (-column, row);
+        }
+        input.get(x as isize + column, y as isize + row)
+    };
+    let (b, c) = (pixel(-1, 0), pixel(-1, 1));
+    let (d, e, f) = (pixel(0, -1), pixel(0, 0), pixel(0, 1));
+    let (g, h, i) = (pixel(1, -1), pixel(1, 0), pixel(1, 1));
+
+    let equal = xbrz_equal;
+    let do_line_blend = if corner(BOTTOM_RIGHT) == BlendType::Dominant {
+        true
+    } else if corner(TOP_RIGHT) != BlendType::None && !equal(e, g) {
+        // Blending the other corners as well would cut the pixel in half
+        false
+    } else if corner(BOTTOM_LEFT) != BlendType::None && !equal(e, c) {
+        false
+    } else {
+        // Don't blend into an L shape
+        !(!equal(e, i) && equal(g, h) && equal(h, i) && equal(i, f) && equal(f, c))
+    };
+
+    let color = if xbrz_distance(e, f) <= xbrz_distance(e, h) { f } else { h };
+    let mut block = RotatedBlock {
+        output,
+        x,
+        y,
+        factor,
+        rotation,
+    };
+
+    if do_line_blend {
+        let fg = xbrz_distance(f, g);
+        let hc = xbrz_distance(h, c);
+        let shallow_line = XBRZ_STEEP_DIRECTION_THRESHOLD * fg <= hc && e != g && d != g;
+        let steep_line = XBRZ_STEEP_DIRECTION_THRESHOLD * hc <= fg && e != c && b != c;
+        match (shallow_line, steep_line) {
+            (true, true) => block.blend_line_steep_and_shallow(color),
+            (true, false) => block.blend_line_shallow(color),
+            (false, true) => block.blend_line_steep(color),
+            (false, false) => block.blend_line_diagonal(color),
+        }
+    } else {
+        block.blend_corner(color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    // A white staircase going down to the right on a black background
+    fn staircase(size: usize) -> Vec<u8> {
+        let mut image = Vec::new();
+        for y in 0..size {
+            for x in 0..size {
+                image.extend_from_slice(if x <= y { &WHITE } else { &BLACK });
+            }
+        }
+        image
+    }
+
+    fn pixel(image: &[u8], width: usize, x: usize, y: usize) -> [u8; 4] {
+        image[(y * width + x) * 4..(y * width + x) * 4 + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_output_sizes() {
+        let image = staircase(8);
+        for mode in ScaleMode::ALL {
+            let output = scale(mode, &image, 8, 8);
+            assert_eq!(output.len(), 8 * 8 * 4 * mode.factor() * mode.factor(), "{}", mode.name());
+        }
+    }
+
+    #[test]
+    fn test_flat_image_is_unchanged() {
+        let image = [WHITE; 16].concat();
+        for mode in ScaleMode::ALL {
+            let output = scale(mode, &image, 4, 4);
+            assert!(output.chunks_exact(4).all(|pixel| pixel == WHITE), "{}", mode.name());
+        }
+    }
+
+    #[test]
+    fn test_from_name() {
+        for mode in ScaleMode::ALL {
+            assert_eq!(ScaleMode::from_name(&mode.name()), Some(mode));
+        }
+        assert_eq!(ScaleMode::from_name("XBRZ4x"), Some(ScaleMode::Xbrz(4)));
+        assert_eq!(ScaleMode::from_name("nearest3x"), Some(ScaleMode::Nearest(3)));
+        assert_eq!(ScaleMode::from_name("nearest0x"), None);
+        assert_eq!(ScaleMode::from_name("hq3x"), Some(ScaleMode::Hq3x));
+        assert_eq!(ScaleMode::from_name("hq4x"), None);
+    }
+
+    #[test]
+    fn test_nearest() {
+        let image = staircase(2);
+        let output = scale(ScaleMode::Nearest(3), &image, 2, 2);
+        assert_eq!(pixel(&output, 6, 2, 2), WHITE);
+        assert_eq!(pixel(&output, 6, 3, 2), BLACK);
+        assert_eq!(pixel(&output, 6, 5, 5), WHITE);
+    }
+
+    #[test]
+    fn test_scale2x_smooths_diagonals() {
+        let image = staircase(4);
+        let output = scale(ScaleMode::Scale2x, &image, 4, 4);
+        // The black pixel at (1, 0) gets its bottom left corner filled in by the staircase
+        assert_eq!(pixel(&output, 8, 2, 1), WHITE);
+        assert_eq!(pixel(&output, 8, 3, 0), BLACK);
+    }
+
+    #[test]
+    fn test_smoothing_scalers_blend_diagonals() {
+        let image = staircase(6);
+        for mode in [
+            ScaleMode::Scale3x,
+            ScaleMode::Hq2x,
+            ScaleMode::Hq3x,
+            ScaleMode::Xbrz(2),
+            ScaleMode::Xbrz(3),
+            ScaleMode::Xbrz(4),
+        ] {
+            let factor = mode.factor();
+            let output = scale(mode, &image, 6, 6);
+            // The bottom left corner of the black pixel at (3, 2) lies on the staircase edge
+            let corner = pixel(&output, 6 * factor, 3 * factor, 3 * factor - 1);
+            assert_ne!(corner, BLACK, "{}", mode.name());
+            // Pixels away from the edge stay untouched
+            assert_eq!(pixel(&output, 6 * factor, 5 * factor, 0), BLACK, "{}", mode.name());
+            assert_eq!(pixel(&output, 6 * factor, 0, 6 * factor - 1), WHITE, "{}", mode.name());
+        }
+    }
+
+    #[test]
+    fn test_hqx_lone_pixel() {
+        let mut image = [BLACK; 9];
+        image[4] = WHITE;
+        let image = image.concat();
+        let grey = [128, 128, 128, 255];
+
+        // Every corner of a lone pixel blends half way with the two sides around it
+        let output = scale(ScaleMode::Hq2x, &image, 3, 3);
+        for (x, y) in [(2, 2), (3, 2), (2, 3), (3, 3)] {
+            assert_eq!(pixel(&output, 6, x, y), grey);
+        }
+
+        // hq3x keeps its edges and centre
+        let output = scale(ScaleMode::Hq3x, &image, 3, 3);
+        for y in 3..6 {
+            for x in 3..6 {
+                let expected = if x != 4 && y != 4 { grey } else { WHITE };
+                assert_eq!(pixel(&output, 9, x, y), expected, "{} {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hqx_is_rotation_symmetric() {
+        // Rotates a square image clockwise by 90 degrees
+        let rotate = |image: &[u8], size: usize| {
+            let mut rotated = Vec::new();
+            for y in 0..size {
+                for x in 0..size {
+                    rotated.extend_from_slice(&pixel(image, size, y, size - 1 - x));
+                }
+            }
+            rotated
+        };
+        let colors = [BLACK, WHITE, [255, 0, 0, 255], [250, 4, 0, 255]];
+        let mut seed = 12345u32;
+        let image: Vec<u8> = (0..64)
+            .flat_map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                colors[(seed >> 16) as usize % colors.len()]
+            })
+            .collect();
+        for mode in [ScaleMode::Hq2x, ScaleMode::Hq3x] {
+            let size = 8 * mode.factor();
+            assert_eq!(
+                scale(mode, &rotate(&image, 8), 8, 8),
+                rotate(&scale(mode, &image, 8, 8), size),
+                "{}",
+                mode.name()
+            );
+        }
+    }
+}
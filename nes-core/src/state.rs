@@ -0,0 +1,305 @@
+// Save states, a snapshot of the whole machine that can be restored later.
+// Every component writes its fields in a fixed order after a header with the format version, so loading reads them back in the same order
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::region::Region;
+use crate::rom::Mirroring;
+
+const STATE_MAGIC: [u8; 4] = *b"NESS";
+// Bump this whenever a component adds, removes or reorders the fields it saves
+pub const STATE_VERSION: u16 = 2;
+pub const STATE_SLOTS: usize = 10;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    // The data doesn't start with "NESS"
+    BadMagic,
+    // The state was made by a different version of the emulator
+    UnsupportedVersion(u16),
+    Truncated,
+    // The state was made with a different game or cartridge, so a component's memory doesn't fit
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "File is not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "Save state version {} is not supported, expected {}", version, STATE_VERSION),
+            StateError::Truncated => write!(f, "Save state ends early"),
+            StateError::Mismatch(what) => write!(f, "Save state doesn't match this game ({})", what),
+        }
+    }
+}
+
+impl core::error::Error for StateError {}
+
+pub struct StateWriter {
+    pub bytes: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut writer = StateWriter { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(&STATE_MAGIC);
+        writer.write(&STATE_VERSION);
+        writer
+    }
+
+    pub fn write<T: StateValue>(&mut self, value: &T) {
+        value.write_state(self);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, StateError> {
+        if !bytes.starts_with(&STATE_MAGIC) {
+            return Err(StateError::BadMagic);
+        }
+        let mut reader = StateReader {
+            bytes,
+            position: STATE_MAGIC.len(),
+        };
+        let version: u16 = reader.read()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        Ok(reader)
+    }
+
+    pub fn read<T: StateValue>(&mut self) -> Result<T, StateError> {
+        T::read_state(self)
+    }
+
+    // Reads a list saved from memory whose size is fixed by the cartridge, such as PRG RAM
+    pub fn read_into<T: StateValue>(&mut self, values: &mut [T]) -> Result<(), StateError> {
+        let length: u32 = self.read()?;
+        if length as usize != values.len() {
+            return Err(StateError::Mismatch("memory size"));
+        }
+        for value in values {
+            *value = self.read()?;
+        }
+        Ok(())
+    }
+
+    // Every byte should have been read by the time the last component is loaded
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.position != self.bytes.len() {
+            return Err(StateError::Mismatch("state size"));
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self.bytes.get(self.position..self.position + length).ok_or(StateError::Truncated)?;
+        self.position += length;
+        Ok(bytes)
+    }
+}
+
+// Values that can be written to and read from a save state
+pub trait StateValue: Sized {
+    fn write_state(&self, state: &mut StateWriter);
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError>;
+}
+
+macro_rules! integer_state_value {
+    ($($integer:ty),*) => {
+        $(
+            impl StateValue for $integer {
+                fn write_state(&self, state: &mut StateWriter) {
+                    state.write_bytes(&self.to_le_bytes());
+                }
+
+                fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+                    let bytes = state.read_bytes(core::mem::size_of::<$integer>())?;
+                    Ok(<$integer>::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+integer_state_value!(u8, u16, u32, u64, i32, f32, f64);
+
+// Saved as 64 bits so states are the same on every platform
+impl StateValue for usize {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.write(&(*self as u64));
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let value: u64 = state.read()?;
+        usize::try_from(value).map_err(|_| StateError::Mismatch("value out of range"))
+    }
+}
+
+impl StateValue for bool {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.write(&(*self as u8));
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        Ok(state.read::<u8>()? != 0)
+    }
+}
+
+impl<T: StateValue> StateValue for Option<T> {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.write(&self.is_some());
+        if let Some(value) = self {
+            state.write(value);
+        }
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        if state.read::<bool>()? {
+            Ok(Some(state.read()?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: StateValue + Default + Copy, const N: usize> StateValue for [T; N] {
+    fn write_state(&self, state: &mut StateWriter) {
+        for value in self {
+            state.write(value);
+        }
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let mut values = [T::default(); N];
+        for value in &mut values {
+            *value = state.read()?;
+        }
+        Ok(values)
+    }
+}
+
+// Lists are prefixed with their length
+impl<T: StateValue> StateValue for Vec<T> {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.write(&(self.len() as u32));
+        for value in self {
+            state.write(value);
+        }
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let length: u32 = state.read()?;
+        (0..length).map(|_| state.read()).collect()
+    }
+}
+
+impl StateValue for Region {
+    fn write_state(&self, state: &mut StateWriter) {
+        state.write(&(Region::ALL.iter().position(|region| region == self).unwrap() as u8));
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let index: u8 = state.read()?;
+        Region::ALL.get(index as usize).copied().ok_or(StateError::Mismatch("region"))
+    }
+}
+
+impl StateValue for Mirroring {
+    fn write_state(&self, state: &mut StateWriter) {
+        let (index, pages) = match self {
+            Mirroring::Horizontal => (0, [0; 4]),
+            Mirroring::Vertical => (1, [0; 4]),
+            Mirroring::SingleScreenLower => (2, [0; 4]),
+            Mirroring::SingleScreenUpper => (3, [0; 4]),
+            Mirroring::FourScreen => (4, [0; 4]),
+            Mirroring::Custom(pages) => (5, *pages),
+        };
+        state.write(&(index as u8));
+        state.write(&pages);
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let index: u8 = state.read()?;
+        let pages: [u8; 4] = state.read()?;
+        match index {
+            0 => Ok(Mirroring::Horizontal),
+            1 => Ok(Mirroring::Vertical),
+            2 => Ok(Mirroring::SingleScreenLower),
+            3 => Ok(Mirroring::SingleScreenUpper),
+            4 => Ok(Mirroring::FourScreen),
+            5 => Ok(Mirroring::Custom(pages)),
+            _ => Err(StateError::Mismatch("mirroring")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_value_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write(&0x12u8);
+        writer.write(&0x1234u16);
+        writer.write(&-5i32);
+        writer.write(&1.5f32);
+        writer.write(&usize::MAX);
+        writer.write(&true);
+        writer.write(&Some(7u8));
+        writer.write(&None::<u8>);
+        writer.write(&[1u16, 2, 3]);
+        writer.write(&vec![4u8, 5]);
+        writer.write(&Region::Dendy);
+        writer.write(&Mirroring::Custom([3, 2, 1, 0]));
+
+        let mut reader = StateReader::new(&writer.bytes).unwrap();
+        assert_eq!(reader.read::<u8>(), Ok(0x12));
+        assert_eq!(reader.read::<u16>(), Ok(0x1234));
+        assert_eq!(reader.read::<i32>(), Ok(-5));
+        assert_eq!(reader.read::<f32>(), Ok(1.5));
+        assert_eq!(reader.read::<usize>(), Ok(usize::MAX));
+        assert_eq!(reader.read::<bool>(), Ok(true));
+        assert_eq!(reader.read::<Option<u8>>(), Ok(Some(7)));
+        assert_eq!(reader.read::<Option<u8>>(), Ok(None));
+        assert_eq!(reader.read::<[u16; 3]>(), Ok([1, 2, 3]));
+        let mut memory = [0u8; 2];
+        assert_eq!(reader.read_into(&mut memory), Ok(()));
+        assert_eq!(memory, [4, 5]);
+        assert_eq!(reader.read::<Region>(), Ok(Region::Dendy));
+        assert_eq!(reader.read::<Mirroring>(), Ok(Mirroring::Custom([3, 2, 1, 0])));
+        assert_eq!(reader.finish(), Ok(()));
+        assert_eq!(reader.read::<u8>(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn test_header() {
+        assert_eq!(StateReader::new(b"NES\x1A").err(), Some(StateError::BadMagic));
+        assert_eq!(StateReader::new(b"NESS").err(), Some(StateError::Truncated));
+        let mut bytes = StateWriter::new().bytes;
+        bytes[4] = bytes[4].wrapping_add(1);
+        assert_eq!(StateReader::new(&bytes).err(), Some(StateError::UnsupportedVersion(STATE_VERSION + 1)));
+
+        let mut writer = StateWriter::new();
+        writer.write(&vec![0u8; 3]);
+        let mut reader = StateReader::new(&writer.bytes).unwrap();
+        assert_eq!(reader.read_into(&mut [0u8; 4]), Err(StateError::Mismatch("memory size")));
+    }
+}
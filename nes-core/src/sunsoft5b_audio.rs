@@ -0,0 +1,268 @@
+// The Sunsoft 5B's expansion audio, a YM2149F with three square wave channels, a noise generator and an envelope.
+// See https://www.nesdev.org/wiki/Sunsoft_5B_audio
+
+use libm::powf;
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+// The tone, noise and envelope generators are clocked every 16 cpu cycles
+const CYCLES_PER_TICK: u8 = 16;
+const ENVELOPE_STEPS: u8 = 32;
+// A channel at full volume is about as loud as a 2A03 pulse at full volume
+const CHANNEL_LEVEL: f32 = 0.15;
+
+#[derive(Default, Clone)]
+struct Tone {
+    period: u16,
+    counter: u16,
+    high: bool,
+}
+
+#[derive(Clone)]
+pub struct Sunsoft5bAudio {
+    registers: [u8; 16],
+    selected_register: u8,
+    tick_counter: u8,
+    tones: [Tone; 3],
+    noise_counter: u16,
+    noise_shift_register: u32,
+    envelope_counter: u32,
+    envelope_step: u8,
+    envelope_attacking: bool,
+    envelope_holding: bool,
+    // The output levels of the 5 bit volumes, which go up 1.5 dB per step
+    volume_levels: [f32; 32],
+}
+
+impl Default for Sunsoft5bAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Self {
+        let mut volume_levels = [0.0; 32];
+        for (volume, level) in volume_levels.iter_mut().enumerate().skip(1) {
+            *level = powf(10.0, (volume as f32 - 31.0) * 1.5 / 20.0);
+        }
+        Sunsoft5bAudio {
+            registers: [0; 16],
+            selected_register: 0,
+            tick_counter: 0,
+            tones: Default::default(),
+            noise_counter: 0,
+            noise_shift_register: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attacking: false,
+            envelope_holding: true,
+            volume_levels,
+        }
+    }
+
+    // $C000 selects a register and $E000 writes to it
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address & 0xE000 {
+            0xC000 => self.selected_register = value & 0x0F,
+            0xE000 => {
+                let register = self.selected_register as usize;
+                self.registers[register] = value;
+                match register {
+                    0..=5 => {
+                        let channel = register / 2;
+                        self.tones[channel].period = u16::from_le_bytes([self.registers[channel * 2], self.registers[channel * 2 + 1] & 0x0F]);
+                    }
+                    // Writing the envelope shape restarts the envelope
+                    13 => {
+                        self.envelope_step = 0;
+                        self.envelope_counter = 0;
+                        self.envelope_attacking = value & 0x04 != 0;
+                        self.envelope_holding = false;
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Runs the chip for one cpu cycle
+    pub fn clock(&mut self) {
+        self.tick_counter += 1;
+        if self.tick_counter < CYCLES_PER_TICK {
+            return;
+        }
+        self.tick_counter = 0;
+
+        for tone in &mut self.tones {
+            tone.counter += 1;
+            if tone.counter >= tone.period.max(1) {
+                tone.counter = 0;
+                tone.high = !tone.high;
+            }
+        }
+
+        // The noise generator runs at half the rate of the tone generators
+        self.noise_counter += 1;
+        if self.noise_counter >= (self.registers[6] as u16 & 0x1F).max(1) * 2 {
+            self.noise_counter = 0;
+            let feedback = (self.noise_shift_register ^ (self.noise_shift_register >> 3)) & 1;
+            self.noise_shift_register = (self.noise_shift_register >> 1) | (feedback << 16);
+        }
+
+        self.envelope_counter += 1;
+        let envelope_period = u16::from_le_bytes([self.registers[11], self.registers[12]]).max(1) as u32;
+        if self.envelope_counter >= envelope_period {
+            self.envelope_counter = 0;
+            self.clock_envelope();
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step < ENVELOPE_STEPS - 1 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        let shape = self.registers[13];
+        let continuing = shape & 0x08 != 0;
+        let alternating = shape & 0x02 != 0;
+        let holding = shape & 0x01 != 0;
+        if !continuing {
+            // Stops at silence no matter which direction it went
+            self.envelope_attacking = false;
+            self.envelope_holding = true;
+        } else {
+            if alternating {
+                self.envelope_attacking = !self.envelope_attacking;
+            }
+            if holding {
+                self.envelope_holding = true;
+            } else {
+                self.envelope_step = 0;
+            }
+        }
+    }
+
+    fn envelope_volume(&self) -> usize {
+        if self.envelope_attacking {
+            self.envelope_step as usize
+        } else {
+            (ENVELOPE_STEPS - 1 - self.envelope_step) as usize
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[7];
+        let noise_high = self.noise_shift_register & 1 != 0;
+        let mut output = 0.0;
+        for (channel, tone) in self.tones.iter().enumerate() {
+            let tone_disabled = mixer & (1 << channel) != 0;
+            let noise_disabled = mixer & (8 << channel) != 0;
+            if !(tone.high || tone_disabled) || !(noise_high || noise_disabled) {
+                continue;
+            }
+            let volume = self.registers[8 + channel];
+            let level = if volume & 0x10 != 0 {
+                self.volume_levels[self.envelope_volume()]
+            } else if volume & 0x0F == 0 {
+                0.0
+            } else {
+                // The 4 bit volumes go up 3 dB per step
+                self.volume_levels[(volume as usize & 0x0F) * 2 + 1]
+            };
+            output += level;
+        }
+        output * CHANNEL_LEVEL
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.registers);
+        state.write(&self.selected_register);
+        state.write(&self.tick_counter);
+        for tone in &self.tones {
+            state.write(&tone.period);
+            state.write(&tone.counter);
+            state.write(&tone.high);
+        }
+        state.write(&self.noise_counter);
+        state.write(&self.noise_shift_register);
+        state.write(&self.envelope_counter);
+        state.write(&self.envelope_step);
+        state.write(&self.envelope_attacking);
+        state.write(&self.envelope_holding);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers = state.read()?;
+        self.selected_register = state.read::<u8>()? & 0x0F;
+        self.tick_counter = state.read()?;
+        for tone in &mut self.tones {
+            tone.period = state.read()?;
+            tone.counter = state.read()?;
+            tone.high = state.read()?;
+        }
+        self.noise_counter = state.read()?;
+        self.noise_shift_register = state.read()?;
+        self.envelope_counter = state.read()?;
+        self.envelope_step = state.read::<u8>()? % ENVELOPE_STEPS;
+        self.envelope_attacking = state.read()?;
+        self.envelope_holding = state.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn write_register(audio: &mut Sunsoft5bAudio, register: u8, value: u8) {
+        audio.write(0xC000, register);
+        audio.write(0xE000, value);
+    }
+
+    #[test]
+    fn test_square_wave() {
+        let mut audio = Sunsoft5bAudio::new();
+        write_register(&mut audio, 0, 4);
+        write_register(&mut audio, 1, 0);
+        // Channel A's tone on and noise off, at full volume
+        write_register(&mut audio, 7, 0b11_1110);
+        write_register(&mut audio, 8, 0x0F);
+
+        let outputs: Vec<f32> = (0..16 * 8)
+            .map(|_| {
+                audio.clock();
+                audio.output()
+            })
+            .collect();
+        // The tone toggles every 4 ticks of 16 cycles
+        assert_eq!(outputs.iter().filter(|output| **output == CHANNEL_LEVEL).count(), 16 * 4);
+        assert_eq!(outputs.iter().filter(|output| **output == 0.0).count(), 16 * 4);
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let mut audio = Sunsoft5bAudio::new();
+        write_register(&mut audio, 11, 1);
+        // Attack then hold at the top
+        write_register(&mut audio, 13, 0b1101);
+        for _ in 0..16 * 40 {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_volume(), 31);
+
+        // Decay once then stay silent
+        write_register(&mut audio, 13, 0b0000);
+        assert_eq!(audio.envelope_volume(), 31);
+        for _ in 0..16 * 40 {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_volume(), 0);
+    }
+}
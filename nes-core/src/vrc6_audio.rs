@@ -0,0 +1,259 @@
+// Konami's VRC6 expansion audio, two pulse channels with 8 duty cycles and a sawtooth channel.
+// See https://www.nesdev.org/wiki/VRC6_audio
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+// A VRC6 pulse at full volume is about as loud as a 2A03 pulse at full volume
+const OUTPUT_LEVEL_PER_STEP: f32 = 0.15 / 15.0;
+
+#[derive(Default, Clone)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    // Ignores the duty and outputs the volume constantly
+    digitized: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.digitized = value & 0x80 != 0;
+                self.duty = (value >> 4) & 0b111;
+                self.volume = value & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.checked_sub(1).unwrap_or(15);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.volume);
+        state.write(&self.duty);
+        state.write(&self.digitized);
+        state.write(&self.enabled);
+        state.write(&self.period);
+        state.write(&self.timer);
+        state.write(&self.step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.volume = state.read()?;
+        self.duty = state.read()?;
+        self.digitized = state.read()?;
+        self.enabled = state.read()?;
+        self.period = state.read()?;
+        self.timer = state.read()?;
+        self.step = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Default, Clone)]
+struct Vrc6Saw {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    // The accumulator is added to on every other timer clock and reset after 7 additions
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    // The top 5 bits of the accumulator
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write(&self.rate);
+        state.write(&self.enabled);
+        state.write(&self.period);
+        state.write(&self.timer);
+        state.write(&self.step);
+        state.write(&self.accumulator);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rate = state.read()?;
+        self.enabled = state.read()?;
+        self.period = state.read()?;
+        self.timer = state.read()?;
+        self.step = state.read()?;
+        self.accumulator = state.read()?;
+        Ok(())
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Vrc6Audio {
+    pulses: [Vrc6Pulse; 2],
+    saw: Vrc6Saw,
+    halted: bool,
+    // $9003 can speed all channels up by 16 or 256 times
+    frequency_shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // $9000-$9003, $A000-$A002 and $B000-$B002
+    pub fn write(&mut self, address: u16, value: u8) {
+        let register = address & 0b11;
+        match address & 0xF000 {
+            0x9000 if register == 3 => {
+                self.halted = value & 0x01 != 0;
+                self.frequency_shift = if value & 0x04 != 0 {
+                    8
+                } else if value & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000 => self.pulses[0].write(register, value),
+            0xA000 if register < 3 => self.pulses[1].write(register, value),
+            0xB000 if register < 3 => self.saw.write(register, value),
+            _ => {}
+        }
+    }
+
+    // Runs the channels for one cpu cycle
+    pub fn clock(&mut self) {
+        if self.halted {
+            return;
+        }
+        for pulse in &mut self.pulses {
+            pulse.clock(self.frequency_shift);
+        }
+        self.saw.clock(self.frequency_shift);
+    }
+
+    pub fn output(&self) -> f32 {
+        (self.pulses[0].output() + self.pulses[1].output() + self.saw.output()) as f32 * OUTPUT_LEVEL_PER_STEP
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for pulse in &self.pulses {
+            pulse.save_state(state);
+        }
+        self.saw.save_state(state);
+        state.write(&self.halted);
+        state.write(&self.frequency_shift);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for pulse in &mut self.pulses {
+            pulse.load_state(state)?;
+        }
+        self.saw.load_state(state)?;
+        self.halted = state.read()?;
+        self.frequency_shift = state.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut audio = Vrc6Audio::new();
+        // Duty 7 is a 50% square, 8 of the 16 steps are high
+        audio.write(0x9000, 0x7F);
+        audio.write(0x9001, 9);
+        audio.write(0x9002, 0x80);
+        let mut high_cycles = 0;
+        for _ in 0..16 * 10 {
+            audio.clock();
+            if audio.pulses[0].output() == 15 {
+                high_cycles += 1;
+            }
+        }
+        assert_eq!(high_cycles, 8 * 10);
+    }
+
+    #[test]
+    fn test_saw_ramp() {
+        let mut audio = Vrc6Audio::new();
+        audio.write(0xB000, 42);
+        audio.write(0xB001, 0);
+        audio.write(0xB002, 0x80);
+        let mut outputs = Vec::new();
+        for _ in 0..14 {
+            audio.clock();
+            outputs.push(audio.saw.output());
+        }
+        // 6 additions of 42 reach 252, then the 7th step resets the accumulator
+        assert_eq!(outputs.iter().max(), Some(&(252 >> 3)));
+        assert_eq!(outputs.last(), Some(&0));
+    }
+}
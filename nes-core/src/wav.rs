@@ -0,0 +1,48 @@
+// Writes audio to uncompressed 16 bit mono WAV files.
+// See http://soundfile.sapp.org/doc/WaveFormat/
+use alloc::vec::Vec;
+
+const HEADER_SIZE: usize = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+// Samples are clamped to -1.0 to 1.0
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_size = samples.len() * 2;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + data_size);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&((HEADER_SIZE - 8 + data_size) as u32).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    // PCM with one channel
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    bytes.extend_from_slice(&2u16.to_le_bytes());
+    bytes.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&(data_size as u32).to_le_bytes());
+    for sample in samples {
+        bytes.extend_from_slice(&((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_wav() {
+        let bytes = encode_wav(&[0.0, 1.0, -2.0], 44100);
+        assert_eq!(bytes.len(), HEADER_SIZE + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 42);
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 6);
+        assert_eq!(&bytes[44..], [0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
@@ -0,0 +1,33 @@
+// The core is no_std + alloc so it can run on microcontrollers, this builds it for a Cortex-M4F to keep it that way.
+// It needs the target installed with `rustup target add thumbv7em-none-eabihf`, so it's ignored by default and CI runs it
+// with `cargo test -p nes-core --test embedded_build -- --ignored`.
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+const EMBEDDED_TARGET: &str = "thumbv7em-none-eabihf";
+
+#[test]
+#[ignore = "needs the thumbv7em-none-eabihf target installed"]
+fn test_builds_for_thumbv7em() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let sysroot = Command::new(rustc).args(["--print", "sysroot"]).output().expect("rustc should run");
+    let sysroot = String::from_utf8_lossy(&sysroot.stdout).trim().to_owned();
+    assert!(
+        Path::new(&sysroot).join("lib/rustlib").join(EMBEDDED_TARGET).exists(),
+        "The {} target isn't installed, add it with: rustup target add {}",
+        EMBEDDED_TARGET,
+        EMBEDDED_TARGET
+    );
+
+    // A target directory of its own so it doesn't wait on the lock held by the cargo running the tests
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(EMBEDDED_TARGET);
+    let output = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--target", EMBEDDED_TARGET, "--manifest-path"])
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(target_dir)
+        .output()
+        .expect("cargo should run");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
@@ -0,0 +1,77 @@
+# Builds input_test.nes, an NROM rom for the golden frame hashes that draws something this ppu can show and reacts to input:
+# the palette colours change every 4 frames, A turns on red emphasis and B turns on greyscale.
+# Run from this directory with: python3 make_input_test.py
+
+program = []
+labels = {}
+fixups = []
+
+
+def emit(*values):
+    program.extend(values)
+
+
+def label(name):
+    labels[name] = 0x8000 + len(program)
+
+
+# Instructions that take a label, absolute for JMP and relative for branches
+def absolute(opcode, name):
+    emit(opcode, 0, 0)
+    fixups.append((len(program) - 2, name, False))
+
+
+def branch(opcode, name):
+    emit(opcode, 0)
+    fixups.append((len(program) - 1, name, True))
+
+
+def read_button():
+    emit(0xAD, 0x16, 0x40)  # LDA $4016
+    emit(0x29, 0x01)  # AND #$01
+
+
+label("reset")
+emit(0x78, 0xD8)  # SEI, CLD
+emit(0xA2, 0xFF, 0x9A)  # LDX #$FF, TXS
+emit(0xA9, 0x00, 0x85, 0x00)  # LDA #$00, STA $00
+
+label("frame")
+emit(0x2C, 0x02, 0x20)  # BIT $2002
+branch(0x10, "frame")  # BPL frame
+emit(0xE6, 0x00)  # INC $00
+# Every palette entry is set from the frame counter / 4, so whichever colours end up drawn change over time
+emit(0xA9, 0x3F, 0x8D, 0x06, 0x20)  # LDA #$3F, STA $2006
+emit(0xA9, 0x00, 0x8D, 0x06, 0x20)  # LDA #$00, STA $2006
+emit(0xA5, 0x00, 0x4A, 0x4A)  # LDA $00, LSR A, LSR A
+emit(0xA2, 0x20)  # LDX #$20
+label("palette")
+emit(0x29, 0x3F, 0x8D, 0x07, 0x20)  # AND #$3F, STA $2007
+emit(0x18, 0x69, 0x05)  # CLC, ADC #$05
+emit(0xCA)  # DEX
+branch(0xD0, "palette")  # BNE palette
+# A sets the red emphasis bit and B the greyscale bit of $2001, along with showing the background
+emit(0xA9, 0x01, 0x8D, 0x16, 0x40)  # LDA #$01, STA $4016
+emit(0xA9, 0x00, 0x8D, 0x16, 0x40)  # LDA #$00, STA $4016
+read_button()
+emit(0x0A, 0x0A, 0x0A, 0x0A, 0x0A)  # ASL A x5
+emit(0x85, 0x01)  # STA $01
+read_button()
+emit(0x05, 0x01)  # ORA $01
+emit(0x09, 0x08)  # ORA #$08
+emit(0x8D, 0x01, 0x20)  # STA $2001
+absolute(0x4C, "frame")  # JMP frame
+
+for offset, name, relative in fixups:
+    target = labels[name]
+    if relative:
+        program[offset] = (target - (0x8000 + offset + 1)) & 0xFF
+    else:
+        program[offset : offset + 2] = [target & 0xFF, target >> 8]
+
+prg_rom = bytearray(program) + bytearray(0x4000 - len(program))
+prg_rom[0x3FFC:0x3FFE] = labels["reset"].to_bytes(2, "little")
+chr_rom = bytes((i * 3) & 0xFF for i in range(0x2000))
+header = b"NES\x1a\x01\x01" + bytes(10)
+with open("input_test.nes", "wb") as rom:
+    rom.write(header + prg_rom + chr_rom)
@@ -0,0 +1,807 @@
+use egui_macroquad::egui::{self, vec2, Color32, ColorImage, Context, Painter, TextureId};
+use egui_macroquad::macroquad;
+use egui_macroquad::macroquad::audio::{load_sound_from_bytes, play_sound_once, stop_sound, Sound};
+use egui_macroquad::macroquad::color::{BLACK, WHITE};
+use egui_macroquad::macroquad::input::{is_key_down, is_key_pressed, is_quit_requested, prevent_quit, KeyCode};
+use egui_macroquad::macroquad::texture::{draw_texture_ex, DrawTextureParams, Texture2D};
+use egui_macroquad::macroquad::time::get_time;
+use egui_macroquad::macroquad::window::{clear_background, next_frame, Conf};
+use nes_core::controller::Buttons;
+use nes_core::database::GameInfo;
+use nes_core::fds::{self, DiskDrive};
+use nes_core::filter::{PaletteFilter, VideoFilter};
+use nes_core::frame::Frame;
+use nes_core::movie::{Movie, MovieFormat, MovieMode, MovieSession, RomIdentity};
+use nes_core::nes::Nes;
+use nes_core::nsf::{self, ExpansionChips, Nsf, NsfPlayer};
+use nes_core::ntsc::NtscFilter;
+use nes_core::palette::{BuiltinPalette, Palette};
+use nes_core::patch::{self, PATCH_EXTENSIONS};
+use nes_core::ppu::{ControlFlags, Ppu};
+use nes_core::region::Region;
+use nes_core::rewind::RewindBuffer;
+use nes_core::rom::Rom;
+use nes_core::scaler::{ScaleFilter, ScaleMode};
+use nes_core::state::STATE_SLOTS;
+use nes_core::{archive, wav};
+use save::SaveFile;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod save;
+
+const WINDOW_SCALE: usize = 4;
+// How often battery backed save RAM is written to disk if it changed, about every 5 seconds
+const SAVE_FLUSH_INTERVAL_FRAMES: u32 = 300;
+const FDS_BIOS_FILE_NAME: &str = "disksys.rom";
+// Number keys pick a save state slot, F5 saves to it and F7 loads it
+const STATE_SLOT_KEYS: [KeyCode; STATE_SLOTS] = [
+    KeyCode::Key0,
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+];
+const SAVE_STATE_KEY: KeyCode = KeyCode::F5;
+const LOAD_STATE_KEY: KeyCode = KeyCode::F7;
+// Holding backspace steps back through the rewind buffer
+const REWIND_KEY: KeyCode = KeyCode::Backspace;
+const DEFAULT_REWIND_MEMORY_MIB: usize = 64;
+const DEFAULT_FRAMES_PER_REWIND_SNAPSHOT: u32 = 2;
+// The keyboard plays the first controller
+const CONTROLLER_KEYS: [(KeyCode, Buttons); 8] = [
+    (KeyCode::X, Buttons::A),
+    (KeyCode::Z, Buttons::B),
+    (KeyCode::RightShift, Buttons::Select),
+    (KeyCode::Enter, Buttons::Start),
+    (KeyCode::Up, Buttons::Up),
+    (KeyCode::Down, Buttons::Down),
+    (KeyCode::Left, Buttons::Left),
+    (KeyCode::Right, Buttons::Right),
+];
+// macroquad mixes at 44100 Hz
+const NSF_SAMPLE_RATE: u32 = 44100;
+// How long each frame spends rendering a track so the window stays responsive
+const NSF_RENDER_SECONDS_PER_FRAME: f64 = 0.01;
+const NSF_RENDER_CHUNK_SAMPLES: usize = 1024;
+
+fn window_conf() -> Conf {
+    Conf {
+        window_title: "Nes Emulator".to_owned(),
+        fullscreen: false,
+        window_width: 256 * WINDOW_SCALE as i32,
+        window_height: 240 * WINDOW_SCALE as i32,
+        ..Default::default()
+    }
+}
+
+fn main() {
+    // Access the command-line arguments
+    let args: Vec<String> = env::args().collect();
+
+    // Positional arguments are the rom and an optional palette, a patch can be passed with --patch
+    // and the Famicom Disk System BIOS with --fds-bios. NSF files take a track and can be rendered to a WAV file instead.
+    // Movies are recorded from power on, or from a save state slot with --from-slot.
+    // nes-headless runs roms without a window
+    let mut paths = Vec::new();
+    let mut patch_path = None;
+    let mut fds_bios_path = None;
+    let mut rewind_memory_mib = DEFAULT_REWIND_MEMORY_MIB;
+    let mut track = None;
+    let mut wav_path = None;
+    let mut seconds = None;
+    let mut record_path = None;
+    let mut play_path = None;
+    let mut from_slot = None;
+    let mut arguments = args.iter().skip(1);
+    while let Some(argument) = arguments.next() {
+        if argument == "--patch" {
+            patch_path = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--fds-bios" {
+            fds_bios_path = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--rewind-memory" {
+            rewind_memory_mib = arguments
+                .next()
+                .and_then(|mib| mib.parse::<usize>().ok())
+                .unwrap_or_else(|| exit_with_usage(&args[0]));
+        } else if argument == "--track" {
+            track = Some(
+                arguments
+                    .next()
+                    .and_then(|track| track.parse::<usize>().ok())
+                    .unwrap_or_else(|| exit_with_usage(&args[0])),
+            );
+        } else if argument == "--wav" {
+            wav_path = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--seconds" {
+            seconds = Some(
+                arguments
+                    .next()
+                    .and_then(|seconds| seconds.parse::<u32>().ok())
+                    .unwrap_or_else(|| exit_with_usage(&args[0])),
+            );
+        } else if argument == "--record" {
+            record_path = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--play" {
+            play_path = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--from-slot" {
+            from_slot = Some(
+                arguments
+                    .next()
+                    .and_then(|slot| slot.parse::<usize>().ok())
+                    .filter(|slot| *slot < STATE_SLOTS)
+                    .unwrap_or_else(|| exit_with_usage(&args[0])),
+            );
+        } else {
+            paths.push(argument);
+        }
+    }
+    if paths.is_empty() || paths.len() > 2 {
+        exit_with_usage(&args[0]);
+    }
+
+    // Get the file path from the command-line argument
+    let file_path = paths[0];
+
+    let bytes = read_file(file_path).unwrap_or_else(|error| exit_with_error(file_path, error));
+    let mut bytes = archive::unpack_rom(bytes).unwrap_or_else(|error| exit_with_error(file_path, error));
+    if let Some(patch_path) = patch_path.or_else(|| find_patch(Path::new(file_path))) {
+        let patch = read_file(&patch_path).unwrap_or_else(|error| exit_with_error(&patch_path, error));
+        bytes = patch::apply_patch(&bytes, &patch).unwrap_or_else(|error| exit_with_error(&patch_path, error));
+        eprintln!("Applied patch {}", patch_path);
+    }
+
+    if nsf::is_nsf(&bytes) {
+        let mut nsf = Nsf::new(&bytes).unwrap_or_else(|error| exit_with_error(file_path, error));
+        let unsupported_chips = nsf.expansion_chips & ExpansionChips::UNSUPPORTED;
+        if !unsupported_chips.is_empty() {
+            eprintln!(
+                "Warning: {} expansion audio is not supported and will be silent",
+                unsupported_chips.names().join(", ")
+            );
+        }
+        if let Some(seconds) = seconds {
+            for info in &mut nsf.tracks {
+                info.length_ms = Some(seconds * 1000);
+            }
+        }
+        let mut player = NsfPlayer::new(nsf, NSF_SAMPLE_RATE);
+        if let Some(track) = track {
+            if track == 0 || track > player.nsf.track_count {
+                exit_with_error(
+                    file_path,
+                    format!("Track {} doesn't exist, the file has {} tracks", track, player.nsf.track_count),
+                );
+            }
+            player.select_track(track - 1);
+        }
+        match wav_path {
+            Some(wav_path) => render_nsf_to_wav(&mut player, &wav_path),
+            None => macroquad::Window::from_config(window_conf(), run_nsf_player(player)),
+        }
+        return;
+    }
+    if wav_path.is_some() {
+        exit_with_error(file_path, "only NSF files can be rendered to WAV files");
+    }
+
+    let rom = if fds::is_fds_image(&bytes) {
+        // The BIOS can't be distributed with the emulator, by default it's looked for next to the disk image
+        let bios_path = fds_bios_path.unwrap_or_else(|| Path::new(file_path).with_file_name(FDS_BIOS_FILE_NAME).to_string_lossy().into_owned());
+        let bios = read_file(&bios_path)
+            .unwrap_or_else(|error| exit_with_error(&bios_path, format!("{}, the FDS BIOS can be given with --fds-bios <disksys.rom>", error)));
+        Rom::from_fds(&bytes, &bios).unwrap_or_else(|error| exit_with_error(file_path, error))
+    } else {
+        Rom::new(&bytes).unwrap_or_else(|error| exit_with_error(file_path, error))
+    };
+    if rom.dirty_header {
+        eprintln!("Warning: {} has garbage in its header, bytes 7-15 were ignored", file_path);
+    }
+    let game_info = rom.game_info.clone();
+    let unif_board = rom.board.clone();
+    let rom_name = Path::new(file_path)
+        .file_stem()
+        .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+    let rom_identity = RomIdentity::new(&rom_name, &rom);
+    let mut nes = Nes::new(rom).unwrap_or_else(|error| exit_with_error(file_path, error));
+
+    let movie = match (record_path, play_path) {
+        (Some(_), Some(_)) => exit_with_usage(&args[0]),
+        (Some(path), None) => {
+            if movie_format(&path).is_none() {
+                exit_with_error(&path, "movies are recorded as .fm2 or .bk2 files");
+            }
+            let start_state = from_slot.map(|slot| {
+                let state_path = save::state_slot_path(Path::new(file_path), slot).to_string_lossy().into_owned();
+                let state = read_file(&state_path).unwrap_or_else(|error| exit_with_error(&state_path, error));
+                nes.load_state(&state).unwrap_or_else(|error| exit_with_error(&state_path, error));
+                state
+            });
+            // The current time keeps the guids of different recordings apart
+            let guid_seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+            let session = MovieSession::record(Movie::new(&rom_identity, nes.region(), start_state, guid_seed), &nes);
+            Some(MovieFile {
+                session,
+                path,
+                unsaved: true,
+            })
+        }
+        (None, Some(path)) => {
+            if from_slot.is_some() {
+                exit_with_usage(&args[0]);
+            }
+            let bytes = read_file(&path).unwrap_or_else(|error| exit_with_error(&path, error));
+            let movie = Movie::load(&bytes).unwrap_or_else(|error| exit_with_error(&path, error));
+            if !movie.matches_rom(&rom_identity) {
+                eprintln!("Warning: {} was recorded with a different rom and will probably desync", path);
+            }
+            let session = MovieSession::play(movie, &mut nes).unwrap_or_else(|error| exit_with_error(&path, error));
+            Some(MovieFile {
+                session,
+                path,
+                unsaved: false,
+            })
+        }
+        (None, None) if from_slot.is_some() => exit_with_usage(&args[0]),
+        (None, None) => None,
+    };
+
+    let palette_path = paths.get(1).map(|palette_path| palette_path.to_string());
+    let rewind = RewindBuffer::new(DEFAULT_FRAMES_PER_REWIND_SNAPSHOT, rewind_memory_mib * 1024 * 1024);
+    macroquad::Window::from_config(
+        window_conf(),
+        run_emulator(file_path.clone(), nes, game_info, unif_board, palette_path, rewind, movie),
+    );
+}
+
+// A movie from --record or --play, recordings are written out when stopped or when the window closes
+struct MovieFile {
+    session: MovieSession,
+    path: String,
+    unsaved: bool,
+}
+
+async fn run_emulator(
+    file_path: String,
+    mut nes: Nes,
+    game_info: Option<GameInfo>,
+    unif_board: Option<String>,
+    palette_path: Option<String>,
+    mut rewind: RewindBuffer,
+    mut movie: Option<MovieFile>,
+) {
+    // Movies start with blank cartridge RAM so they play back the same everywhere, and leave the save file alone
+    let mut save_file = movie.is_none().then(|| SaveFile::new(Path::new(&file_path)));
+    if let Some(save_file) = &mut save_file {
+        if let Err(error) = save_file.load(&mut nes) {
+            eprintln!("Warning: failed to load {}: {}", save_file.path.display(), error);
+        }
+    }
+
+    // None means the palette was loaded from a file
+    let default_palette = BuiltinPalette::for_region(nes.region());
+    let mut selected_palette = Some(default_palette);
+    let mut scale_filter = ScaleFilter::new(PaletteFilter::new(default_palette.palette()), ScaleMode::Nearest(1));
+    if let Some(palette_path) = palette_path {
+        let bytes = read_file(&palette_path).unwrap_or_else(|error| exit_with_error(&palette_path, error));
+        let palette = Palette::from_pal_bytes(&bytes).unwrap_or_else(|error| {
+            eprintln!("Failed to load palette: {}", error);
+            std::process::exit(1);
+        });
+        scale_filter.source.palette = palette;
+        selected_palette = None;
+    }
+    let mut ntsc_filter = NtscFilter::default();
+    let mut use_ntsc_filter = false;
+
+    let draw_egui = true;
+    let mut nametable_index = 0;
+    let mut frames_since_flush = 0;
+    let mut state_slot = 0;
+    let mut state_message = String::new();
+    let mut movie_message = String::new();
+    // Emulation is paused for stepping through instructions unless a movie is running
+    let mut running = movie.is_some();
+    let mut was_rewinding = false;
+
+    // Closing the window is handled in the loop so the save RAM can be flushed first
+    prevent_quit();
+    clear_background(BLACK);
+
+    loop {
+        if is_quit_requested() {
+            break;
+        }
+
+        if let Some(slot) = STATE_SLOT_KEYS.iter().position(|key| is_key_pressed(*key)) {
+            state_slot = slot;
+        }
+        if is_key_pressed(SAVE_STATE_KEY) {
+            state_message = save_state_slot(&nes, Path::new(&file_path), state_slot);
+        }
+        if is_key_pressed(LOAD_STATE_KEY) {
+            state_message = load_state_slot(&mut nes, Path::new(&file_path), state_slot, &mut movie);
+        }
+
+        let rewinding = is_key_down(REWIND_KEY);
+        if rewinding {
+            if let Some(snapshot) = rewind.rewind() {
+                if let Err(error) = nes.load_state(&snapshot) {
+                    eprintln!("Failed to rewind: {}", error);
+                }
+            }
+        } else if running {
+            // A movie carries on from wherever the rewind stopped
+            if was_rewinding {
+                if let Some(movie) = &mut movie {
+                    movie.session.state_loaded(&nes);
+                }
+            }
+            let live_buttons = [keyboard_buttons(), Buttons::empty()];
+            match &mut movie {
+                Some(movie) => movie.session.run_frame(&mut nes, live_buttons),
+                None => {
+                    nes.set_buttons(0, live_buttons[0]);
+                    nes.run_frame();
+                }
+            }
+            rewind.push_frame(|| nes.save_state());
+        }
+        was_rewinding = rewinding;
+
+        let video_filter: &mut dyn VideoFilter = if use_ntsc_filter { &mut ntsc_filter } else { &mut scale_filter };
+        let (width, height) = video_filter.output_size();
+        draw_nes_screen(&video_filter.apply_to_vec(nes.frame()), width, height);
+
+        if draw_egui {
+            egui_macroquad::ui(|egui_ctx| {
+                egui::SidePanel::left("").show(egui_ctx, |ui| {
+                    let left_pattern_table = pattern_table_image(nes.ppu(), false);
+                    let right_pattern_table = pattern_table_image(nes.ppu(), true);
+                    let pattern_tables = [left_pattern_table.clone(), right_pattern_table.clone()];
+                    let nametable = nametable_image(nes.ppu(), nametable_index, &pattern_tables);
+
+                    let left_pattern_table_handle = egui_ctx.load_texture("left_pattern_table", left_pattern_table, egui::TextureOptions::NEAREST);
+                    let right_pattern_table_handle = egui_ctx.load_texture("right_pattern_table", right_pattern_table, egui::TextureOptions::NEAREST);
+                    let nametable_handle = egui_ctx.load_texture("nametable", nametable, egui::TextureOptions::NEAREST);
+
+                    ui.collapsing("Cartridge", |ui| {
+                        match &game_info {
+                            Some(game_info) => {
+                                ui.label(&game_info.title);
+                                ui.label(format!("Mapper {} (submapper {})", game_info.mapper, game_info.submapper));
+                                ui.label(format!("Region {}", game_info.timing.region().name()));
+                                ui.label(format!("Expansion device: {}", game_info.expansion_device_name()));
+                            }
+                            None => {
+                                ui.label("Not in the rom database");
+                            }
+                        }
+                        if let Some(board) = &unif_board {
+                            ui.label(format!("UNIF board {}", board));
+                        }
+                    });
+                    if let Some(drive) = nes.disk_drive() {
+                        ui.collapsing("Disk", |ui| {
+                            for side in 0..drive.side_count() {
+                                if ui.radio(drive.inserted_side() == Some(side), DiskDrive::side_name(side)).clicked() {
+                                    drive.insert(side);
+                                }
+                            }
+                            if ui.button("Eject").clicked() {
+                                drive.eject();
+                            }
+                        });
+                    }
+                    ui.collapsing("Save States", |ui| {
+                        ui.horizontal_wrapped(|ui| {
+                            for slot in 0..STATE_SLOTS {
+                                ui.radio_value(&mut state_slot, slot, slot.to_string());
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            if ui.button("Save (F5)").clicked() {
+                                state_message = save_state_slot(&nes, Path::new(&file_path), state_slot);
+                            }
+                            if ui.button("Load (F7)").clicked() {
+                                state_message = load_state_slot(&mut nes, Path::new(&file_path), state_slot, &mut movie);
+                            }
+                        });
+                        ui.label(&state_message);
+                    });
+                    if let Some(movie) = &mut movie {
+                        ui.collapsing("Movie", |ui| {
+                            let session = &mut movie.session;
+                            ui.label(&movie.path);
+                            ui.label(match session.mode {
+                                MovieMode::Recording => "Recording",
+                                MovieMode::Playing => "Playing",
+                                MovieMode::Finished => "Finished",
+                            });
+                            ui.label(format!("Frame {} of {}", session.frame, session.movie.frames.len()));
+                            ui.label(format!("{} rerecords", session.movie.rerecord_count));
+                            if let Some(frame) = session.desync {
+                                ui.colored_label(Color32::RED, format!("Desynced at frame {}", frame));
+                            }
+                            if movie.unsaved && ui.button("Stop Recording").clicked() {
+                                session.mode = MovieMode::Finished;
+                                movie_message = save_movie(movie);
+                            }
+                            ui.label(&movie_message);
+                        });
+                    }
+                    ui.collapsing("Rewind", |ui| {
+                        ui.label(if rewinding { "Rewinding" } else { "Hold backspace to rewind" });
+                        ui.label(format!(
+                            "{} snapshots, {} KiB of {} KiB",
+                            rewind.snapshot_count(),
+                            rewind.used_bytes() / 1024,
+                            rewind.memory_budget / 1024
+                        ));
+                        ui.add(egui::Slider::new(&mut rewind.frames_per_snapshot, 1..=30).text("Frames per snapshot"));
+                    });
+                    ui.collapsing("Timing", |ui| {
+                        ui.checkbox(&mut running, "Run");
+                        ui.label(format!("CPU {}", nes.cpu().cycles));
+                        let instruction = nes.cpu().fetch();
+                        ui.label(nes.cpu().execution_trace(&instruction));
+                        ui.horizontal(|ui| {
+                            if ui.button("Step Instruction").clicked() {
+                                nes.step_instruction();
+                            }
+                            if ui.button("Step Cycle").clicked() {
+                                nes.step_cycle();
+                            }
+                        });
+                        ui.label(format!("PPU {}, {}", nes.ppu().scanline, nes.ppu().cycle));
+                        ui.horizontal(|ui| {
+                            for region in Region::ALL {
+                                if ui.radio(nes.region() == region, region.name()).clicked() {
+                                    nes.set_region(region);
+                                }
+                            }
+                        });
+                    });
+                    ui.collapsing("Pattern Tables", |ui| {
+                        ui.horizontal(|ui| {
+                            ui.image(left_pattern_table_handle.id(), left_pattern_table_handle.size_vec2());
+                            ui.image(right_pattern_table_handle.id(), right_pattern_table_handle.size_vec2());
+                        });
+                    });
+                    ui.collapsing("Nametables", |ui| {
+                        ui.horizontal(|ui| {
+                            ui.radio_value(&mut nametable_index, 0, "$2000");
+                            ui.radio_value(&mut nametable_index, 1, "$2400");
+                            ui.radio_value(&mut nametable_index, 2, "$2800");
+                            ui.radio_value(&mut nametable_index, 3, "$2C00");
+                        });
+                        ui.image(nametable_handle.id(), nametable_handle.size_vec2());
+                    });
+                    ui.collapsing("Palette", |ui| {
+                        for builtin_palette in BuiltinPalette::ALL {
+                            if ui.radio(selected_palette == Some(builtin_palette), builtin_palette.name()).clicked() {
+                                selected_palette = Some(builtin_palette);
+                                scale_filter.source.palette = builtin_palette.palette();
+                            }
+                        }
+                    });
+                    ui.collapsing("Video Filter", |ui| {
+                        ui.horizontal(|ui| {
+                            ui.radio_value(&mut use_ntsc_filter, false, "Palette");
+                            ui.radio_value(&mut use_ntsc_filter, true, "NTSC");
+                        });
+                        if !use_ntsc_filter {
+                            egui::ComboBox::from_label("Scaler")
+                                .selected_text(scale_filter.mode.name())
+                                .show_ui(ui, |ui| {
+                                    for mode in ScaleMode::ALL {
+                                        ui.selectable_value(&mut scale_filter.mode, mode, mode.name());
+                                    }
+                                });
+                        } else {
+                            let settings = &mut ntsc_filter.settings;
+                            ui.add(egui::Slider::new(&mut settings.hue, -45.0..=45.0).text("Hue"));
+                            ui.add(egui::Slider::new(&mut settings.saturation, 0.0..=2.0).text("Saturation"));
+                            ui.add(egui::Slider::new(&mut settings.sharpness, 0.0..=1.0).text("Sharpness"));
+                            ui.add(egui::Slider::new(&mut settings.artifacts, 0.0..=1.0).text("Artifacts"));
+                            ui.checkbox(&mut settings.merge_fields, "Merge fields");
+                        }
+                    });
+                });
+            });
+
+            egui_macroquad::draw();
+        }
+
+        frames_since_flush += 1;
+        if frames_since_flush >= SAVE_FLUSH_INTERVAL_FRAMES {
+            frames_since_flush = 0;
+            if let Some(save_file) = &mut save_file {
+                flush_save_file(save_file, &nes);
+            }
+        }
+
+        next_frame().await;
+    }
+
+    if let Some(save_file) = &mut save_file {
+        flush_save_file(save_file, &nes);
+    }
+    if let Some(movie) = &mut movie {
+        if movie.unsaved {
+            eprintln!("{}", save_movie(movie));
+        }
+    }
+}
+
+fn keyboard_buttons() -> Buttons {
+    CONTROLLER_KEYS
+        .iter()
+        .filter(|(key, _)| is_key_down(*key))
+        .fold(Buttons::empty(), |buttons, (_, button)| buttons | *button)
+}
+
+fn render_nsf_to_wav(player: &mut NsfPlayer, wav_path: &str) {
+    let samples = player.render(player.track_sample_count() as usize);
+    let wav = wav::encode_wav(&samples, player.sample_rate());
+    if let Err(error) = std::fs::write(wav_path, wav) {
+        eprintln!("Failed to write {}: {}", wav_path, error);
+        std::process::exit(1);
+    }
+    eprintln!("Wrote {} ({} seconds)", wav_path, samples.len() / player.sample_rate() as usize);
+}
+
+// macroquad can't stream audio, so each track is rendered whole before it plays.
+// Sounds can't be freed either, so they're kept and reused when a track is played again
+async fn run_nsf_player(mut player: NsfPlayer) {
+    let mut sounds: Vec<Option<Sound>> = vec![None; player.nsf.track_count];
+    let mut rendered_samples = Vec::new();
+    // The sound that's playing and when it started
+    let mut playing: Option<(Sound, f64)> = None;
+    let mut selected_track = None;
+
+    loop {
+        if is_key_pressed(KeyCode::Right) {
+            selected_track = Some((player.track + 1) % player.nsf.track_count);
+        }
+        if is_key_pressed(KeyCode::Left) {
+            selected_track = Some((player.track + player.nsf.track_count - 1) % player.nsf.track_count);
+        }
+        let track_seconds = player.track_sample_count() as f64 / player.sample_rate() as f64;
+        if let Some((_, started)) = playing {
+            if get_time() - started >= track_seconds {
+                selected_track = Some((player.track + 1) % player.nsf.track_count);
+            }
+        }
+
+        if let Some(track) = selected_track.take() {
+            if let Some((sound, _)) = playing.take() {
+                stop_sound(sound);
+            }
+            player.select_track(track);
+            rendered_samples.clear();
+        }
+
+        if playing.is_none() {
+            if let Some(sound) = sounds[player.track] {
+                play_sound_once(sound);
+                playing = Some((sound, get_time()));
+            } else {
+                let track_samples = player.track_sample_count() as usize;
+                let render_start = get_time();
+                while !player.track_finished() && get_time() - render_start < NSF_RENDER_SECONDS_PER_FRAME {
+                    let count = NSF_RENDER_CHUNK_SAMPLES.min(track_samples - rendered_samples.len());
+                    rendered_samples.extend(player.render(count));
+                }
+                if player.track_finished() {
+                    let wav = wav::encode_wav(&rendered_samples, player.sample_rate());
+                    rendered_samples.clear();
+                    let sound = load_sound_from_bytes(&wav)
+                        .await
+                        .unwrap_or_else(|error| exit_with_error(&player.nsf.track_name(player.track), error));
+                    sounds[player.track] = Some(sound);
+                }
+            }
+        }
+
+        let status = match playing {
+            Some((_, started)) => format!("Playing {} / {}", format_seconds(get_time() - started), format_seconds(track_seconds)),
+            None => format!(
+                "Rendering {}%",
+                rendered_samples.len() * 100 / player.track_sample_count().max(1) as usize
+            ),
+        };
+
+        clear_background(BLACK);
+        egui_macroquad::ui(|egui_ctx| {
+            egui::CentralPanel::default().show(egui_ctx, |ui| {
+                let nsf = &player.nsf;
+                ui.heading(&nsf.title);
+                ui.label(&nsf.artist);
+                ui.label(&nsf.copyright);
+                if !nsf.expansion_chips.is_empty() {
+                    ui.label(format!("Expansion audio: {}", nsf.expansion_chips.names().join(", ")));
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Previous").clicked() {
+                        selected_track = Some((player.track + nsf.track_count - 1) % nsf.track_count);
+                    }
+                    if ui.button("Next").clicked() {
+                        selected_track = Some((player.track + 1) % nsf.track_count);
+                    }
+                    ui.label(&status);
+                });
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for track in 0..nsf.track_count {
+                        if ui
+                            .selectable_label(track == player.track, format!("{}. {}", track + 1, nsf.track_name(track)))
+                            .clicked()
+                        {
+                            selected_track = Some(track);
+                        }
+                    }
+                });
+            });
+        });
+        egui_macroquad::draw();
+
+        next_frame().await;
+    }
+}
+
+fn format_seconds(seconds: f64) -> String {
+    let seconds = seconds as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// Returns a message for the ui saying how it went
+fn save_state_slot(nes: &Nes, rom_path: &Path, slot: usize) -> String {
+    let path = save::state_slot_path(rom_path, slot);
+    match save::write_atomic(&path, &nes.save_state()) {
+        Ok(()) => format!("Saved slot {}", slot),
+        Err(error) => format!("Failed to write {}: {}", path.display(), error),
+    }
+}
+
+// A movie being recorded carries on from the loaded state
+fn load_state_slot(nes: &mut Nes, rom_path: &Path, slot: usize, movie: &mut Option<MovieFile>) -> String {
+    let path = save::state_slot_path(rom_path, slot);
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return format!("Slot {} is empty", slot),
+        Err(error) => return format!("Failed to read {}: {}", path.display(), error),
+    };
+    match nes.load_state(&bytes) {
+        Ok(()) => {
+            if let Some(movie) = movie {
+                movie.session.state_loaded(nes);
+            }
+            format!("Loaded slot {}", slot)
+        }
+        Err(error) => format!("Failed to load slot {}: {}", slot, error),
+    }
+}
+
+fn movie_format(path: &str) -> Option<MovieFormat> {
+    Path::new(path).extension()?.to_str().and_then(MovieFormat::from_extension)
+}
+
+fn save_movie(movie: &mut MovieFile) -> String {
+    let path = Path::new(&movie.path);
+    // The extension was checked when recording started
+    let format = movie_format(&movie.path).unwrap_or(MovieFormat::Fm2);
+    match save::write_atomic(path, &movie.session.movie.to_bytes(format)) {
+        Ok(()) => {
+            movie.unsaved = false;
+            format!("Saved {} frames to {}", movie.session.movie.frames.len(), movie.path)
+        }
+        Err(error) => format!("Failed to write {}: {}", movie.path, error),
+    }
+}
+
+fn flush_save_file(save_file: &mut SaveFile, nes: &Nes) {
+    if let Err(error) = save_file.flush(nes.mapper()) {
+        eprintln!("Warning: failed to write {}: {}", save_file.path.display(), error);
+    }
+}
+
+fn read_file(file_path: &str) -> io::Result<Vec<u8>> {
+    let mut file = File::open(file_path)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// A patch with the same name as the rom, like game.ips next to game.nes or game.zip
+fn find_patch(rom_path: &Path) -> Option<String> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .find(|patch_path| patch_path.is_file())
+        .map(|patch_path| patch_path.to_string_lossy().into_owned())
+}
+
+fn exit_with_usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} <file_path> [palette.pal] [--patch <patch.ips|bps|ups>] [--fds-bios <disksys.rom>] [--rewind-memory <MiB>] [--record <movie.fm2|bk2> [--from-slot <n>] | --play <movie.fm2|bk2>] [--track <n>] [--wav <out.wav>] [--seconds <n>]",
+        program
+    );
+    std::process::exit(1);
+}
+
+fn exit_with_error(file_path: &str, error: impl std::fmt::Display) -> ! {
+    eprintln!("Failed to load {}: {}", file_path, error);
+    std::process::exit(1);
+}
+
+fn pattern_table_image(ppu: &Ppu, is_right: bool) -> egui::ColorImage {
+    let start_address = if is_right { 0x1000 } else { 0x0000 };
+    let mut pattern_table = egui::ColorImage::new([16 * 8, 16 * 8], egui::Color32::BLACK);
+    for tile_y in 0..16 {
+        for tile_x in 0..16 {
+            let tile_address = start_address + tile_y * 16 * 16 + tile_x * 16;
+            for y in 0..8 {
+                let low_byte = ppu.read(tile_address + y);
+                let high_byte = ppu.read(tile_address + y + 8);
+                for x in 0..8 {
+                    let low_bit = (low_byte & (1 << x)) >> x;
+                    let high_bit = (high_byte & (1 << x)) >> x;
+                    //let color_index = low_bit + high_bit;
+                    let pixel_x = tile_x * 8 + (7 - x);
+                    let pixel_y = tile_y * 8 + y;
+                    //println!("x: {} y: {}", pixel_x, pixel_y);
+                    pattern_table[(pixel_x as usize, pixel_y as usize)] = Color32::from_rgb(low_bit * 255, high_bit * 255, 0);
+                }
+            }
+        }
+    }
+    pattern_table
+}
+fn nametable_image(ppu: &Ppu, nametable_index: u16, pattern_tables: &[ColorImage; 2]) -> ColorImage {
+    let pattern_table_image = if ppu.control_register.contains(ControlFlags::BackgroundPatternTableAddress) {
+        &pattern_tables[1]
+    } else {
+        &pattern_tables[0]
+    };
+    let start_address = 0x2000 + nametable_index * 0x400;
+    let mut nametable_image = egui::ColorImage::new([32 * 8, 30 * 8], egui::Color32::BLACK);
+    for tile_y in 0..30 {
+        for tile_x in 0..32 {
+            let nametable_address = start_address + tile_y * 16 + tile_x;
+            let nametable_byte = ppu.read(nametable_address);
+            let pattern_x = (nametable_byte % 16) * 8;
+            let pattern_y = (nametable_byte / 16) * 8;
+            //println!("{}", nametable_byte);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let pixel_x = pattern_x + x;
+                    let pixel_y = pattern_y + y;
+                    let nametable_x = tile_x as u8 * 8 + pixel_x;
+                    let nametable_y = tile_y as u8 * 8 + pixel_y;
+                    nametable_image[(nametable_x as usize, nametable_y as usize)] = pattern_table_image[(pixel_x as usize, pixel_y as usize)];
+                }
+            }
+        }
+    }
+    nametable_image
+}
+
+fn draw_nes_screen(rgba: &[u8], width: usize, height: usize) {
+    let texture = Texture2D::from_rgba8(width as u16, height as u16, rgba);
+    let draw_params = DrawTextureParams {
+        dest_size: Some(macroquad::math::Vec2::new(
+            (Frame::WIDTH * WINDOW_SCALE) as f32,
+            (Frame::HEIGHT * WINDOW_SCALE) as f32,
+        )),
+        ..Default::default()
+    };
+    draw_texture_ex(texture, 0.0, 0.0, WHITE, draw_params);
+}
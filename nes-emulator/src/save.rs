@@ -0,0 +1,111 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use nes_core::mapper::Mapper;
+use nes_core::nes::Nes;
+
+// Battery backed save RAM stored next to the rom as <rom>.sav, in the same raw format other emulators use
+pub struct SaveFile {
+    pub path: PathBuf,
+    // Contents of the save RAM the last time it was loaded or flushed, used to skip writing unchanged saves
+    last_flushed: Vec<u8>,
+}
+
+impl SaveFile {
+    pub fn new(rom_path: &Path) -> Self {
+        SaveFile {
+            path: rom_path.with_extension("sav"),
+            last_flushed: Vec::new(),
+        }
+    }
+
+    // Loads the save file into the cartridge, a missing file leaves the save RAM as it is
+    pub fn load(&mut self, nes: &mut Nes) -> io::Result<()> {
+        if nes.mapper().save_ram().is_none() {
+            return Ok(());
+        }
+        match fs::read(&self.path) {
+            Ok(data) => {
+                nes.load_save_ram(&data);
+                self.last_flushed = nes.mapper().save_ram().unwrap_or_default().to_vec();
+                Ok(())
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Writes the save RAM to disk if it changed since the last flush, returns whether anything was written
+    pub fn flush(&mut self, mapper: &dyn Mapper) -> io::Result<bool> {
+        let Some(save_ram) = mapper.save_ram() else {
+            return Ok(false);
+        };
+        if save_ram == self.last_flushed.as_slice() {
+            return Ok(false);
+        }
+        write_atomic(&self.path, save_ram)?;
+        self.last_flushed = save_ram.to_vec();
+        Ok(true)
+    }
+}
+
+// Writes to a temporary file first and renames it over the destination, so a crash never leaves a half written save
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, data)?;
+    fs::rename(&temp_path, path)
+}
+
+// Save state slots are stored next to the rom as <rom>.ss0 to <rom>.ss9
+pub fn state_slot_path(rom_path: &Path, slot: usize) -> PathBuf {
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes_core::rom::Rom;
+
+    fn test_nes(flags_6: u8) -> Nes {
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, flags_6];
+        bytes.resize(16 + 0x4000 + 0x2000, 0);
+        Nes::new(Rom::new(&bytes).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let directory = std::env::temp_dir().join(format!("nes-save-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let rom_path = directory.join("game.nes");
+
+        // Battery backed
+        let mut nes = test_nes(0b10);
+        let mut save_file = SaveFile::new(&rom_path);
+        assert_eq!(save_file.path, directory.join("game.sav"));
+        save_file.load(&mut nes).unwrap();
+
+        nes.mapper_mut().write(0x6000, 0x12);
+        nes.mapper_mut().write(0x7FFF, 0x34);
+        assert!(save_file.flush(nes.mapper()).unwrap());
+        assert!(!save_file.flush(nes.mapper()).unwrap());
+        assert!(!directory.join("game.sav.tmp").exists());
+
+        let mut nes = test_nes(0b10);
+        SaveFile::new(&rom_path).load(&mut nes).unwrap();
+        assert_eq!(nes.mapper().read(0x6000), 0x12);
+        assert_eq!(nes.mapper().read(0x7FFF), 0x34);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_no_battery() {
+        let mut nes = test_nes(0);
+        nes.mapper_mut().write(0x6000, 0x12);
+        let mut save_file = SaveFile::new(Path::new("/nonexistent/game.nes"));
+        save_file.load(&mut nes).unwrap();
+        assert!(!save_file.flush(nes.mapper()).unwrap());
+    }
+}
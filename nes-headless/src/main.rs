@@ -0,0 +1,152 @@
+use nes_core::filter::{PaletteFilter, VideoFilter};
+use nes_core::headless::HeadlessRunner;
+use nes_core::movie::{Movie, MovieSession, RomIdentity};
+use nes_core::nes::Nes;
+use nes_core::palette::{BuiltinPalette, Palette};
+use nes_core::patch::{self, PATCH_EXTENSIONS};
+use nes_core::rom::Rom;
+use nes_core::scaler::{ScaleFilter, ScaleMode};
+use nes_core::{archive, fds, png};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const FDS_BIOS_FILE_NAME: &str = "disksys.rom";
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // Runs a rom for a number of frames without a window and prints the hashes of chosen frames, optionally saving them as PNGs
+    // that can be upscaled with --scale.
+    // A movie can be played to give the inputs, the exit code is 1 if it desynced.
+    let mut paths = Vec::new();
+    let mut patch_path = None;
+    let mut fds_bios_path = None;
+    let mut play_path = None;
+    let mut hash_frames = Vec::new();
+    let mut png_dir = None;
+    let mut scale_mode = ScaleMode::Nearest(1);
+    let mut arguments = args.iter().skip(1);
+    while let Some(argument) = arguments.next() {
+        if argument == "--patch" {
+            patch_path = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--fds-bios" {
+            fds_bios_path = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--play" {
+            play_path = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--hash-frames" {
+            hash_frames = arguments
+                .next()
+                .and_then(|frames| frames.split(',').map(|frame| frame.parse::<u64>().ok()).collect::<Option<Vec<_>>>())
+                .unwrap_or_else(|| exit_with_usage(&args[0]));
+        } else if argument == "--png" {
+            png_dir = Some(arguments.next().unwrap_or_else(|| exit_with_usage(&args[0])).clone());
+        } else if argument == "--scale" {
+            scale_mode = arguments
+                .next()
+                .and_then(|name| ScaleMode::from_name(name))
+                .unwrap_or_else(|| exit_with_usage(&args[0]));
+        } else {
+            paths.push(argument);
+        }
+    }
+    if paths.len() < 2 || paths.len() > 3 {
+        exit_with_usage(&args[0]);
+    }
+    let file_path = paths[0];
+    let frames = paths[1].parse::<u64>().unwrap_or_else(|_| exit_with_usage(&args[0]));
+
+    let bytes = fs::read(file_path).unwrap_or_else(|error| exit_with_error(file_path, error));
+    let mut bytes = archive::unpack_rom(bytes).unwrap_or_else(|error| exit_with_error(file_path, error));
+    if let Some(patch_path) = patch_path.or_else(|| find_patch(Path::new(file_path))) {
+        let patch = fs::read(&patch_path).unwrap_or_else(|error| exit_with_error(&patch_path, error));
+        bytes = patch::apply_patch(&bytes, &patch).unwrap_or_else(|error| exit_with_error(&patch_path, error));
+        eprintln!("Applied patch {}", patch_path);
+    }
+    let rom = if fds::is_fds_image(&bytes) {
+        let bios_path = fds_bios_path.unwrap_or_else(|| Path::new(file_path).with_file_name(FDS_BIOS_FILE_NAME).to_string_lossy().into_owned());
+        let bios = fs::read(&bios_path)
+            .unwrap_or_else(|error| exit_with_error(&bios_path, format!("{}, the FDS BIOS can be given with --fds-bios <disksys.rom>", error)));
+        Rom::from_fds(&bytes, &bios).unwrap_or_else(|error| exit_with_error(file_path, error))
+    } else {
+        Rom::new(&bytes).unwrap_or_else(|error| exit_with_error(file_path, error))
+    };
+    let rom_name = Path::new(file_path)
+        .file_stem()
+        .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+    let rom_identity = RomIdentity::new(&rom_name, &rom);
+    let mut nes = Nes::new(rom).unwrap_or_else(|error| exit_with_error(file_path, error));
+
+    let movie = play_path.map(|path| {
+        let bytes = fs::read(&path).unwrap_or_else(|error| exit_with_error(&path, error));
+        let movie = Movie::load(&bytes).unwrap_or_else(|error| exit_with_error(&path, error));
+        if !movie.matches_rom(&rom_identity) {
+            eprintln!("Warning: {} was recorded with a different rom and will probably desync", path);
+        }
+        MovieSession::play(movie, &mut nes).unwrap_or_else(|error| exit_with_error(&path, error))
+    });
+
+    // Only the last frame is hashed unless others are asked for
+    if hash_frames.is_empty() {
+        hash_frames.push(frames);
+    }
+    hash_frames.sort_unstable();
+    hash_frames.retain(|frame| *frame <= frames);
+    let palette = match paths.get(2) {
+        Some(palette_path) => {
+            let bytes = fs::read(palette_path).unwrap_or_else(|error| exit_with_error(palette_path, error));
+            Palette::from_pal_bytes(&bytes).unwrap_or_else(|error| exit_with_error(palette_path, error))
+        }
+        None => BuiltinPalette::for_region(nes.region()).palette(),
+    };
+    let filter = ScaleFilter::new(PaletteFilter::new(palette), scale_mode);
+    let desynced = run_headless(HeadlessRunner::new(nes, movie), &hash_frames, png_dir.as_deref(), &rom_name, filter);
+    std::process::exit(if desynced { 1 } else { 0 });
+}
+
+// Prints "<frame> <crc32>" for each of the frames and returns whether a movie desynced
+fn run_headless(mut runner: HeadlessRunner, hash_frames: &[u64], png_dir: Option<&str>, rom_name: &str, mut filter: impl VideoFilter) -> bool {
+    let (width, height) = filter.output_size();
+    for frame in hash_frames {
+        let hash = runner.frame_hashes(&[*frame])[0];
+        println!("{} {:08x}", frame, hash);
+        if let Some(png_dir) = png_dir {
+            let png_path = Path::new(png_dir).join(format!("{}_{}.png", rom_name, frame));
+            let rgba = filter.apply_to_vec(runner.nes.frame());
+            if let Err(error) = fs::write(&png_path, png::encode_png(&rgba, width, height)) {
+                eprintln!("Failed to write {}: {}", png_path.display(), error);
+                std::process::exit(1);
+            }
+        }
+    }
+    match runner.desync() {
+        Some(frame) => {
+            eprintln!("Movie desynced at frame {}", frame);
+            true
+        }
+        None => false,
+    }
+}
+
+// A patch with the same name as the rom, like game.ips next to game.nes or game.zip
+fn find_patch(rom_path: &Path) -> Option<String> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .find(|patch_path| patch_path.is_file())
+        .map(|patch_path| patch_path.to_string_lossy().into_owned())
+}
+
+fn exit_with_usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} <file_path> <frames> [palette.pal] [--patch <patch.ips|bps|ups>] [--fds-bios <disksys.rom>] [--play <movie.fm2|bk2>] [--hash-frames <n,n,...>] [--png <dir> [--scale <mode>]]\nScale modes: {}",
+        program,
+        ScaleMode::ALL.map(|mode| mode.name()).join(", ")
+    );
+    std::process::exit(1);
+}
+
+fn exit_with_error(file_path: &str, error: impl std::fmt::Display) -> ! {
+    eprintln!("Failed to load {}: {}", file_path, error);
+    std::process::exit(1);
+}